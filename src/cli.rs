@@ -39,9 +39,15 @@ pub struct Cli {
     /// Use light theme
     #[clap(long)]
     pub light_theme: bool,
+    /// Render markdown with ANSI escapes even when stdout is not a terminal
+    #[clap(long)]
+    pub ansi: bool,
     /// Display the message without sending it
     #[clap(long)]
     pub dry_run: bool,
+    /// Print only the reply, without spinner or banner
+    #[clap(short = 'q', long)]
+    pub quiet: bool,
     /// Display information
     #[clap(long)]
     pub info: bool,
@@ -1,5 +1,7 @@
 use super::openai::openai_build_body;
-use super::{AzureOpenAIClient, ExtraConfig, Model, ModelConfig, PromptType, SendData};
+use super::{
+    merge_extra_body, AzureOpenAIClient, ExtraConfig, Model, ModelConfig, PromptType, SendData,
+};
 
 use crate::utils::PromptKind;
 
@@ -38,7 +40,7 @@ impl AzureOpenAIClient {
         let api_key = self.get_api_key()?;
 
         let mut body = openai_build_body(data, &self.model);
-        self.model.merge_extra_fields(&mut body);
+        merge_extra_body(&mut body, &self.config.extra, &self.model);
 
         let url = format!(
             "{}/openai/deployments/{}/chat/completions?api-version=2023-05-15",
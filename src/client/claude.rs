@@ -1,6 +1,7 @@
 use super::{
-    catch_error, extract_system_message, ClaudeClient, ExtraConfig, ImageUrl, MessageContent,
-    MessageContentPart, Model, ModelConfig, PromptType, ReplyHandler, SendData,
+    catch_error, extract_system_message, fold_system_messages, merge_extra_body,
+    notice_unsupported_seed, optional_api_key, ClaudeClient, ClientError, ExtraConfig, ImageUrl,
+    MessageContent, MessageContentPart, Model, ModelConfig, PromptType, ReplyHandler, SendData,
 };
 
 use crate::utils::PromptKind;
@@ -40,9 +41,10 @@ impl ClaudeClient {
         [("api_key", "API Key:", false, PromptKind::String)];
 
     fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
-        let api_key = self.get_api_key().ok();
+        let api_key = optional_api_key(self.get_api_key(), &self.config.extra)?;
 
-        let body = claude_build_body(data, &self.model)?;
+        let mut body = claude_build_body(data, &self.model)?;
+        merge_extra_body(&mut body, &self.config.extra, &self.model);
 
         let url = API_BASE;
 
@@ -86,14 +88,33 @@ pub async fn claude_send_message_streaming(
     let mut es = builder.eventsource()?;
     while let Some(event) = es.next().await {
         match event {
-            Ok(Event::Open) => {}
+            Ok(Event::Open) => handler.set_status(200),
             Ok(Event::Message(message)) => {
                 let data: Value = serde_json::from_str(&message.data)?;
                 if let Some(typ) = data["type"].as_str() {
-                    if typ == "content_block_delta" {
-                        if let Some(text) = data["delta"]["text"].as_str() {
-                            handler.text(text)?;
+                    match typ {
+                        "content_block_delta" => {
+                            if let Some(text) = data["delta"]["text"].as_str() {
+                                handler.text(text)?;
+                            }
+                        }
+                        "message_start" => {
+                            let usage = &data["message"]["usage"];
+                            let cache_read = usage["cache_read_input_tokens"].as_u64();
+                            let cache_creation = usage["cache_creation_input_tokens"].as_u64();
+                            // input_tokens excludes the tokens read from or written to the cache
+                            let input_tokens = usage["input_tokens"].as_u64().map(|v| {
+                                v + cache_read.unwrap_or_default()
+                                    + cache_creation.unwrap_or_default()
+                            });
+                            handler.usage(input_tokens, usage["output_tokens"].as_u64());
+                            handler.cached_tokens(cache_read);
                         }
+                        "message_delta" => {
+                            handler.finish_reason(data["delta"]["stop_reason"].as_str());
+                            handler.usage(None, data["usage"]["output_tokens"].as_u64());
+                        }
+                        _ => {}
                     }
                 }
             }
@@ -101,6 +122,7 @@ pub async fn claude_send_message_streaming(
                 match err {
                     EventSourceError::StreamEnded => {}
                     EventSourceError::InvalidStatusCode(status, res) => {
+                        handler.set_status(status.as_u16());
                         let text = res.text().await?;
                         let data: Value = match text.parse() {
                             Ok(data) => data,
@@ -117,6 +139,9 @@ pub async fn claude_send_message_streaming(
                         let text = res.text().await?;
                         bail!("The API server should return data as 'text/event-stream', but it isn't. Check the client config. {text}");
                     }
+                    EventSourceError::Transport(err) => {
+                        return Err(ClientError::Network(err).into());
+                    }
                     _ => {
                         bail!("{}", err);
                     }
@@ -134,17 +159,25 @@ pub fn claude_build_body(data: SendData, model: &Model) -> Result<Value> {
         mut messages,
         temperature,
         top_p,
+        seed,
+        user,
         stream,
+        cache_prompt,
+        thread: _,
     } = data;
 
+    fold_system_messages(&mut messages);
     let system_message = extract_system_message(&mut messages);
+    // Everything before the latest user message is stable across turns
+    let cache_index = messages.len().checked_sub(2).filter(|_| cache_prompt);
 
     let mut network_image_urls = vec![];
     let messages: Vec<Value> = messages
         .into_iter()
-        .map(|message| {
+        .enumerate()
+        .map(|(i, message)| {
             let role = message.role;
-            let content = match message.content {
+            let mut content: Vec<Value> = match message.content {
                 MessageContent::Text(text) => vec![json!({"type": "text", "text": text})],
                 MessageContent::Array(list) => list
                     .into_iter()
@@ -173,6 +206,11 @@ pub fn claude_build_body(data: SendData, model: &Model) -> Result<Value> {
                     })
                     .collect(),
             };
+            if Some(i) == cache_index {
+                if let Some(part) = content.last_mut() {
+                    part["cache_control"] = json!({ "type": "ephemeral" });
+                }
+            }
             json!({ "role": role, "content": content })
         })
         .collect();
@@ -189,7 +227,11 @@ pub fn claude_build_body(data: SendData, model: &Model) -> Result<Value> {
         "messages": messages,
     });
     if let Some(v) = system_message {
-        body["system"] = v.into();
+        body["system"] = if cache_prompt {
+            json!([{ "type": "text", "text": v, "cache_control": { "type": "ephemeral" } }])
+        } else {
+            v.into()
+        };
     }
     if let Some(v) = model.max_output_tokens {
         body["max_tokens"] = v.into();
@@ -200,6 +242,12 @@ pub fn claude_build_body(data: SendData, model: &Model) -> Result<Value> {
     if let Some(v) = top_p {
         body["top_p"] = v.into();
     }
+    if seed.is_some() {
+        notice_unsupported_seed(model);
+    }
+    if let Some(v) = user {
+        body["metadata"]["user_id"] = v.into();
+    }
     if stream {
         body["stream"] = true.into();
     }
@@ -1,6 +1,7 @@
 use super::{
-    catch_error, extract_system_message, json_stream, message::*, CohereClient,
-    ExtraConfig, Model, ModelConfig, PromptType, ReplyHandler, SendData,
+    catch_error, extract_system_message, fold_system_messages, json_stream, merge_extra_body,
+    message::*, optional_api_key, CohereClient, ExtraConfig, Model, ModelConfig, PromptType,
+    ReplyHandler, SendData,
 };
 
 use crate::utils::PromptKind;
@@ -36,9 +37,10 @@ impl CohereClient {
         [("api_key", "API Key:", false, PromptKind::String)];
 
     fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
-        let api_key = self.get_api_key().ok();
+        let api_key = optional_api_key(self.get_api_key(), &self.config.extra)?;
 
-        let body = build_body(data, &self.model)?;
+        let mut body = build_body(data, &self.model)?;
+        merge_extra_body(&mut body, &self.config.extra, &self.model);
 
         let url = API_URL;
 
@@ -69,14 +71,17 @@ async fn send_message(builder: RequestBuilder) -> Result<String> {
 async fn send_message_streaming(builder: RequestBuilder, handler: &mut ReplyHandler) -> Result<()> {
     let res = builder.send().await?;
     let status = res.status();
+    handler.set_status(status.as_u16());
     if status != 200 {
         let data: Value = res.json().await?;
         catch_error(&data, status.as_u16())?;
     } else {
         let handle = |value: &str| -> Result<()> {
             let value: Value = serde_json::from_str(value)?;
-            if let Some("text-generation") = value["event_type"].as_str() {
-                handler.text(extract_text(&value)?)?;
+            match value["event_type"].as_str() {
+                Some("text-generation") => handler.text(extract_text(&value)?)?,
+                Some("stream-end") => handler.finish_reason(value["finish_reason"].as_str()),
+                _ => {}
             }
             Ok(())
         };
@@ -90,9 +95,14 @@ fn build_body(data: SendData, model: &Model) -> Result<Value> {
         mut messages,
         temperature,
         top_p,
+        seed,
+        user: _,
         stream,
+        cache_prompt: _,
+        thread: _,
     } = data;
 
+    fold_system_messages(&mut messages);
     let system_message = extract_system_message(&mut messages);
 
     let mut image_urls = vec![];
@@ -155,6 +165,9 @@ fn build_body(data: SendData, model: &Model) -> Result<Value> {
     if let Some(v) = top_p {
         body["p"] = v.into();
     }
+    if let Some(v) = seed {
+        body["seed"] = v.into();
+    }
     if stream {
         body["stream"] = true.into();
     }
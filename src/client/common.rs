@@ -1,19 +1,37 @@
-use super::{openai::OpenAIConfig, ClientConfig, Message, Model, ReplyHandler};
+use super::{
+    ensure_model_capabilities, init_client, init_client_with_model, list_models,
+    openai::OpenAIConfig, reply_channel, ClientConfig, ClientError, Message, Model, ReplyHandler,
+};
 
 use crate::{
-    config::{GlobalConfig, Input},
+    config::{format_info, DisconnectAction, GlobalConfig, Input, InputContext},
     render::{render_error, render_stream},
-    utils::{prompt_input_integer, prompt_input_string, tokenize, AbortSignal, PromptKind},
+    utils::{
+        prompt_input_integer, prompt_input_string, run_command_output, tokenize, watch_abort,
+        AbortSignal, PromptKind,
+    },
 };
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
 use futures_util::{Stream, StreamExt};
+use lazy_static::lazy_static;
+use nu_ansi_term::Color;
+use parking_lot::Mutex;
 use reqwest::{Client as ReqwestClient, ClientBuilder, Proxy, RequestBuilder};
 use serde::Deserialize;
 use serde_json::{json, Value};
-use std::{env, future::Future, time::Duration};
-use tokio::{sync::mpsc::unbounded_channel, time::sleep};
+use std::{
+    collections::HashMap,
+    env,
+    future::Future,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+lazy_static! {
+    static ref API_KEYS: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
 
 #[macro_export]
 macro_rules! register_client {
@@ -50,8 +68,8 @@ macro_rules! register_client {
             impl $client {
                 pub const NAME: &'static str = $name;
 
-                pub fn init(global_config: &$crate::config::GlobalConfig) -> Option<Box<dyn Client>> {
-                    let model = global_config.read().model.clone();
+                pub fn init(global_config: &$crate::config::GlobalConfig, model: &$crate::client::Model) -> Option<Box<dyn Client>> {
+                    let model = model.clone();
                     let config = global_config.read().clients.iter().find_map(|client_config| {
                         if let ClientConfig::$config(c) = client_config {
                             if Self::name(c) == &model.client_name {
@@ -76,12 +94,15 @@ macro_rules! register_client {
         )+
 
         pub fn init_client(config: &$crate::config::GlobalConfig) -> anyhow::Result<Box<dyn Client>> {
+            let model = config.read().model.clone();
+            init_client_with_model(config, &model)
+        }
+
+        /// Create the client of a model other than the current one
+        pub fn init_client_with_model(config: &$crate::config::GlobalConfig, model: &$crate::client::Model) -> anyhow::Result<Box<dyn Client>> {
             None
-            $(.or_else(|| $client::init(config)))+
-            .ok_or_else(|| {
-                let model = config.read().model.clone();
-                anyhow::anyhow!("Unknown client '{}'", &model.client_name)
-            })
+            $(.or_else(|| $client::init(config, model)))+
+            .ok_or_else(|| anyhow::anyhow!("Unknown client '{}'", &model.client_name))
         }
 
         pub fn ensure_model_capabilities(client: &mut dyn Client, capabilities: $crate::client::ModelCapabilities) -> anyhow::Result<()> {
@@ -116,7 +137,11 @@ macro_rules! register_client {
                 .clients
                 .iter()
                 .flat_map(|v| match v {
-                    $(ClientConfig::$config(c) => $client::list_models(c),)+
+                    $(ClientConfig::$config(c) => $crate::client::Model::merge_registry(
+                        $client::name(c),
+                        $client::list_models(c),
+                        &config.model_registry,
+                    ),)+
                     ClientConfig::Unknown => vec![],
                 })
                 .collect()
@@ -175,9 +200,11 @@ macro_rules! openai_compatible_client {
                 [("api_key", "API Key:", false, PromptKind::String)];
 
             fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
-                let api_key = self.get_api_key().ok();
+                let api_key =
+                    $crate::client::optional_api_key(self.get_api_key(), &self.config.extra)?;
 
-                let body = openai_build_body(data, &self.model);
+                let mut body = openai_build_body(data, &self.model);
+                $crate::client::merge_extra_body(&mut body, &self.config.extra, &self.model);
 
                 let url = format!("{API_BASE}/chat/completions");
 
@@ -208,7 +235,11 @@ macro_rules! client_common_fns {
         }
 
         fn list_models(&self) -> Vec<Model> {
-            Self::list_models(&self.config)
+            Model::merge_registry(
+                Self::name(&self.config),
+                Self::list_models(&self.config),
+                &self.global_config.read().model_registry,
+            )
         }
 
         fn model(&self) -> &Model {
@@ -244,6 +275,7 @@ macro_rules! impl_client_trait {
                 data: $crate::client::SendData,
             ) -> Result<()> {
                 let builder = self.request_builder(client, data)?;
+                handler.set_request(&builder);
                 $send_message_streaming(builder, handler).await
             }
         }
@@ -252,21 +284,36 @@ macro_rules! impl_client_trait {
 
 #[macro_export]
 macro_rules! config_get_fn {
+    (api_key, $fn_name:ident) => {
+        fn $fn_name(&self) -> anyhow::Result<String> {
+            if self.config.api_key.is_none() {
+                if let Some(ret) = $crate::client::api_key_from_command(&self.config.extra) {
+                    return ret;
+                }
+            }
+            $crate::config_get_fn!(@value self, api_key)
+        }
+    };
     ($field_name:ident, $fn_name:ident) => {
         fn $fn_name(&self) -> anyhow::Result<String> {
-            let api_key = self.config.$field_name.clone();
-            api_key
-                .or_else(|| {
-                    let env_prefix = Self::name(&self.config);
-                    let env_name =
-                        format!("{}_{}", env_prefix, stringify!($field_name)).to_ascii_uppercase();
-                    std::env::var(&env_name).ok()
-                })
-                .ok_or_else(|| {
-                    anyhow::anyhow!("Miss '{}' in client configuration", stringify!($field_name))
-                })
+            $crate::config_get_fn!(@value self, $field_name)
         }
     };
+    (@value $self:ident, $field_name:ident) => {
+        $self
+            .config
+            .$field_name
+            .clone()
+            .or_else(|| {
+                let env_prefix = Self::name(&$self.config);
+                let env_name =
+                    format!("{}_{}", env_prefix, stringify!($field_name)).to_ascii_uppercase();
+                std::env::var(&env_name).ok()
+            })
+            .ok_or_else(|| {
+                anyhow::anyhow!("Miss '{}' in client configuration", stringify!($field_name))
+            })
+    };
 }
 
 #[macro_export]
@@ -329,32 +376,24 @@ pub trait Client: Sync + Send {
         Ok(client)
     }
 
-    async fn send_message(&self, input: Input) -> Result<String> {
+    async fn send_message(&self, input: Input) -> Result<String, ClientError> {
         let global_config = self.config().0;
         if global_config.read().dry_run {
             let content = global_config.read().echo_messages(&input);
             return Ok(content);
         }
+        global_config.read().guard_online()?;
         let client = self.build_client()?;
         let data = global_config.read().prepare_send_data(&input, false)?;
-        self.send_message_inner(&client, data)
-            .await
-            .with_context(|| "Failed to get answer")
+        let output = self.send_message_inner(&client, data).await?;
+        Ok(output)
     }
 
     async fn send_message_streaming(
         &self,
         input: &Input,
         handler: &mut ReplyHandler,
-    ) -> Result<()> {
-        async fn watch_abort(abort: AbortSignal) {
-            loop {
-                if abort.aborted() {
-                    break;
-                }
-                sleep(Duration::from_millis(100)).await;
-            }
-        }
+    ) -> Result<(), ClientError> {
         let abort = handler.get_abort();
         let input = input.clone();
         tokio::select! {
@@ -369,12 +408,13 @@ pub trait Client: Sync + Send {
                     }
                     return Ok(());
                 }
+                global_config.read().guard_online()?;
                 let client = self.build_client()?;
                 let data = global_config.read().prepare_send_data(&input, true)?;
                 self.send_message_streaming_inner(&client, handler, data).await
             } => {
                 handler.done()?;
-                ret.with_context(|| "Failed to get answer")
+                ret.map_err(ClientError::from)
             }
             _ = watch_abort(abort.clone()) => {
                 handler.done()?;
@@ -403,6 +443,9 @@ impl Default for ClientConfig {
 pub struct ExtraConfig {
     pub proxy: Option<String>,
     pub connect_timeout: Option<u64>,
+    #[serde(default, deserialize_with = "super::deserialize_json_object")]
+    pub extra_body: Option<serde_json::Map<String, Value>>,
+    pub api_key_command: Option<String>,
 }
 
 #[derive(Debug)]
@@ -410,7 +453,21 @@ pub struct SendData {
     pub messages: Vec<Message>,
     pub temperature: Option<f64>,
     pub top_p: Option<f64>,
+    pub seed: Option<u64>,
+    pub user: Option<String>,
     pub stream: bool,
+    pub cache_prompt: bool,
+    /// Set for requests in a session, clients with server-side threads may continue it instead of the history
+    pub thread: Option<SessionThread>,
+}
+
+/// Where a session stands in the server-side thread of the provider
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionThread {
+    /// No thread yet, or the local history changed since the last reply
+    Start,
+    /// Continue after the reply with this id
+    Continue(String),
 }
 
 pub type PromptType<'a> = (&'a str, &'a str, bool, PromptKind);
@@ -440,37 +497,191 @@ pub fn create_config(list: &[PromptType], client: &str) -> Result<(String, Value
     Ok((model, clients))
 }
 
+/// Send the input once without streaming and return the reply, nothing is printed or saved
+pub async fn ask_once(config: &GlobalConfig, input: Input) -> Result<String, ClientError> {
+    let mut client = init_client(config)?;
+    ensure_model_capabilities(client.as_mut(), input.required_capabilities())?;
+    client.send_message(input).await
+}
+
+/// Send a tiny request outside of any role or session to check the API key and endpoint, returning the latency
+pub async fn ping(config: &GlobalConfig) -> Result<Duration, ClientError> {
+    let input = Input::from_str("Reply with the word pong.", InputContext::new(None, false));
+    let client = init_client(config)?;
+    let start = std::time::Instant::now();
+    client.send_message(input).await?;
+    Ok(start.elapsed())
+}
+
+/// Stream the reply, resending the input to `blocked_fallback_model` if a content filter stopped it or the model refused
 pub async fn send_stream(
     input: &Input,
     client: &dyn Client,
     config: &GlobalConfig,
     abort: AbortSignal,
-) -> Result<String> {
-    let (tx, rx) = unbounded_channel();
-    let mut stream_handler = ReplyHandler::new(tx, abort.clone());
+) -> Result<String, ClientError> {
+    let (output, blocked) = stream_reply(input, client, config, abort.clone()).await?;
+    let Some(reason) = blocked else {
+        return Ok(output);
+    };
+    print_blocked(config, &reason);
+    let fallback = config
+        .read()
+        .blocked_fallback_model
+        .clone()
+        .filter(|v| *v != client.model().id());
+    let Some(model_id) = fallback else {
+        return Ok(output);
+    };
+    let model = Model::find(&list_models(&config.read()), &model_id)
+        .ok_or_else(|| anyhow!("Unknown blocked_fallback_model '{model_id}'"))?;
+    let mut client = init_client_with_model(config, &model)?;
+    ensure_model_capabilities(client.as_mut(), input.required_capabilities())?;
+    println!(
+        "{}",
+        Color::DarkGray.paint(format!("Retrying with {model_id}"))
+    );
+    let (output, blocked) = stream_reply(input, client.as_ref(), config, abort).await?;
+    if let Some(reason) = blocked {
+        print_blocked(config, &reason);
+    }
+    Ok(output)
+}
+
+/// Stream the reply, also returning the finish reason if it was blocked
+async fn stream_reply(
+    input: &Input,
+    client: &dyn Client,
+    config: &GlobalConfig,
+    abort: AbortSignal,
+) -> Result<(String, Option<String>), ClientError> {
+    let (mut stream_handler, stream) = reply_channel(abort.clone());
 
+    let started_at = Instant::now();
     let (send_ret, rend_ret) = tokio::join!(
         client.send_message_streaming(input, &mut stream_handler),
-        render_stream(rx, config, abort.clone()),
+        render_stream(stream, config, abort.clone()),
     );
-    if let Err(err) = rend_ret {
-        render_error(err, config.read().highlight);
-    }
+    let elapsed = started_at.elapsed();
+    let raw_from = rend_ret.unwrap_or_else(|err| {
+        render_error(err, config.read().error_color());
+        None
+    });
     let output = stream_handler.get_buffer().to_string();
-    match send_ret {
+    let ret = match send_ret {
         Ok(_) => {
-            println!();
-            Ok(output)
+            if let Some(usage) = stream_handler.get_usage() {
+                config.write().record_usage(input, &output, usage);
+            }
+            config.write().last_reply_truncated = stream_handler.is_truncated() || abort.aborted();
+            config.write().last_thread_id = stream_handler.get_thread_id().map(|v| v.to_string());
+            end_line(&output);
+            Ok((output, stream_handler.get_blocked().map(|v| v.to_string())))
+        }
+        Err(ClientError::Network(err))
+            if !output.is_empty() && config.read().on_disconnect == DisconnectAction::Keep =>
+        {
+            end_line(&output);
+            config.write().last_reply_truncated = true;
+            let message = format!(
+                "Connection lost ({err}), kept the partial reply, run `.continue` to finish it"
+            );
+            match config.read().error_color() {
+                Some(color) => eprintln!("{}", color.paint(message)),
+                None => eprintln!("{message}"),
+            }
+            Ok((output, None))
         }
         Err(err) => {
             if !output.is_empty() {
-                println!();
+                end_line(&output);
             }
             Err(err)
         }
+    };
+    if config.read().verbose {
+        let first_text_elapsed = stream_handler
+            .get_first_text_at()
+            .map(|v| v.duration_since(started_at));
+        print_verbose_info(
+            config,
+            &stream_handler,
+            elapsed,
+            first_text_elapsed,
+            raw_from,
+            &abort,
+            ret.as_ref().err(),
+        );
+    }
+    ret
+}
+
+/// Mark a reply stopped by a content filter or refused by the model
+fn print_blocked(config: &GlobalConfig, reason: &str) {
+    let text = format!("⛔ Blocked by the provider ({reason})");
+    match config.read().error_color() {
+        Some(color) => eprintln!("{}", color.paint(text)),
+        None => eprintln!("{text}"),
     }
 }
 
+/// Move to a new line unless the streamed output already ended with one
+fn end_line(output: &str) {
+    if !output.ends_with('\n') {
+        println!();
+    }
+}
+
+fn print_verbose_info(
+    config: &GlobalConfig,
+    handler: &ReplyHandler,
+    elapsed: Duration,
+    first_text_elapsed: Option<Duration>,
+    raw_from: Option<usize>,
+    abort: &AbortSignal,
+    err: Option<&ClientError>,
+) {
+    let mut items = vec![];
+    if let Some(url) = handler.get_request_url() {
+        items.push(("url", url.to_string()));
+    }
+    if let Some(status) = handler.get_status() {
+        items.push(("status", status.to_string()));
+    }
+    let mut latency = format!("{:.2}s", elapsed.as_secs_f64());
+    if let Some(v) = first_text_elapsed {
+        latency.push_str(&format!(" (first token {:.2}s)", v.as_secs_f64()));
+    }
+    items.push(("latency", latency));
+    if let Some(rows) = raw_from {
+        items.push(("render", format!("raw text after {rows} rows")));
+    }
+    items.push(("abort", abort.state().to_string()));
+    if let Some(ClientError::Api { body, .. }) = err {
+        items.push(("body", body.trim().to_string()));
+    }
+    if let Some(usage) = handler.get_usage() {
+        items.push((
+            "usage",
+            format!(
+                "{} input, {} output",
+                usage.input_tokens, usage.output_tokens
+            ),
+        ));
+    }
+    let color = if config.read().light_theme {
+        Color::LightGray
+    } else {
+        Color::DarkGray
+    };
+    let lines = [
+        "--- verbose ---".to_string(),
+        format_info(&items),
+        "---------------".to_string(),
+    ];
+    println!("{}", color.paint(lines.join("\n")));
+}
+
 #[allow(unused)]
 pub async fn send_message_as_streaming<F, Fut>(
     builder: RequestBuilder,
@@ -488,39 +699,98 @@ where
     Ok(())
 }
 
+/// Get the API key from the stdout of `api_key_command`, the command runs once per process
+pub fn api_key_from_command(extra: &Option<ExtraConfig>) -> Option<Result<String>> {
+    let command = extra.as_ref()?.api_key_command.as_ref()?;
+    if let Some(api_key) = API_KEYS.lock().get(command) {
+        return Some(Ok(api_key.clone()));
+    }
+    let ret = run_command_output(command)
+        .and_then(|output| {
+            let api_key = output.trim().to_string();
+            if api_key.is_empty() {
+                bail!("No output");
+            }
+            Ok(api_key)
+        })
+        .with_context(|| format!("Failed to get api key from api_key_command `{command}`"));
+    if let Ok(api_key) = &ret {
+        API_KEYS.lock().insert(command.clone(), api_key.clone());
+    }
+    Some(ret)
+}
+
+/// Treat a missing API key as none, unless it should come from `api_key_command`
+pub fn optional_api_key(
+    api_key: Result<String>,
+    extra: &Option<ExtraConfig>,
+) -> Result<Option<String>> {
+    match api_key {
+        Ok(api_key) => Ok(Some(api_key)),
+        Err(err) if extra.as_ref().is_some_and(|v| v.api_key_command.is_some()) => Err(err),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Merge the `extra_body` of the client and then the `extra_fields` of the model into the request body, overriding fields set by aichat
+pub fn merge_extra_body(body: &mut Value, extra: &Option<ExtraConfig>, model: &Model) {
+    let client_extra_body = extra.as_ref().and_then(|v| v.extra_body.as_ref());
+    if let (Some(body), Some(extra_body)) = (body.as_object_mut(), client_extra_body) {
+        for (key, value) in extra_body {
+            body.insert(key.clone(), value.clone());
+        }
+    }
+    model.merge_extra_fields(body);
+}
+
+/// Print a notice the first time a seed is set for a model that can't honor it
+pub fn notice_unsupported_seed(model: &Model) {
+    static NOTICED: AtomicBool = AtomicBool::new(false);
+    if !NOTICED.swap(true, Ordering::Relaxed) {
+        eprintln!(
+            "Note: '{}' does not support seed, outputs may not be reproducible",
+            model.id()
+        );
+    }
+}
+
 pub fn catch_error(data: &Value, status: u16) -> Result<()> {
     if (200..300).contains(&status) {
         return Ok(());
     }
     debug!("Invalid response, status: {status}, data: {data}");
+    let mut message = None;
     if let Some(error) = data["error"].as_object() {
-        if let (Some(typ), Some(message)) = (error["type"].as_str(), error["message"].as_str()) {
-            bail!("{message} (type: {typ})");
+        if let (Some(typ), Some(msg)) = (error["type"].as_str(), error["message"].as_str()) {
+            message = Some(format!("{msg} (type: {typ})"));
         }
     } else if let Some(error) = data[0]["error"].as_object() {
-        if let (Some(status), Some(message)) = (error["status"].as_str(), error["message"].as_str())
-        {
-            bail!("{message} (status: {status})")
+        if let (Some(status), Some(msg)) = (error["status"].as_str(), error["message"].as_str()) {
+            message = Some(format!("{msg} (status: {status})"));
         }
     } else if let Some(error) = data["error"].as_str() {
-        bail!("{error}");
-    } else if let Some(message) = data["message"].as_str() {
-        bail!("{message}");
+        message = Some(error.to_string());
+    } else if let Some(msg) = data["message"].as_str() {
+        message = Some(msg.to_string());
     }
-    bail!("Invalid response data: {data} (status: {status})");
+    let message =
+        message.unwrap_or_else(|| format!("Invalid response data: {data} (status: {status})"));
+    Err(ClientError::from_response(status, data.to_string(), message).into())
 }
 
 pub fn maybe_catch_error(data: &Value) -> Result<()> {
-    if let (Some(code), Some(message)) = (data["code"].as_str(), data["message"].as_str()) {
-        debug!("Invalid response: {}", data);
-        bail!("{message} (code: {code})");
-    } else if let (Some(error_code), Some(error_msg)) =
-        (data["error_code"].as_number(), data["error_msg"].as_str())
-    {
-        debug!("Invalid response: {}", data);
-        bail!("{error_msg} (error_code: {error_code})");
-    }
-    Ok(())
+    let message =
+        if let (Some(code), Some(message)) = (data["code"].as_str(), data["message"].as_str()) {
+            format!("{message} (code: {code})")
+        } else if let (Some(error_code), Some(error_msg)) =
+            (data["error_code"].as_number(), data["error_msg"].as_str())
+        {
+            format!("{error_msg} (error_code: {error_code})")
+        } else {
+            return Ok(());
+        };
+    debug!("Invalid response: {}", data);
+    Err(ClientError::from_response(200, data.to_string(), message).into())
 }
 
 pub async fn json_stream<S, F>(mut stream: S, mut handle: F) -> Result<()>
@@ -562,10 +832,8 @@ where
                     }
                     balances.push(ch);
                 }
-                '[' => {
-                    if start != 0 {
-                        balances.push(ch);
-                    }
+                '[' if start != 0 => {
+                    balances.push(ch);
                 }
                 '}' => {
                     balances.pop();
@@ -641,3 +909,66 @@ fn set_proxy(builder: ClientBuilder, proxy: &Option<String>) -> Result<ClientBui
         builder.proxy(Proxy::all(&proxy).with_context(|| format!("Invalid proxy `{proxy}`"))?);
     Ok(builder)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_json_stream() {
+        let chunks = ["[{\"text\": \"a:b\"},\n", "{\"text\": \"}", "c\"}", "]"];
+        let stream = futures_util::stream::iter(
+            chunks.map(|v| Ok::<_, reqwest::Error>(bytes::Bytes::from(v))),
+        );
+        let mut values = vec![];
+        json_stream(stream, |value| {
+            let value: Value = serde_json::from_str(value)?;
+            values.push(value["text"].as_str().unwrap_or_default().to_string());
+            Ok(())
+        })
+        .await
+        .unwrap();
+        assert_eq!(values, ["a:b", "}c"]);
+    }
+
+    #[test]
+    fn test_merge_extra_body() {
+        let mut body = json!({ "model": "a", "stream": true, "options": { "top_k": 1 } });
+        let extra = Some(ExtraConfig {
+            extra_body: json!({ "model": "b", "seed": 1 }).as_object().cloned(),
+            ..Default::default()
+        });
+        let model = Model::new("test", "a").set_extra_fields(
+            json!({ "seed": 2, "options": { "top_k": 2, "num_ctx": 4096 } })
+                .as_object()
+                .cloned(),
+        );
+        merge_extra_body(&mut body, &extra, &model);
+        assert_eq!(
+            body,
+            json!({
+                "model": "b",
+                "stream": true,
+                "seed": 2,
+                "options": { "top_k": 2, "num_ctx": 4096 }
+            })
+        );
+    }
+
+    #[test]
+    fn test_api_key_from_command() {
+        let extra = |command: &str| {
+            Some(ExtraConfig {
+                api_key_command: Some(command.into()),
+                ..Default::default()
+            })
+        };
+        assert!(api_key_from_command(&None).is_none());
+        let api_key = api_key_from_command(&extra("echo ' sk-test '")).unwrap();
+        assert_eq!(api_key.unwrap(), "sk-test");
+        assert!(api_key_from_command(&extra("exit 3")).unwrap().is_err());
+        assert!(optional_api_key(Err(anyhow::anyhow!("missing")), &None)
+            .unwrap()
+            .is_none());
+    }
+}
@@ -1,5 +1,6 @@
 use super::{
-    maybe_catch_error, patch_system_message, Client, ErnieClient, ExtraConfig, Model, ModelConfig,
+    fold_system_messages, maybe_catch_error, merge_extra_body, notice_unsupported_seed,
+    patch_system_message, Client, ClientError, ErnieClient, ExtraConfig, Model, ModelConfig,
     PromptType, ReplyHandler, SendData,
 };
 
@@ -9,6 +10,7 @@ use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
 use chrono::Utc;
 use futures_util::StreamExt;
+use parking_lot::Mutex;
 use reqwest::{Client as ReqwestClient, RequestBuilder};
 use reqwest_eventsource::{Error as EventSourceError, Event, RequestBuilderExt};
 use serde::Deserialize;
@@ -18,7 +20,7 @@ use std::env;
 const API_BASE: &str = "https://aip.baidubce.com/rpc/2.0/ai_custom/v1";
 const ACCESS_TOKEN_URL: &str = "https://aip.baidubce.com/oauth/2.0/token";
 
-static mut ACCESS_TOKEN: (String, i64) = (String::new(), 0);
+static ACCESS_TOKEN: Mutex<(String, i64)> = Mutex::new((String::new(), 0));
 
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct ErnieConfig {
@@ -51,7 +53,8 @@ impl ErnieClient {
     ];
 
     fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
-        let body = build_body(data, &self.model);
+        let mut body = build_body(data, &self.model);
+        merge_extra_body(&mut body, &self.config.extra, &self.model);
 
         let endpoint = match self.model.name.as_str() {
             "ernie-4.0-8k" => "completions_pro",
@@ -63,7 +66,7 @@ impl ErnieClient {
 
         let url = format!(
             "{API_BASE}/wenxinworkshop/chat/{endpoint}?access_token={}",
-            unsafe { &ACCESS_TOKEN.0 }
+            &ACCESS_TOKEN.lock().0
         );
 
         debug!("Ernie Request: {url} {body}");
@@ -74,7 +77,11 @@ impl ErnieClient {
     }
 
     async fn prepare_access_token(&self) -> Result<()> {
-        if unsafe { ACCESS_TOKEN.0.is_empty() || Utc::now().timestamp() > ACCESS_TOKEN.1 } {
+        let expired = {
+            let token = ACCESS_TOKEN.lock();
+            token.0.is_empty() || Utc::now().timestamp() > token.1
+        };
+        if expired {
             let env_prefix = Self::name(&self.config).to_uppercase();
             let api_key = self.config.api_key.clone();
             let api_key = api_key
@@ -90,7 +97,7 @@ impl ErnieClient {
             let token = fetch_access_token(&client, &api_key, &secret_key)
                 .await
                 .with_context(|| "Failed to fetch access token")?;
-            unsafe { ACCESS_TOKEN = (token, 86400) };
+            *ACCESS_TOKEN.lock() = (token, 86400);
         }
         Ok(())
     }
@@ -114,6 +121,7 @@ impl Client for ErnieClient {
     ) -> Result<()> {
         self.prepare_access_token().await?;
         let builder = self.request_builder(client, data)?;
+        handler.set_request(&builder);
         send_message_streaming(builder, handler).await
     }
 }
@@ -133,12 +141,13 @@ async fn send_message_streaming(builder: RequestBuilder, handler: &mut ReplyHand
     let mut es = builder.eventsource()?;
     while let Some(event) = es.next().await {
         match event {
-            Ok(Event::Open) => {}
+            Ok(Event::Open) => handler.set_status(200),
             Ok(Event::Message(message)) => {
                 let data: Value = serde_json::from_str(&message.data)?;
                 if let Some(text) = data["result"].as_str() {
                     handler.text(text)?;
                 }
+                handler.finish_reason(data["finish_reason"].as_str());
             }
             Err(err) => {
                 match err {
@@ -163,6 +172,9 @@ async fn send_message_streaming(builder: RequestBuilder, handler: &mut ReplyHand
                         }
                     }
                     EventSourceError::StreamEnded => {}
+                    EventSourceError::Transport(err) => {
+                        return Err(ClientError::Network(err).into());
+                    }
                     _ => {
                         bail!("{}", err);
                     }
@@ -180,9 +192,14 @@ fn build_body(data: SendData, model: &Model) -> Value {
         mut messages,
         temperature,
         top_p,
+        seed,
+        user: _,
         stream,
+        cache_prompt: _,
+        thread: _,
     } = data;
 
+    fold_system_messages(&mut messages);
     patch_system_message(&mut messages);
 
     let mut body = json!({
@@ -198,6 +215,9 @@ fn build_body(data: SendData, model: &Model) -> Value {
     if let Some(v) = top_p {
         body["top_p"] = v.into();
     }
+    if seed.is_some() {
+        notice_unsupported_seed(model);
+    }
 
     if stream {
         body["stream"] = true.into();
@@ -0,0 +1,104 @@
+use std::fmt;
+
+/// Errors returned by `Client::send_message`, `Client::send_message_streaming` and `send_stream`
+#[derive(Debug)]
+pub enum ClientError {
+    /// The API rejected the request with status 429
+    RateLimited(String),
+    /// The API rejected the credentials with status 401 or 403
+    Auth(String),
+    /// The request could not be sent or the connection broke
+    Network(reqwest::Error),
+    /// Any other error reported by the API
+    Api {
+        status: u16,
+        /// The raw response body, shown in the verbose output
+        body: String,
+        message: String,
+    },
+    Other(anyhow::Error),
+}
+
+impl ClientError {
+    pub fn from_response(status: u16, body: String, message: String) -> Self {
+        match status {
+            401 | 403 => Self::Auth(message),
+            429 => Self::RateLimited(message),
+            _ => Self::Api {
+                status,
+                body,
+                message,
+            },
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RateLimited(message) | Self::Auth(message) => write!(f, "{message}"),
+            Self::Api { message, .. } => write!(f, "{message}"),
+            Self::Network(err) => write!(f, "{err}"),
+            Self::Other(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Network(err) => err.source(),
+            Self::Other(err) => err.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_decode() {
+            Self::Other(err.into())
+        } else {
+            Self::Network(err)
+        }
+    }
+}
+
+impl From<anyhow::Error> for ClientError {
+    fn from(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<ClientError>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        match err.downcast::<reqwest::Error>() {
+            Ok(err) => err.into(),
+            Err(err) => Self::Other(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_anyhow() {
+        let err: anyhow::Error =
+            ClientError::from_response(429, "{}".into(), "slow down".into()).into();
+        assert!(matches!(
+            ClientError::from(err),
+            ClientError::RateLimited(_)
+        ));
+        let err: anyhow::Error =
+            ClientError::from_response(401, "{}".into(), "bad key".into()).into();
+        assert!(matches!(ClientError::from(err), ClientError::Auth(_)));
+        let err: anyhow::Error = ClientError::from_response(500, "{}".into(), "oops".into()).into();
+        assert!(matches!(
+            ClientError::from(err),
+            ClientError::Api { status: 500, .. }
+        ));
+        let err = ClientError::from(anyhow::anyhow!("other"));
+        assert!(matches!(err, ClientError::Other(_)));
+        assert_eq!(err.to_string(), "other");
+    }
+}
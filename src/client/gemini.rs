@@ -1,5 +1,7 @@
 use super::vertexai::gemini_build_body;
-use super::{ExtraConfig, GeminiClient, Model, ModelConfig, PromptType, SendData};
+use super::{
+    merge_extra_body, ExtraConfig, GeminiClient, Model, ModelConfig, PromptType, SendData,
+};
 
 use crate::utils::PromptKind;
 
@@ -44,7 +46,8 @@ impl GeminiClient {
 
         let block_threshold = self.config.block_threshold.clone();
 
-        let body = gemini_build_body(data, &self.model, block_threshold)?;
+        let mut body = gemini_build_body(data, &self.model, block_threshold)?;
+        merge_extra_body(&mut body, &self.config.extra, &self.model);
 
         let model = &self.model.name;
 
@@ -127,6 +127,39 @@ pub fn patch_system_message(messages: &mut Vec<Message>) {
     }
 }
 
+/// Fold the system messages after the first one into the user message that follows them,
+/// for APIs that only accept a leading system prompt
+pub fn fold_system_messages(messages: &mut Vec<Message>) {
+    let mut pending: Vec<String> = vec![];
+    let mut output = Vec::with_capacity(messages.len());
+    for (i, mut message) in messages.drain(..).enumerate() {
+        if i > 0 && message.role.is_system() {
+            pending.push(message.content.to_text());
+            continue;
+        }
+        if !pending.is_empty() && message.role.is_user() {
+            let system_text = std::mem::take(&mut pending).join("\n\n");
+            message.content = match message.content {
+                MessageContent::Text(text) => {
+                    MessageContent::Text(format!("{system_text}\n\n{text}"))
+                }
+                MessageContent::Array(mut list) => {
+                    list.insert(0, MessageContentPart::Text { text: system_text });
+                    MessageContent::Array(list)
+                }
+            };
+        }
+        output.push(message);
+    }
+    if !pending.is_empty() {
+        output.push(Message {
+            role: MessageRole::User,
+            content: MessageContent::Text(pending.join("\n\n")),
+        });
+    }
+    *messages = output;
+}
+
 pub fn extract_system_message(messages: &mut Vec<Message>) -> Option<String> {
     if messages[0].role.is_system() {
         let system_message = messages.remove(0);
@@ -151,4 +184,24 @@ mod tests {
             "{\"role\":\"user\",\"content\":\"Hello World\"}"
         );
     }
+
+    #[test]
+    fn test_fold_system_messages() {
+        let message = |role, text: &str| Message {
+            role,
+            content: MessageContent::Text(text.into()),
+        };
+        let mut messages = vec![
+            message(MessageRole::System, "sys"),
+            message(MessageRole::User, "u1"),
+            message(MessageRole::Assistant, "a1"),
+            message(MessageRole::System, "be brief"),
+            message(MessageRole::User, "u2"),
+        ];
+        fold_system_messages(&mut messages);
+        let texts: Vec<_> = messages.iter().map(|v| v.content.to_text()).collect();
+        assert_eq!(texts, ["sys", "u1", "a1", "be brief\n\nu2"]);
+        assert!(messages[0].role.is_system());
+        assert!(messages[3].role.is_user());
+    }
 }
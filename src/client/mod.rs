@@ -1,10 +1,12 @@
 #[macro_use]
 mod common;
+mod error;
 mod message;
 mod model;
 mod reply_handler;
 
 pub use common::*;
+pub use error::*;
 pub use message::*;
 pub use model::*;
 pub use reply_handler::*;
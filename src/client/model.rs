@@ -8,12 +8,18 @@ use serde::{Deserialize, Deserializer};
 const PER_MESSAGES_TOKENS: usize = 5;
 const BASIS_TOKENS: usize = 2;
 
+pub const JSON_OBJECT_ERROR: &str = "`extra_fields` and `extra_body` must be JSON objects";
+
 #[derive(Debug, Clone)]
 pub struct Model {
     pub client_name: String,
     pub name: String,
     pub max_input_tokens: Option<usize>,
     pub max_output_tokens: Option<isize>,
+    pub input_price: Option<f64>,
+    pub output_price: Option<f64>,
+    pub default_temperature: Option<f64>,
+    pub default_top_p: Option<f64>,
     pub extra_fields: Option<serde_json::Map<String, serde_json::Value>>,
     pub capabilities: ModelCapabilities,
 }
@@ -32,6 +38,10 @@ impl Model {
             extra_fields: None,
             max_input_tokens: None,
             max_output_tokens: None,
+            input_price: None,
+            output_price: None,
+            default_temperature: None,
+            default_top_p: None,
             capabilities: ModelCapabilities::Text,
         }
     }
@@ -44,11 +54,30 @@ impl Model {
                     .set_capabilities(v.capabilities)
                     .set_max_input_tokens(v.max_input_tokens)
                     .set_max_output_tokens(v.max_output_tokens)
+                    .set_prices(v.input_price, v.output_price)
+                    .set_default_sampling(v.default_temperature, v.default_top_p)
                     .set_extra_fields(v.extra_fields.clone())
             })
             .collect()
     }
 
+    /// Merge the models of the registry into the builtin/client models, entries with the same name are overridden
+    pub fn merge_registry(
+        client_name: &str,
+        mut models: Vec<Self>,
+        registry: &[ModelRegistry],
+    ) -> Vec<Self> {
+        for item in registry.iter().filter(|v| v.client == client_name) {
+            for model in Self::from_config(client_name, &item.models) {
+                match models.iter_mut().find(|v| v.name == model.name) {
+                    Some(found) => *found = model,
+                    None => models.push(model),
+                }
+            }
+        }
+        models
+    }
+
     pub fn find(models: &[Self], value: &str) -> Option<Self> {
         let mut model = None;
         let (client_name, model_name) = match value.split_once(':') {
@@ -80,6 +109,15 @@ impl Model {
         model
     }
 
+    /// The models whose id contains `value`, ignoring case
+    pub fn search<'a>(models: &'a [Self], value: &str) -> Vec<&'a Self> {
+        let value = value.to_lowercase();
+        models
+            .iter()
+            .filter(|v| v.id().to_lowercase().contains(&value))
+            .collect()
+    }
+
     pub fn id(&self) -> String {
         format!("{}:{}", self.client_name, self.name)
     }
@@ -97,6 +135,12 @@ impl Model {
         self
     }
 
+    pub fn set_default_sampling(mut self, temperature: Option<f64>, top_p: Option<f64>) -> Self {
+        self.default_temperature = temperature;
+        self.default_top_p = top_p;
+        self
+    }
+
     pub fn set_max_input_tokens(mut self, max_input_tokens: Option<usize>) -> Self {
         match max_input_tokens {
             None | Some(0) => self.max_input_tokens = None,
@@ -113,6 +157,19 @@ impl Model {
         self
     }
 
+    pub fn set_prices(mut self, input_price: Option<f64>, output_price: Option<f64>) -> Self {
+        self.input_price = input_price;
+        self.output_price = output_price;
+        self
+    }
+
+    /// Estimate the cost in USD, prices are per 1M tokens
+    pub fn estimate_cost(&self, input_tokens: usize, output_tokens: usize) -> Option<f64> {
+        let input_cost = self.input_price? * input_tokens as f64;
+        let output_cost = self.output_price.unwrap_or_default() * output_tokens as f64;
+        Some((input_cost + output_cost) / 1_000_000.0)
+    }
+
     pub fn messages_tokens(&self, messages: &[Message]) -> usize {
         messages
             .iter()
@@ -148,21 +205,22 @@ impl Model {
         Ok(())
     }
 
+    /// Merge `extra_fields` into the request body, overriding fields set by aichat, objects are merged key by key
     pub fn merge_extra_fields(&self, body: &mut serde_json::Value) {
         if let (Some(body), Some(extra_fields)) = (body.as_object_mut(), &self.extra_fields) {
             for (key, extra_field) in extra_fields {
-                if body.contains_key(key) {
-                    if let (Some(sub_body), Some(extra_field)) =
-                        (body[key].as_object_mut(), extra_field.as_object())
-                    {
+                match (
+                    body.get_mut(key).and_then(|v| v.as_object_mut()),
+                    extra_field.as_object(),
+                ) {
+                    (Some(sub_body), Some(extra_field)) => {
                         for (subkey, sub_field) in extra_field {
-                            if !sub_body.contains_key(subkey) {
-                                sub_body.insert(subkey.clone(), sub_field.clone());
-                            }
+                            sub_body.insert(subkey.clone(), sub_field.clone());
                         }
                     }
-                } else {
-                    body.insert(key.clone(), extra_field.clone());
+                    _ => {
+                        body.insert(key.clone(), extra_field.clone());
+                    }
                 }
             }
         }
@@ -174,12 +232,24 @@ pub struct ModelConfig {
     pub name: String,
     pub max_input_tokens: Option<usize>,
     pub max_output_tokens: Option<isize>,
+    pub input_price: Option<f64>,
+    pub output_price: Option<f64>,
+    pub default_temperature: Option<f64>,
+    pub default_top_p: Option<f64>,
+    #[serde(default, deserialize_with = "deserialize_json_object")]
     pub extra_fields: Option<serde_json::Map<String, serde_json::Value>>,
     #[serde(deserialize_with = "deserialize_capabilities")]
     #[serde(default = "default_capabilities")]
     pub capabilities: ModelCapabilities,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModelRegistry {
+    pub client: String,
+    #[serde(default)]
+    pub models: Vec<ModelConfig>,
+}
+
 bitflags::bitflags! {
     #[derive(Debug, Clone, Copy, PartialEq)]
     pub struct ModelCapabilities: u32 {
@@ -202,6 +272,19 @@ impl From<&str> for ModelCapabilities {
     }
 }
 
+impl ModelCapabilities {
+    pub fn stringify(&self) -> String {
+        let mut names = vec![];
+        if self.contains(ModelCapabilities::Text) {
+            names.push("text");
+        }
+        if self.contains(ModelCapabilities::Vision) {
+            names.push("vision");
+        }
+        names.join(",")
+    }
+}
+
 fn deserialize_capabilities<'de, D>(deserializer: D) -> Result<ModelCapabilities, D::Error>
 where
     D: Deserializer<'de>,
@@ -210,6 +293,87 @@ where
     Ok(value.as_str().into())
 }
 
+/// Reject a non-object `extra_fields`/`extra_body`, which can't be merged into the request body
+pub(crate) fn deserialize_json_object<'de, D>(
+    deserializer: D,
+) -> Result<Option<serde_json::Map<String, serde_json::Value>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<serde_json::Value>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(serde_json::Value::Object(map)) => Ok(Some(map)),
+        Some(value) => Err(serde::de::Error::custom(format!(
+            "{JSON_OBJECT_ERROR}, found `{value}`"
+        ))),
+    }
+}
+
 fn default_capabilities() -> ModelCapabilities {
     ModelCapabilities::Text
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_registry() {
+        let models = vec![
+            Model::new("openai", "gpt-3.5-turbo").set_max_input_tokens(Some(16385)),
+            Model::new("openai", "gpt-4").set_max_input_tokens(Some(8192)),
+        ];
+        let registry: Vec<ModelRegistry> = serde_yaml::from_str(
+            r#"
+- client: openai
+  models:
+    - name: gpt-4
+      max_input_tokens: 32768
+      input_price: 30
+    - name: gpt-4o
+      capabilities: text,vision
+- client: claude
+  models:
+    - name: claude-3-opus-20240229
+"#,
+        )
+        .unwrap();
+        let models = Model::merge_registry("openai", models, &registry);
+        let ids: Vec<String> = models.iter().map(|v| v.id()).collect();
+        assert_eq!(
+            ids,
+            ["openai:gpt-3.5-turbo", "openai:gpt-4", "openai:gpt-4o"]
+        );
+        assert_eq!(models[1].max_input_tokens, Some(32768));
+        assert_eq!(models[1].estimate_cost(1000, 0), Some(0.03));
+        assert_eq!(models[0].estimate_cost(1000, 0), None);
+        assert!(models[2].capabilities.contains(ModelCapabilities::Vision));
+    }
+
+    #[test]
+    fn test_search() {
+        let models = vec![
+            Model::new("openai", "gpt-3.5-turbo"),
+            Model::new("openai", "gpt-4"),
+            Model::new("claude", "claude-3-opus-20240229"),
+        ];
+        let ids = |value| -> Vec<String> {
+            Model::search(&models, value)
+                .iter()
+                .map(|v| v.id())
+                .collect()
+        };
+        assert_eq!(ids("GPT"), ["openai:gpt-3.5-turbo", "openai:gpt-4"]);
+        assert_eq!(ids("opus"), ["claude:claude-3-opus-20240229"]);
+        assert!(ids("mistral").is_empty());
+    }
+
+    #[test]
+    fn test_extra_fields_object() {
+        let config: ModelConfig =
+            serde_yaml::from_str("name: a\nextra_fields:\n  seed: 1").unwrap();
+        assert!(config.extra_fields.is_some());
+        let err = serde_yaml::from_str::<ModelConfig>("name: a\nextra_fields: 1").unwrap_err();
+        assert!(err.to_string().contains(JSON_OBJECT_ERROR));
+    }
+}
@@ -1,6 +1,6 @@
 use super::{
-    catch_error, message::*, ExtraConfig, Model, ModelConfig, OllamaClient, PromptType,
-    ReplyHandler, SendData,
+    catch_error, merge_extra_body, message::*, optional_api_key, ExtraConfig, Model, ModelConfig,
+    OllamaClient, PromptType, ReplyHandler, SendData,
 };
 
 use crate::utils::PromptKind;
@@ -38,10 +38,10 @@ impl OllamaClient {
     ];
 
     fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
-        let api_key = self.get_api_key().ok();
+        let api_key = optional_api_key(self.get_api_key(), &self.config.extra)?;
 
         let mut body = build_body(data, &self.model)?;
-        self.model.merge_extra_fields(&mut body);
+        merge_extra_body(&mut body, &self.config.extra, &self.model);
 
         let chat_endpoint = self.config.chat_endpoint.as_deref().unwrap_or("/api/chat");
 
@@ -76,6 +76,7 @@ async fn send_message(builder: RequestBuilder) -> Result<String> {
 async fn send_message_streaming(builder: RequestBuilder, handler: &mut ReplyHandler) -> Result<()> {
     let res = builder.send().await?;
     let status = res.status();
+    handler.set_status(status.as_u16());
     if status != 200 {
         let data = res.json().await?;
         catch_error(&data, status.as_u16())?;
@@ -91,6 +92,7 @@ async fn send_message_streaming(builder: RequestBuilder, handler: &mut ReplyHand
                 if let Some(text) = data["message"]["content"].as_str() {
                     handler.text(text)?;
                 }
+                handler.finish_reason(data["done_reason"].as_str());
             } else {
                 bail!("Invalid response data: {data}")
             }
@@ -104,7 +106,11 @@ fn build_body(data: SendData, model: &Model) -> Result<Value> {
         messages,
         temperature,
         top_p,
+        seed,
+        user: _,
         stream,
+        cache_prompt: _,
+        thread: _,
     } = data;
 
     let mut network_image_urls = vec![];
@@ -169,6 +175,9 @@ fn build_body(data: SendData, model: &Model) -> Result<Value> {
     if let Some(v) = top_p {
         body["options"]["top_p"] = v.into();
     }
+    if let Some(v) = seed {
+        body["options"]["seed"] = v.into();
+    }
 
     Ok(body)
 }
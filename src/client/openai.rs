@@ -1,5 +1,7 @@
 use super::{
-    catch_error, ExtraConfig, Model, ModelConfig, OpenAIClient, PromptType, ReplyHandler, SendData,
+    catch_error, merge_extra_body, notice_unsupported_seed, ClientError, ExtraConfig, Message,
+    MessageContent, MessageContentPart, MessageRole, Model, ModelConfig, OpenAIClient, PromptType,
+    ReplyHandler, SendData, SessionThread,
 };
 
 use crate::utils::PromptKind;
@@ -19,6 +21,9 @@ pub struct OpenAIConfig {
     pub api_key: Option<String>,
     pub api_base: Option<String>,
     pub organization_id: Option<String>,
+    /// Keep sessions as server-side threads of the Responses API, sending only the new turn
+    #[serde(default)]
+    pub threads: bool,
     #[serde(default)]
     pub models: Vec<ModelConfig>,
     pub extra: Option<ExtraConfig>,
@@ -49,9 +54,20 @@ impl OpenAIClient {
         let api_key = self.get_api_key()?;
         let api_base = self.get_api_base().unwrap_or_else(|_| API_BASE.to_string());
 
-        let body = openai_build_body(data, &self.model);
-
-        let url = format!("{api_base}/chat/completions");
+        let (mut body, url) = match data.thread.clone().filter(|_| self.config.threads) {
+            Some(thread) => (
+                openai_build_responses_body(data, thread, &self.model),
+                format!("{api_base}/responses"),
+            ),
+            None => {
+                let mut body = openai_build_body(data, &self.model);
+                if body["stream"].as_bool() == Some(true) {
+                    body["stream_options"] = json!({ "include_usage": true });
+                }
+                (body, format!("{api_base}/chat/completions"))
+            }
+        };
+        merge_extra_body(&mut body, &self.config.extra, &self.model);
 
         debug!("OpenAI Request: {url} {body}");
 
@@ -73,6 +89,10 @@ pub async fn openai_send_message(builder: RequestBuilder) -> Result<String> {
         catch_error(&data, status.as_u16())?;
     }
 
+    if data["object"] == "response" {
+        return responses_output(&data);
+    }
+
     let output = data["choices"][0]["message"]["content"]
         .as_str()
         .ok_or_else(|| anyhow!("Invalid response data: {data}"))?;
@@ -87,19 +107,36 @@ pub async fn openai_send_message_streaming(
     let mut es = builder.eventsource()?;
     while let Some(event) = es.next().await {
         match event {
-            Ok(Event::Open) => {}
+            Ok(Event::Open) => handler.set_status(200),
             Ok(Event::Message(message)) => {
                 if message.data == "[DONE]" {
                     break;
                 }
                 let data: Value = serde_json::from_str(&message.data)?;
+                if let Some(event_type) = data["type"].as_str() {
+                    responses_event(event_type, &data, handler)?;
+                    continue;
+                }
                 if let Some(text) = data["choices"][0]["delta"]["content"].as_str() {
                     handler.text(text)?;
                 }
+                if let Some(text) = data["choices"][0]["delta"]["refusal"].as_str() {
+                    handler.text(text)?;
+                    handler.finish_reason(Some("refusal"));
+                }
+                handler.finish_reason(data["choices"][0]["finish_reason"].as_str());
+                handler.usage(
+                    data["usage"]["prompt_tokens"].as_u64(),
+                    data["usage"]["completion_tokens"].as_u64(),
+                );
+                handler.cached_tokens(
+                    data["usage"]["prompt_tokens_details"]["cached_tokens"].as_u64(),
+                );
             }
             Err(err) => {
                 match err {
                     EventSourceError::InvalidStatusCode(status, res) => {
+                        handler.set_status(status.as_u16());
                         let text = res.text().await?;
                         let data: Value = match text.parse() {
                             Ok(data) => data,
@@ -117,6 +154,9 @@ pub async fn openai_send_message_streaming(
                         let text = res.text().await?;
                         bail!("The API server should return data as 'text/event-stream', but it isn't. Check the client config. {text}");
                     }
+                    EventSourceError::Transport(err) => {
+                        return Err(ClientError::Network(err).into());
+                    }
                     _ => {
                         bail!("{}", err);
                     }
@@ -134,7 +174,11 @@ pub fn openai_build_body(data: SendData, model: &Model) -> Value {
         messages,
         temperature,
         top_p,
+        seed,
+        user,
         stream,
+        cache_prompt: _,
+        thread: _,
     } = data;
 
     let mut body = json!({
@@ -151,12 +195,151 @@ pub fn openai_build_body(data: SendData, model: &Model) -> Value {
     if let Some(v) = top_p {
         body["top_p"] = v.into();
     }
+    if let Some(v) = seed {
+        body["seed"] = v.into();
+    }
+    if let Some(v) = user {
+        body["user"] = v.into();
+    }
+    if stream {
+        body["stream"] = true.into();
+    }
+    body
+}
+
+/// Build a request of the Responses API, which keeps the conversation on the server
+///
+/// Continuing a thread sends only the messages after the last reply, the rest is already there
+fn openai_build_responses_body(data: SendData, thread: SessionThread, model: &Model) -> Value {
+    let SendData {
+        mut messages,
+        temperature,
+        top_p,
+        seed,
+        user,
+        stream,
+        cache_prompt: _,
+        thread: _,
+    } = data;
+
+    let previous_response_id = match thread {
+        SessionThread::Start => None,
+        SessionThread::Continue(id) => {
+            if let Some(index) = messages.iter().rposition(|v| v.role.is_assistant()) {
+                messages.drain(..=index);
+            }
+            Some(id)
+        }
+    };
+    let input: Vec<Value> = messages.iter().map(responses_input).collect();
+
+    let mut body = json!({
+        "model": &model.name,
+        "input": input,
+        "store": true,
+    });
+
+    if let Some(v) = previous_response_id {
+        body["previous_response_id"] = v.into();
+    }
+    if let Some(v) = model.max_output_tokens {
+        body["max_output_tokens"] = v.into();
+    }
+    if let Some(v) = temperature {
+        body["temperature"] = v.into();
+    }
+    if let Some(v) = top_p {
+        body["top_p"] = v.into();
+    }
+    if seed.is_some() {
+        notice_unsupported_seed(model);
+    }
+    if let Some(v) = user {
+        body["user"] = v.into();
+    }
     if stream {
         body["stream"] = true.into();
     }
     body
 }
 
+fn responses_input(message: &Message) -> Value {
+    let content: Value = match &message.content {
+        MessageContent::Text(text) => text.as_str().into(),
+        MessageContent::Array(list) => list
+            .iter()
+            .map(|part| match part {
+                MessageContentPart::Text { text } => json!({ "type": "input_text", "text": text }),
+                MessageContentPart::ImageUrl { image_url } => {
+                    json!({ "type": "input_image", "image_url": image_url.url })
+                }
+            })
+            .collect(),
+    };
+    let role = match message.role {
+        MessageRole::System => "system",
+        MessageRole::Assistant => "assistant",
+        MessageRole::User => "user",
+    };
+    json!({ "role": role, "content": content })
+}
+
+/// Handle a streamed event of the Responses API
+fn responses_event(event_type: &str, data: &Value, handler: &mut ReplyHandler) -> Result<()> {
+    match event_type {
+        "response.output_text.delta" => {
+            if let Some(text) = data["delta"].as_str() {
+                handler.text(text)?;
+            }
+        }
+        "response.refusal.delta" => {
+            if let Some(text) = data["delta"].as_str() {
+                handler.text(text)?;
+            }
+            handler.finish_reason(Some("refusal"));
+        }
+        "response.completed" | "response.incomplete" => {
+            let response = &data["response"];
+            match response["incomplete_details"]["reason"].as_str() {
+                Some("max_output_tokens") => handler.finish_reason(Some("length")),
+                reason => handler.finish_reason(reason),
+            }
+            handler.usage(
+                response["usage"]["input_tokens"].as_u64(),
+                response["usage"]["output_tokens"].as_u64(),
+            );
+            handler.cached_tokens(response["usage"]["input_tokens_details"]["cached_tokens"].as_u64());
+            handler.set_thread_id(response["id"].as_str());
+        }
+        "response.failed" => {
+            let message = data["response"]["error"]["message"]
+                .as_str()
+                .unwrap_or("The response failed");
+            bail!("{message}");
+        }
+        "error" => {
+            let message = data["message"].as_str().unwrap_or("Unknown error");
+            bail!("{message}");
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// The text of a non-streamed response of the Responses API
+fn responses_output(data: &Value) -> Result<String> {
+    let output = data["output"]
+        .as_array()
+        .ok_or_else(|| anyhow!("Invalid response data: {data}"))?;
+    let text = output
+        .iter()
+        .filter(|v| v["type"] == "message")
+        .flat_map(|v| v["content"].as_array().cloned().unwrap_or_default())
+        .filter_map(|v| v["text"].as_str().or(v["refusal"].as_str()).map(String::from))
+        .collect();
+    Ok(text)
+}
+
 impl_client_trait!(
     OpenAIClient,
     openai_send_message,
@@ -1,5 +1,8 @@
 use super::openai::openai_build_body;
-use super::{ExtraConfig, Model, ModelConfig, OpenAICompatibleClient, PromptType, SendData};
+use super::{
+    merge_extra_body, optional_api_key, ExtraConfig, Model, ModelConfig, OpenAICompatibleClient,
+    PromptType, SendData,
+};
 
 use crate::utils::PromptKind;
 
@@ -35,10 +38,10 @@ impl OpenAICompatibleClient {
     ];
 
     fn request_builder(&self, client: &ReqwestClient, data: SendData) -> Result<RequestBuilder> {
-        let api_key = self.get_api_key().ok();
+        let api_key = optional_api_key(self.get_api_key(), &self.config.extra)?;
 
         let mut body = openai_build_body(data, &self.model);
-        self.model.merge_extra_fields(&mut body);
+        merge_extra_body(&mut body, &self.config.extra, &self.model);
 
         let chat_endpoint = self
             .config
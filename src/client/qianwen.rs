@@ -1,6 +1,6 @@
 use super::{
-    maybe_catch_error, message::*, Client, ExtraConfig, Model, ModelConfig, PromptType,
-    QianwenClient, ReplyHandler, SendData,
+    maybe_catch_error, merge_extra_body, message::*, Client, ClientError, ExtraConfig, Model,
+    ModelConfig, PromptType, QianwenClient, ReplyHandler, SendData,
 };
 
 use crate::utils::{sha256sum, PromptKind};
@@ -62,7 +62,8 @@ impl QianwenClient {
             true => API_URL_VL,
             false => API_URL,
         };
-        let (body, has_upload) = build_body(data, &self.model, is_vl)?;
+        let (mut body, has_upload) = build_body(data, &self.model, is_vl)?;
+        merge_extra_body(&mut body, &self.config.extra, &self.model);
 
         debug!("Qianwen Request: {url} {body}");
 
@@ -106,7 +107,7 @@ async fn send_message_streaming(
 
     while let Some(event) = es.next().await {
         match event {
-            Ok(Event::Open) => {}
+            Ok(Event::Open) => handler.set_status(200),
             Ok(Event::Message(message)) => {
                 let data: Value = serde_json::from_str(&message.data)?;
                 maybe_catch_error(&data)?;
@@ -116,13 +117,20 @@ async fn send_message_streaming(
                     {
                         handler.text(text)?;
                     }
-                } else if let Some(text) = data["output"]["text"].as_str() {
-                    handler.text(text)?;
+                    handler.finish_reason(data["output"]["choices"][0]["finish_reason"].as_str());
+                } else {
+                    if let Some(text) = data["output"]["text"].as_str() {
+                        handler.text(text)?;
+                    }
+                    handler.finish_reason(data["output"]["finish_reason"].as_str());
                 }
             }
             Err(err) => {
                 match err {
                     EventSourceError::StreamEnded => {}
+                    EventSourceError::Transport(err) => {
+                        return Err(ClientError::Network(err).into());
+                    }
                     _ => {
                         bail!("{}", err);
                     }
@@ -140,7 +148,11 @@ fn build_body(data: SendData, model: &Model, is_vl: bool) -> Result<(Value, bool
         messages,
         temperature,
         top_p,
+        seed,
+        user: _,
         stream,
+        cache_prompt: _,
+        thread: _,
     } = data;
 
     let mut has_upload = false;
@@ -193,6 +205,9 @@ fn build_body(data: SendData, model: &Model, is_vl: bool) -> Result<(Value, bool
     if let Some(v) = top_p {
         parameters["top_p"] = v.into();
     }
+    if let Some(v) = seed {
+        parameters["seed"] = v.into();
+    }
 
     let body = json!({
         "model": &model.name,
@@ -321,6 +336,7 @@ impl Client for QianwenClient {
         let api_key = self.get_api_key()?;
         patch_messages(&self.model.name, &api_key, &mut data.messages).await?;
         let builder = self.request_builder(client, data)?;
+        handler.set_request(&builder);
         send_message_streaming(builder, handler, self.is_vl()).await
     }
 }
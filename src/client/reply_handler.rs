@@ -1,11 +1,45 @@
 use crate::utils::AbortSignal;
 
 use anyhow::{Context, Result};
-use tokio::sync::mpsc::UnboundedSender;
+use reqwest::RequestBuilder;
+use std::time::Instant;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+/// Finish reasons of replies stopped by a content filter or refused by the model
+const BLOCKED_REASONS: [&str; 8] = [
+    "content_filter",
+    "refusal",
+    "SAFETY",
+    "RECITATION",
+    "BLOCKLIST",
+    "PROHIBITED_CONTENT",
+    "SPII",
+    "ERROR_TOXIC",
+];
+
+/// The events of a reply as a `Stream`, for consuming a reply without the terminal renderer
+pub type ReplyStream = UnboundedReceiverStream<ReplyEvent>;
+
+/// Create a handler for a client to feed, along with the stream of the events it emits
+pub fn reply_channel(abort: AbortSignal) -> (ReplyHandler, ReplyStream) {
+    let (tx, rx) = unbounded_channel();
+    (
+        ReplyHandler::new(tx, abort),
+        UnboundedReceiverStream::new(rx),
+    )
+}
 
 pub struct ReplyHandler {
     sender: UnboundedSender<ReplyEvent>,
     buffer: String,
+    usage: Option<TokenUsage>,
+    request_url: Option<String>,
+    status: Option<u16>,
+    first_text_at: Option<Instant>,
+    truncated: bool,
+    blocked: Option<String>,
+    thread_id: Option<String>,
     abort: AbortSignal,
 }
 
@@ -15,6 +49,13 @@ impl ReplyHandler {
             sender,
             abort,
             buffer: String::new(),
+            usage: None,
+            request_url: None,
+            status: None,
+            first_text_at: None,
+            truncated: false,
+            blocked: None,
+            thread_id: None,
         }
     }
 
@@ -23,6 +64,9 @@ impl ReplyHandler {
         if text.is_empty() {
             return Ok(());
         }
+        if self.first_text_at.is_none() {
+            self.first_text_at = Some(Instant::now());
+        }
         self.buffer.push_str(text);
         let ret = self
             .sender
@@ -42,10 +86,90 @@ impl ReplyHandler {
         Ok(())
     }
 
+    /// Record the token usage reported by the API, fields missing in one event keep previous values
+    pub fn usage(&mut self, input_tokens: Option<u64>, output_tokens: Option<u64>) {
+        if input_tokens.is_none() && output_tokens.is_none() {
+            return;
+        }
+        let usage = self.usage.get_or_insert_with(TokenUsage::default);
+        if let Some(v) = input_tokens {
+            usage.input_tokens = v as usize;
+        }
+        if let Some(v) = output_tokens {
+            usage.output_tokens = v as usize;
+        }
+    }
+
+    pub fn cached_tokens(&mut self, cached_tokens: Option<u64>) {
+        if let Some(v) = cached_tokens {
+            self.usage
+                .get_or_insert_with(TokenUsage::default)
+                .cached_tokens = v as usize;
+        }
+    }
+
+    /// Record the finish reason reported by the API, marking the reply truncated when it hit the token limit,
+    /// or blocked when it was stopped by a content filter or refused
+    pub fn finish_reason(&mut self, reason: Option<&str>) {
+        if let Some(reason) = reason {
+            self.truncated = matches!(reason, "length" | "max_tokens" | "MAX_TOKENS");
+            if BLOCKED_REASONS.contains(&reason) {
+                self.blocked = Some(reason.to_string());
+            }
+        }
+    }
+
+    pub fn set_request(&mut self, builder: &RequestBuilder) {
+        self.request_url = builder
+            .try_clone()
+            .and_then(|builder| builder.build().ok())
+            .map(|request| request.url().to_string());
+    }
+
+    /// Record the id the provider keeps the reply under, to continue its server-side thread
+    pub fn set_thread_id(&mut self, thread_id: Option<&str>) {
+        if let Some(v) = thread_id {
+            self.thread_id = Some(v.to_string());
+        }
+    }
+
+    pub fn get_thread_id(&self) -> Option<&str> {
+        self.thread_id.as_deref()
+    }
+
+    pub fn set_status(&mut self, status: u16) {
+        self.status = Some(status);
+    }
+
+    pub fn get_request_url(&self) -> Option<&str> {
+        self.request_url.as_deref()
+    }
+
+    pub fn get_status(&self) -> Option<u16> {
+        self.status
+    }
+
+    pub fn get_first_text_at(&self) -> Option<Instant> {
+        self.first_text_at
+    }
+
     pub fn get_buffer(&self) -> &str {
         &self.buffer
     }
 
+    pub fn get_usage(&self) -> Option<TokenUsage> {
+        self.usage
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// The finish reason if the reply was blocked by a content filter or refused
+    pub fn get_blocked(&self) -> Option<&str> {
+        self.blocked.as_deref()
+    }
+
     pub fn get_abort(&self) -> AbortSignal {
         self.abort.clone()
     }
@@ -63,3 +187,11 @@ pub enum ReplyEvent {
     Text(String),
     Done,
 }
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TokenUsage {
+    pub input_tokens: usize,
+    pub output_tokens: usize,
+    /// Input tokens served from the provider's prompt cache
+    pub cached_tokens: usize,
+}
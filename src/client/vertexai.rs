@@ -1,6 +1,6 @@
 use super::{
-    catch_error, json_stream, message::*, patch_system_message, Client, ExtraConfig, Model,
-    ModelConfig, PromptType, ReplyHandler, SendData, VertexAIClient,
+    catch_error, json_stream, merge_extra_body, message::*, patch_system_message, Client,
+    ExtraConfig, Model, ModelConfig, PromptType, ReplyHandler, SendData, VertexAIClient,
 };
 
 use crate::utils::PromptKind;
@@ -8,12 +8,13 @@ use crate::utils::PromptKind;
 use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
 use chrono::{Duration, Utc};
+use parking_lot::Mutex;
 use reqwest::{Client as ReqwestClient, RequestBuilder};
 use serde::Deserialize;
 use serde_json::{json, Value};
 use std::path::PathBuf;
 
-static mut ACCESS_TOKEN: (String, i64) = (String::new(), 0); // safe under linear operation
+static ACCESS_TOKEN: Mutex<(String, i64)> = Mutex::new((String::new(), 0));
 
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct VertexAIConfig {
@@ -51,20 +52,25 @@ impl VertexAIClient {
         let url = format!("{api_base}/{}:{}", &self.model.name, func);
 
         let block_threshold = self.config.block_threshold.clone();
-        let body = gemini_build_body(data, &self.model, block_threshold)?;
+        let mut body = gemini_build_body(data, &self.model, block_threshold)?;
+        merge_extra_body(&mut body, &self.config.extra, &self.model);
 
         debug!("VertexAI Request: {url} {body}");
 
         let builder = client
             .post(url)
-            .bearer_auth(unsafe { &ACCESS_TOKEN.0 })
+            .bearer_auth(&ACCESS_TOKEN.lock().0)
             .json(&body);
 
         Ok(builder)
     }
 
     async fn prepare_access_token(&self) -> Result<()> {
-        if unsafe { ACCESS_TOKEN.0.is_empty() || Utc::now().timestamp() > ACCESS_TOKEN.1 } {
+        let expired = {
+            let token = ACCESS_TOKEN.lock();
+            token.0.is_empty() || Utc::now().timestamp() > token.1
+        };
+        if expired {
             let client = self.build_client()?;
             let (token, expires_in) = fetch_access_token(&client, &self.config.adc_file)
                 .await
@@ -72,7 +78,7 @@ impl VertexAIClient {
             let expires_at = Utc::now()
                 + Duration::try_seconds(expires_in)
                     .ok_or_else(|| anyhow!("Failed to parse expires_in of access_token"))?;
-            unsafe { ACCESS_TOKEN = (token, expires_at.timestamp()) };
+            *ACCESS_TOKEN.lock() = (token, expires_at.timestamp());
         }
         Ok(())
     }
@@ -96,6 +102,7 @@ impl Client for VertexAIClient {
     ) -> Result<()> {
         self.prepare_access_token().await?;
         let builder = self.request_builder(client, data)?;
+        handler.set_request(&builder);
         gemini_send_message_streaming(builder, handler).await
     }
 }
@@ -117,6 +124,7 @@ pub async fn gemini_send_message_streaming(
 ) -> Result<()> {
     let res = builder.send().await?;
     let status = res.status();
+    handler.set_status(status.as_u16());
     if status != 200 {
         let data: Value = res.json().await?;
         catch_error(&data, status.as_u16())?;
@@ -124,6 +132,11 @@ pub async fn gemini_send_message_streaming(
         let handle = |value: &str| -> Result<()> {
             let value: Value = serde_json::from_str(value)?;
             handler.text(gemini_extract_text(&value)?)?;
+            handler.finish_reason(value["candidates"][0]["finishReason"].as_str());
+            handler.usage(
+                value["usageMetadata"]["promptTokenCount"].as_u64(),
+                value["usageMetadata"]["candidatesTokenCount"].as_u64(),
+            );
             Ok(())
         };
         json_stream(res.bytes_stream(), handle).await?;
@@ -156,9 +169,14 @@ pub(crate) fn gemini_build_body(
         mut messages,
         temperature,
         top_p,
+        seed,
+        user: _,
         stream: _,
+        cache_prompt: _,
+        thread: _,
     } = data;
 
+    fold_system_messages(&mut messages);
     patch_system_message(&mut messages);
 
     let mut network_image_urls = vec![];
@@ -222,6 +240,9 @@ pub(crate) fn gemini_build_body(
     if let Some(v) = top_p {
         body["generationConfig"]["topP"] = v.into();
     }
+    if let Some(v) = seed {
+        body["generationConfig"]["seed"] = v.into();
+    }
 
     Ok(body)
 }
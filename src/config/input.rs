@@ -1,26 +1,42 @@
 use super::role::Role;
 use super::session::Session;
+use super::CrlfAction;
 
 use crate::client::{ImageUrl, MessageContent, MessageContentPart, ModelCapabilities};
-use crate::utils::sha256sum;
+use crate::utils::{count_tokens, sha256sum};
 
 use anyhow::{bail, Context, Result};
 use base64::{self, engine::general_purpose::STANDARD, Engine};
 use fancy_regex::Regex;
 use lazy_static::lazy_static;
 use mime_guess::from_path;
+use parking_lot::Mutex;
 use std::{
     collections::HashMap,
-    fs::File,
+    fs::{self, File},
     io::Read,
     path::{Path, PathBuf},
+    time::{Instant, SystemTime},
 };
 use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 const IMAGE_EXTS: [&str; 5] = ["png", "jpeg", "jpg", "webp", "gif"];
+/// The number of files kept in `FILE_CACHE`, the least recently used one is dropped first
+const FILE_CACHE_SIZE: usize = 16;
 
 lazy_static! {
     static ref URL_RE: Regex = Regex::new(r"^[A-Za-z0-9_-]{2,}:/").unwrap();
+    /// Attached text files, reused while their mtime and size are unchanged
+    static ref FILE_CACHE: Mutex<HashMap<PathBuf, CachedFile>> = Default::default();
+}
+
+#[derive(Debug, Clone)]
+struct CachedFile {
+    modified: SystemTime,
+    len: u64,
+    text: String,
+    tokens: Option<usize>,
+    used: Instant,
 }
 
 #[derive(Debug, Clone)]
@@ -28,6 +44,10 @@ pub struct Input {
     text: String,
     medias: Vec<String>,
     data_urls: HashMap<String, String>,
+    file_texts: Vec<(String, PathBuf)>,
+    continuation: Option<String>,
+    /// Ask for the summary of a session compression, which leaves out the rounds kept verbatim
+    summarize: bool,
     context: InputContext,
 }
 
@@ -37,6 +57,9 @@ impl Input {
             text: text.to_string(),
             medias: Default::default(),
             data_urls: Default::default(),
+            file_texts: Default::default(),
+            continuation: None,
+            summarize: false,
             context,
         }
     }
@@ -45,27 +68,50 @@ impl Input {
         let mut texts = vec![text.to_string()];
         let mut medias = vec![];
         let mut data_urls = HashMap::new();
+        let mut file_texts = vec![];
+        let mut crlf_files = vec![];
         let files: Vec<_> = files
             .iter()
-            .map(|f| (f, is_image_ext(Path::new(f))))
+            .map(|f| {
+                let (file, file_type) = split_file_type(f);
+                let is_image = match file_type {
+                    Some(file_type) => is_image_type(file_type),
+                    None => is_image_ext(Path::new(file)),
+                };
+                (file, file_type, is_image)
+            })
             .collect();
-        let include_filepath = files.iter().filter(|(_, is_image)| !*is_image).count() > 1;
-        for (file_item, is_image) in files {
+        let include_filepath = files.iter().filter(|(_, _, is_image)| !*is_image).count() > 1;
+        for (file_item, file_type, is_image) in files {
             match resolve_local_file(file_item) {
                 Some(file_path) => {
                     if is_image {
-                        let data_url = read_media_to_data_url(&file_path)
+                        let mime_type = file_type.map(image_mime_type);
+                        let data_url = read_media_to_data_url(&file_path, mime_type)
                             .with_context(|| format!("Unable to read media file '{file_item}'"))?;
                         data_urls.insert(sha256sum(&data_url), file_path.display().to_string());
                         medias.push(data_url)
                     } else {
-                        let text = read_file(&file_path)
+                        let text = read_file_cached(&file_path)
                             .with_context(|| format!("Unable to read file '{file_item}'"))?;
-                        if include_filepath {
-                            texts.push(format!("`{file_item}`:\n~~~~~~\n{text}\n~~~~~~"));
+                        let text = match fix_line_endings(text, context.crlf) {
+                            (text, true) => {
+                                crlf_files.push(file_item.to_string());
+                                text
+                            }
+                            (text, false) => text,
+                        };
+                        let text = if let Some(file_type) = file_type {
+                            let lang = file_type.rsplit('/').next().unwrap_or(file_type);
+                            let lang = lang.strip_prefix("x-").unwrap_or(lang);
+                            format!("`{file_item}` ({file_type}):\n~~~~~~{lang}\n{text}\n~~~~~~")
+                        } else if include_filepath {
+                            format!("`{file_item}`:\n~~~~~~\n{text}\n~~~~~~")
                         } else {
-                            texts.push(text);
-                        }
+                            text
+                        };
+                        file_texts.push((file_item.to_string(), file_path));
+                        texts.push(text);
                     }
                 }
                 None => {
@@ -78,10 +124,27 @@ impl Input {
             }
         }
 
+        if !crlf_files.is_empty() {
+            let count = crlf_files.len();
+            let noun = if count == 1 { "file" } else { "files" };
+            match context.crlf {
+                CrlfAction::Normalize => {
+                    eprintln!("Normalized CRLF line endings in {count} {noun}")
+                }
+                _ => eprintln!(
+                    "Warning: {count} {noun} with CRLF line endings: {}",
+                    crlf_files.join(", ")
+                ),
+            }
+        }
+
         Ok(Self {
             text: texts.join("\n"),
             medias,
             data_urls,
+            file_texts,
+            continuation: None,
+            summarize: false,
             context,
         })
     }
@@ -94,6 +157,31 @@ impl Input {
         self.data_urls.clone()
     }
 
+    /// The name and size of each attached file and image, the size is unknown for remote images
+    pub fn attachments(&self) -> Vec<(String, Option<u64>)> {
+        let file_size = |path: &Path| fs::metadata(path).ok().map(|v| v.len());
+        let files = self
+            .file_texts
+            .iter()
+            .map(|(file, path)| (file.clone(), file_size(path)));
+        let medias = self
+            .medias
+            .iter()
+            .map(|media| match self.data_urls.get(&sha256sum(media)) {
+                Some(path) => (path.clone(), file_size(Path::new(path))),
+                None => (media.clone(), None),
+            });
+        files.chain(medias).collect()
+    }
+
+    /// The estimated tokens of the content of each attached text file
+    pub fn file_tokens(&self) -> Vec<(&str, usize)> {
+        self.file_texts
+            .iter()
+            .map(|(file, path)| (file.as_str(), cached_file_tokens(path)))
+            .collect()
+    }
+
     pub fn text(&self) -> String {
         self.text.clone()
     }
@@ -102,6 +190,26 @@ impl Input {
         self.text = text;
     }
 
+    /// Turn the input into a request to continue its truncated reply
+    pub fn continue_reply(mut self, output: &str) -> Self {
+        self.continuation = Some(output.to_string());
+        self
+    }
+
+    pub fn continuation(&self) -> Option<&str> {
+        self.continuation.as_deref()
+    }
+
+    /// Turn the input into the summarize prompt of a session compression
+    pub fn summarize_session(mut self) -> Self {
+        self.summarize = true;
+        self
+    }
+
+    pub fn is_summarize(&self) -> bool {
+        self.summarize
+    }
+
     pub fn role(&self) -> Option<&Role> {
         self.context.role.as_ref()
     }
@@ -201,11 +309,21 @@ impl Input {
 pub struct InputContext {
     role: Option<Role>,
     in_session: bool,
+    crlf: CrlfAction,
 }
 
 impl InputContext {
     pub fn new(role: Option<Role>, in_session: bool) -> Self {
-        Self { role, in_session }
+        Self {
+            role,
+            in_session,
+            crlf: Default::default(),
+        }
+    }
+
+    pub fn with_crlf(mut self, crlf: CrlfAction) -> Self {
+        self.crlf = crlf;
+        self
     }
 }
 
@@ -233,6 +351,55 @@ fn resolve_local_file(file: &str) -> Option<PathBuf> {
     Some(path)
 }
 
+/// Split the optional type override from `path:type`, e.g. `Dockerfile:dockerfile` or `data:text/csv`
+fn split_file_type(file: &str) -> (&str, Option<&str>) {
+    if let Ok(true) = URL_RE.is_match(file) {
+        return (file, None);
+    }
+    if Path::new(file).exists() {
+        return (file, None);
+    }
+    match file.rsplit_once(':') {
+        Some((path, file_type))
+            if path.len() > 1
+                && file_type.starts_with(|c: char| c.is_ascii_alphanumeric())
+                && file_type
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-./_".contains(c)) =>
+        {
+            (path, Some(file_type))
+        }
+        _ => (file, None),
+    }
+}
+
+/// Apply the `crlf_files` action to a file's text, returning whether it has CRLF line endings
+fn fix_line_endings(text: String, action: CrlfAction) -> (String, bool) {
+    if action == CrlfAction::Keep || !text.contains("\r\n") {
+        return (text, false);
+    }
+    match action {
+        CrlfAction::Normalize => (text.replace("\r\n", "\n"), true),
+        _ => (text, true),
+    }
+}
+
+fn is_image_type(file_type: &str) -> bool {
+    let file_type = file_type.to_lowercase();
+    file_type.starts_with("image/") || IMAGE_EXTS.contains(&file_type.as_str())
+}
+
+fn image_mime_type(file_type: &str) -> String {
+    let file_type = file_type.to_lowercase();
+    if file_type.contains('/') {
+        file_type
+    } else if file_type == "jpg" {
+        "image/jpeg".into()
+    } else {
+        format!("image/{file_type}")
+    }
+}
+
 fn is_image_ext(path: &Path) -> bool {
     path.extension()
         .map(|v| {
@@ -243,10 +410,14 @@ fn is_image_ext(path: &Path) -> bool {
         .unwrap_or_default()
 }
 
-fn read_media_to_data_url<P: AsRef<Path>>(image_path: P) -> Result<String> {
+fn read_media_to_data_url<P: AsRef<Path>>(
+    image_path: P,
+    mime_type: Option<String>,
+) -> Result<String> {
     let image_path = image_path.as_ref();
 
-    let mime_type = from_path(image_path).first_or_octet_stream().to_string();
+    let mime_type =
+        mime_type.unwrap_or_else(|| from_path(image_path).first_or_octet_stream().to_string());
     let mut file = File::open(image_path)?;
     let mut buffer = Vec::new();
     file.read_to_end(&mut buffer)?;
@@ -257,6 +428,49 @@ fn read_media_to_data_url<P: AsRef<Path>>(image_path: P) -> Result<String> {
     Ok(data_url)
 }
 
+/// Read a text file, or reuse the content read before if its mtime and size are unchanged
+fn read_file_cached(path: &Path) -> Result<String> {
+    let metadata = fs::metadata(path)?;
+    let (modified, len) = (metadata.modified()?, metadata.len());
+    if let Some(file) = FILE_CACHE.lock().get_mut(path) {
+        if file.modified == modified && file.len == len {
+            file.used = Instant::now();
+            return Ok(file.text.clone());
+        }
+    }
+    let text = read_file(path)?;
+    let mut cache = FILE_CACHE.lock();
+    if cache.len() >= FILE_CACHE_SIZE && !cache.contains_key(path) {
+        let oldest = cache
+            .iter()
+            .min_by_key(|(_, file)| file.used)
+            .map(|(path, _)| path.clone());
+        if let Some(oldest) = oldest {
+            cache.remove(&oldest);
+        }
+    }
+    cache.insert(
+        path.to_path_buf(),
+        CachedFile {
+            modified,
+            len,
+            text: text.clone(),
+            tokens: None,
+            used: Instant::now(),
+        },
+    );
+    Ok(text)
+}
+
+/// Count the tokens of a cached file once, until it is read again
+fn cached_file_tokens(path: &Path) -> usize {
+    let mut cache = FILE_CACHE.lock();
+    match cache.get_mut(path) {
+        Some(file) => *file.tokens.get_or_insert_with(|| count_tokens(&file.text)),
+        None => 0,
+    }
+}
+
 fn read_file<P: AsRef<Path>>(file_path: P) -> Result<String> {
     let file_path = file_path.as_ref();
 
@@ -265,3 +479,72 @@ fn read_file<P: AsRef<Path>>(file_path: P) -> Result<String> {
     file.read_to_string(&mut text)?;
     Ok(text)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_file_type() {
+        assert_eq!(split_file_type("a.txt"), ("a.txt", None));
+        assert_eq!(
+            split_file_type("Dockerfile:dockerfile"),
+            ("Dockerfile", Some("dockerfile"))
+        );
+        assert_eq!(
+            split_file_type("data.bin:text/csv"),
+            ("data.bin", Some("text/csv"))
+        );
+        assert_eq!(
+            split_file_type("https://ibb.co/a.png"),
+            ("https://ibb.co/a.png", None)
+        );
+        assert_eq!(split_file_type("C:\\a.txt"), ("C:\\a.txt", None));
+        assert_eq!(split_file_type("C:/a.txt"), ("C:/a.txt", None));
+    }
+
+    #[test]
+    fn test_fix_line_endings() {
+        let text = || "a\r\nb\r\n".to_string();
+        assert_eq!(
+            fix_line_endings(text(), CrlfAction::Normalize),
+            ("a\nb\n".into(), true)
+        );
+        assert_eq!(fix_line_endings(text(), CrlfAction::Warn), (text(), true));
+        assert_eq!(fix_line_endings(text(), CrlfAction::Keep), (text(), false));
+        assert_eq!(
+            fix_line_endings("a\nb".into(), CrlfAction::Normalize),
+            ("a\nb".into(), false)
+        );
+    }
+
+    #[test]
+    fn test_read_file_cached() {
+        let path = std::env::temp_dir().join(format!("aichat-cache-{}.txt", std::process::id()));
+        let write = |text: &str, secs: u64| {
+            fs::write(&path, text).unwrap();
+            let modified = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        };
+        write("hello world", 1000);
+        assert_eq!(read_file_cached(&path).unwrap(), "hello world");
+        assert_eq!(cached_file_tokens(&path), 2);
+
+        // Same mtime and size, the cached content is kept
+        write("HELLO WORLD", 1000);
+        assert_eq!(read_file_cached(&path).unwrap(), "hello world");
+
+        write("CHANGED", 1000);
+        assert_eq!(read_file_cached(&path).unwrap(), "CHANGED");
+
+        write("changed", 2000);
+        assert_eq!(read_file_cached(&path).unwrap(), "changed");
+        assert_eq!(cached_file_tokens(&path), 1);
+        let _ = fs::remove_file(&path);
+    }
+}
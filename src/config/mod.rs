@@ -5,21 +5,26 @@ mod session;
 pub use self::input::{Input, InputContext};
 use self::role::Role;
 pub use self::role::{CODE_ROLE, EXPLAIN_ROLE, SHELL_ROLE};
-use self::session::{Session, TEMP_SESSION_NAME};
+use self::session::{AutocompactPolicy, Session, TEMP_SESSION_NAME};
 
 use crate::client::{
     create_client_config, list_client_types, list_models, ClientConfig, ExtraConfig, Message,
-    Model, OpenAIClient, SendData,
+    MessageContent, MessageRole, Model, ModelRegistry, OpenAIClient, SendData, TokenUsage,
+    JSON_OBJECT_ERROR,
+};
+use crate::render::{ColorTheme, Colors, HeadingStyle, MarkdownRender, RenderOptions};
+use crate::utils::{
+    count_tokens, edit_distance, find_program, get_env_name, light_theme_from_colorfgbg,
+    machine_id, now, prompt_width, render_prompt, set_text, sha256sum,
 };
-use crate::render::{MarkdownRender, RenderOptions};
-use crate::utils::{get_env_name, light_theme_from_colorfgbg, now, render_prompt, set_text};
 
 use anyhow::{anyhow, bail, Context, Result};
 use inquire::{Confirm, Select, Text};
 use is_terminal::IsTerminal;
+use nu_ansi_term::Color;
 use parking_lot::RwLock;
 use serde::Deserialize;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::{
     env,
     fs::{create_dir_all, read_dir, read_to_string, remove_file, File, OpenOptions},
@@ -27,6 +32,7 @@ use std::{
     path::{Path, PathBuf},
     process::exit,
     sync::Arc,
+    time::SystemTime,
 };
 use syntect::highlighting::ThemeSet;
 
@@ -35,18 +41,52 @@ const DARK_THEME: &[u8] = include_bytes!("../../assets/monokai-extended.theme.bi
 const LIGHT_THEME: &[u8] = include_bytes!("../../assets/monokai-extended-light.theme.bin");
 
 const CONFIG_FILE_NAME: &str = "config.yaml";
+const CONFIG_OVERLAY_FILE_NAME: &str = "config.local.yaml";
 const ROLES_FILE_NAME: &str = "roles.yaml";
+const MODELS_FILE_NAME: &str = "models.yaml";
+const MACROS_FILE_NAME: &str = "macros.yaml";
+const COLORS_FILE_NAME: &str = "colors.yaml";
+const PROJECT_MODELS_FILE_NAME: &str = "project_models.yaml";
 const MESSAGES_FILE_NAME: &str = "messages.md";
 const SESSIONS_DIR_NAME: &str = "sessions";
+const ROLES_DIR_NAME: &str = "roles";
 
 const CLIENTS_FIELD: &str = "clients";
 
 const SUMMARIZE_PROMPT: &str =
     "Summarize the discussion briefly in 200 words or less to use as a prompt for future context.";
+const CONTINUE_PROMPT: &str =
+    "Continue exactly where you stopped, without repeating anything you have already written.";
+const COMPACT_PROMPT: &str =
+    "Answer in a single line of plain text, as briefly as possible, without markdown.";
 const SUMMARY_PROMPT: &str = "This is a summary of the chat history as a recap: ";
-const LEFT_PROMPT: &str = "{color.green}{?session {session}{?role /}}{role}{color.cyan}{?session )}{!session >}{color.reset} ";
+const LEFT_PROMPT: &str = "{color.prompt}{?session {session}{?role /}}{role}{color.cyan}{?session )}{!session >}{color.reset} ";
 const RIGHT_PROMPT: &str = "{color.purple}{?session {?consume_tokens {consume_tokens}({consume_percent}%)}{!consume_tokens {consume_tokens}}}{color.reset}";
 
+/// A sampling parameter and where its value comes from, the source is empty when it is unset
+pub type SamplingParam = (Option<f64>, &'static str);
+
+/// The keys `.set` accepts, in the order they are completed
+const SET_KEYS: [&str; 17] = [
+    "temperature",
+    "top_p",
+    "seed",
+    "request_user",
+    "compress_threshold",
+    "max_input_chars",
+    "batch_delimiter",
+    "cache_prompt",
+    "save",
+    "save_session",
+    "highlight",
+    "dry_run",
+    "offline",
+    "auto_copy",
+    "show_model",
+    "compact",
+    "tee_output",
+];
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(default)]
 pub struct Config {
@@ -54,26 +94,79 @@ pub struct Config {
     pub model_id: Option<String>,
     pub temperature: Option<f64>,
     pub top_p: Option<f64>,
+    pub seed: Option<u64>,
+    pub request_user: Option<String>,
+    pub blocked_fallback_model: Option<String>,
+    pub on_disconnect: DisconnectAction,
     pub dry_run: bool,
+    pub offline: bool,
+    pub verbose: bool,
     pub save: bool,
     pub save_session: Option<bool>,
+    pub save_session_on_exit: bool,
+    pub on_exit: Option<String>,
     pub highlight: bool,
     pub light_theme: bool,
     pub wrap: Option<String>,
     pub wrap_code: bool,
+    pub soft_wrap: bool,
+    pub heading_styles: Vec<HeadingStyle>,
+    pub instant_first_token: bool,
+    pub adaptive_batch: bool,
+    pub show_model: bool,
+    pub turn_separator: TurnSeparator,
+    pub compact: bool,
+    pub max_stream_rows: usize,
+    pub pipe_output: PipeOutput,
+    pub reply_ansi: ReplyAnsi,
+    pub tee_output: Option<String>,
+    pub tee_append: bool,
+    pub hyperlinks: bool,
+    pub citations: bool,
+    pub truncation_indicator: String,
+    pub code_lang: Option<String>,
     pub auto_copy: bool,
     pub keybindings: Keybindings,
+    pub ctrl_d: CtrlDAction,
+    pub submit_key: SubmitKey,
+    pub swap_models: Vec<String>,
+    pub swap_models_key: String,
+    pub empty_input: EmptyInputAction,
+    pub crlf_files: CrlfAction,
     pub prelude: Option<String>,
+    pub startup_file: Option<String>,
+    pub default_role: Option<String>,
+    pub project_models: bool,
     pub buffer_editor: Option<String>,
+    pub quiet: bool,
+    pub banner_text: Option<String>,
+    pub max_input_chars: Option<usize>,
+    pub batch_delimiter: Option<String>,
+    pub copy_history: usize,
     pub compress_threshold: usize,
+    pub compress_timeout: u64,
+    pub compress_retries: usize,
+    pub compress_keep_last: usize,
+    pub compress_idle: Option<u64>,
     pub summarize_prompt: Option<String>,
     pub summary_prompt: Option<String>,
     pub left_prompt: Option<String>,
     pub right_prompt: Option<String>,
+    pub prompt_drop_order: Vec<String>,
     pub clients: Vec<ClientConfig>,
     #[serde(skip)]
     pub roles: Vec<Role>,
     #[serde(skip)]
+    pub role_files: HashMap<String, PathBuf>,
+    #[serde(skip)]
+    pub model_registry: Vec<ModelRegistry>,
+    #[serde(skip)]
+    pub macros: BTreeMap<String, Vec<String>>,
+    #[serde(skip)]
+    pub color_theme: ColorTheme,
+    #[serde(skip)]
+    pub recording_macro: Option<(String, Vec<String>)>,
+    #[serde(skip)]
     pub role: Option<Role>,
     #[serde(skip)]
     pub session: Option<Session>,
@@ -83,6 +176,23 @@ pub struct Config {
     pub working_mode: WorkingMode,
     #[serde(skip)]
     pub last_message: Option<(Input, String)>,
+    #[serde(skip)]
+    pub last_reply_truncated: bool,
+    /// The untruncated text of the last output that was cut short, shown by `.full`
+    #[serde(skip)]
+    pub full_output: Option<String>,
+    /// The server-side thread id of the last streamed reply, stored with the session by `save_message`
+    #[serde(skip)]
+    pub last_thread_id: Option<String>,
+    #[serde(skip)]
+    pub sampling_overridden: (bool, bool),
+    #[serde(skip)]
+    pub reply_history: VecDeque<String>,
+    #[serde(skip)]
+    pub staged_files: Vec<String>,
+    /// Whether the config overlay was merged on top of the config file
+    #[serde(skip)]
+    pub overlay_merged: bool,
 }
 
 impl Default for Config {
@@ -91,29 +201,84 @@ impl Default for Config {
             model_id: None,
             temperature: None,
             top_p: None,
+            seed: None,
+            request_user: None,
+            blocked_fallback_model: None,
+            on_disconnect: Default::default(),
             save: true,
             save_session: None,
+            save_session_on_exit: false,
+            on_exit: None,
             highlight: true,
             dry_run: false,
+            offline: false,
+            verbose: false,
             light_theme: false,
             wrap: None,
             wrap_code: false,
+            soft_wrap: false,
+            heading_styles: vec![],
+            instant_first_token: false,
+            adaptive_batch: false,
+            show_model: false,
+            turn_separator: Default::default(),
+            compact: false,
+            max_stream_rows: 1000,
+            pipe_output: Default::default(),
+            reply_ansi: Default::default(),
+            tee_output: None,
+            tee_append: false,
+            hyperlinks: false,
+            citations: false,
+            truncation_indicator: "…".into(),
+            code_lang: None,
             auto_copy: false,
             keybindings: Default::default(),
+            ctrl_d: Default::default(),
+            submit_key: Default::default(),
+            swap_models: vec![],
+            swap_models_key: "alt-m".into(),
+            empty_input: Default::default(),
+            crlf_files: Default::default(),
             prelude: None,
+            default_role: None,
+            project_models: false,
+            startup_file: None,
             buffer_editor: None,
+            quiet: false,
+            banner_text: None,
+            max_input_chars: None,
+            batch_delimiter: None,
+            copy_history: 10,
             compress_threshold: 2000,
+            compress_timeout: 60,
+            compress_retries: 1,
+            compress_keep_last: 2,
+            compress_idle: None,
             summarize_prompt: None,
             summary_prompt: None,
             left_prompt: None,
             right_prompt: None,
+            prompt_drop_order: vec!["consume_tokens".into(), "model".into(), "role".into()],
             clients: vec![],
             roles: vec![],
+            role_files: Default::default(),
+            model_registry: vec![],
+            macros: Default::default(),
+            color_theme: Default::default(),
+            recording_macro: None,
             role: None,
             session: None,
             model: Default::default(),
             working_mode: WorkingMode::Command,
+            overlay_merged: false,
             last_message: None,
+            last_reply_truncated: false,
+            full_output: None,
+            last_thread_id: None,
+            sampling_overridden: (false, false),
+            reply_history: Default::default(),
+            staged_files: vec![],
         }
     }
 }
@@ -123,6 +288,7 @@ pub type GlobalConfig = Arc<RwLock<Config>>;
 impl Config {
     pub fn init(working_mode: WorkingMode) -> Result<Self> {
         let config_path = Self::config_file()?;
+        let overlay_path = Self::config_overlay_file()?;
 
         let api_key = env::var("OPENAI_API_KEY").ok();
 
@@ -133,7 +299,7 @@ impl Config {
         let mut config = if api_key.is_some() && !exist_config_path {
             Self::default()
         } else {
-            Self::load_config(&config_path)?
+            Self::load_config(&config_path, &overlay_path)?
         };
 
         // Compatible with old configuration files
@@ -147,10 +313,16 @@ impl Config {
 
         config.working_mode = working_mode;
         config.load_roles()?;
+        config.load_model_registry()?;
+        config.load_color_theme()?;
+        if working_mode == WorkingMode::Repl {
+            config.load_macros()?;
+        }
 
         config.setup_model()?;
         config.setup_highlight();
         config.setup_light_theme()?;
+        config.setup_offline();
 
         Ok(config)
     }
@@ -179,10 +351,40 @@ impl Config {
         Ok(())
     }
 
-    pub fn buffer_editor(&self) -> Option<String> {
-        self.buffer_editor
-            .clone()
-            .or_else(|| env::var("VISUAL").ok().or_else(|| env::var("EDITOR").ok()))
+    /// The editor of Ctrl+O as a program and its args, the first of `buffer_editor`, `$VISUAL` and `$EDITOR` that is installed
+    ///
+    /// Also returns a warning naming the editors that were skipped because they can't be found
+    pub fn buffer_editor(&self) -> (Option<Vec<String>>, Option<String>) {
+        let candidates = [
+            self.buffer_editor.clone(),
+            env::var("VISUAL").ok(),
+            env::var("EDITOR").ok(),
+        ];
+        let mut missing = vec![];
+        for cmd in candidates.into_iter().flatten() {
+            if cmd.trim().is_empty() {
+                continue;
+            }
+            match shell_words::split(&cmd) {
+                Ok(args) if args.first().is_some_and(|v| find_program(v).is_some()) => {
+                    let warning = (!missing.is_empty()).then(|| {
+                        format!(
+                            "Editor {} not found, Ctrl+O uses '{cmd}'",
+                            missing.join(", ")
+                        )
+                    });
+                    return (Some(args), warning);
+                }
+                _ => missing.push(format!("'{cmd}'")),
+            }
+        }
+        let warning = (!missing.is_empty()).then(|| {
+            format!(
+                "Editor {} not found, Ctrl+O is disabled, set buffer_editor or $EDITOR to an installed editor",
+                missing.join(", ")
+            )
+        });
+        (None, warning)
     }
 
     pub fn retrieve_role(&self, name: &str) -> Result<Role> {
@@ -217,7 +419,16 @@ impl Config {
     }
 
     pub fn save_message(&mut self, input: Input, output: &str) -> Result<()> {
+        let thread_id = self.last_thread_id.take();
+        if let Some(reply) = input.continuation() {
+            return self.save_continuation(input.clone(), reply, output, thread_id);
+        }
         self.last_message = Some((input.clone(), output.to_string()));
+        self.full_output = None;
+        if self.copy_history > 0 {
+            self.reply_history.push_front(output.to_string());
+            self.reply_history.truncate(self.copy_history);
+        }
 
         if self.dry_run {
             return Ok(());
@@ -225,14 +436,60 @@ impl Config {
 
         if let Some(session) = input.session_mut(&mut self.session) {
             session.add_message(&input, output)?;
+            session.set_thread_id(thread_id);
             self.write_message_to_file(&input, output)?;
             return Ok(());
         }
         self.write_message_to_file(&input, output)?;
-        return Ok(());
-    
-    
+        Ok(())
+    }
+
+    /// Stitch the continuation into the truncated reply instead of saving a new turn
+    fn save_continuation(
+        &mut self,
+        input: Input,
+        reply: &str,
+        output: &str,
+        thread_id: Option<String>,
+    ) -> Result<()> {
+        let full_output = format!("{reply}{output}");
+        if let Some(last_reply) = self.reply_history.front_mut() {
+            if last_reply == reply {
+                *last_reply = full_output.clone();
+            }
+        }
+        if let Some((_, last_output)) = self.last_message.as_mut() {
+            *last_output = full_output;
+        }
+
+        if self.dry_run {
+            return Ok(());
+        }
+
+        if let Some(session) = input.session_mut(&mut self.session) {
+            session.extend_last_reply(output);
+            session.set_thread_id(thread_id);
+        }
+        self.write_message_to_file(&input, output)?;
+        Ok(())
+    }
+
+    /// Keep the usage reported by the API alongside the local estimate for the same request
+    pub fn record_usage(&mut self, input: &Input, output: &str, usage: TokenUsage) {
+        let estimated_input_tokens = match self.build_messages(input) {
+            Ok(messages) => self.model.total_tokens(&messages),
+            Err(_) => return,
+        };
+        let estimated = TokenUsage {
+            input_tokens: estimated_input_tokens,
+            output_tokens: count_tokens(output),
+            cached_tokens: 0,
+        };
+        if let Some(session) = input.session_mut(&mut self.session) {
+            session.record_usage(usage, estimated);
+        }
     }
+
     fn write_message_to_file(&self, input: &Input, output: &str) -> Result<()> {
         if !self.save || output.is_empty() {
             return Ok(());
@@ -249,6 +506,14 @@ impl Config {
             .with_context(|| "Failed to save message")
     }
 
+    pub fn guard_online(&self) -> Result<()> {
+        if self.offline {
+            bail!(
+                "Network access is disabled in offline mode, run `.set offline false` to enable it"
+            );
+        }
+        Ok(())
+    }
 
     pub fn maybe_copy(&self, text: &str) {
         if self.auto_copy {
@@ -260,6 +525,15 @@ impl Config {
         Self::local_path(CONFIG_FILE_NAME)
     }
 
+    /// Merged on top of `config.yaml`, for machine-specific settings and keys kept out of a shared config
+    pub fn config_overlay_file() -> Result<PathBuf> {
+        let env_name = get_env_name("config_overlay_file");
+        env::var(env_name).map_or_else(
+            |_| Self::local_path(CONFIG_OVERLAY_FILE_NAME),
+            |value| Ok(PathBuf::from(value)),
+        )
+    }
+
     pub fn roles_file() -> Result<PathBuf> {
         let env_name = get_env_name("roles_file");
         env::var(env_name).map_or_else(
@@ -268,6 +542,42 @@ impl Config {
         )
     }
 
+    pub fn roles_dir() -> Result<PathBuf> {
+        Self::local_path(ROLES_DIR_NAME)
+    }
+
+    pub fn models_file() -> Result<PathBuf> {
+        let env_name = get_env_name("models_file");
+        env::var(env_name).map_or_else(
+            |_| Self::local_path(MODELS_FILE_NAME),
+            |value| Ok(PathBuf::from(value)),
+        )
+    }
+
+    pub fn macros_file() -> Result<PathBuf> {
+        let env_name = get_env_name("macros_file");
+        env::var(env_name).map_or_else(
+            |_| Self::local_path(MACROS_FILE_NAME),
+            |value| Ok(PathBuf::from(value)),
+        )
+    }
+
+    pub fn colors_file() -> Result<PathBuf> {
+        let env_name = get_env_name("colors_file");
+        env::var(env_name).map_or_else(
+            |_| Self::local_path(COLORS_FILE_NAME),
+            |value| Ok(PathBuf::from(value)),
+        )
+    }
+
+    pub fn project_models_file() -> Result<PathBuf> {
+        let env_name = get_env_name("project_models_file");
+        env::var(env_name).map_or_else(
+            |_| Self::local_path(PROJECT_MODELS_FILE_NAME),
+            |value| Ok(PathBuf::from(value)),
+        )
+    }
+
     pub fn messages_file() -> Result<PathBuf> {
         Self::local_path(MESSAGES_FILE_NAME)
     }
@@ -278,7 +588,87 @@ impl Config {
 
     pub fn session_file(name: &str) -> Result<PathBuf> {
         let mut path = Self::sessions_dir()?;
-        path.push(&format!("{name}.yaml"));
+        path.push(format!("{name}.yaml"));
+        Ok(path)
+    }
+
+    /// List the roles sorted by name, keeping those whose name or summary contains `filter`
+    pub fn list_roles(&self, filter: &str) -> String {
+        let filter = filter.to_lowercase();
+        let active = self.role.as_ref().map(|v| v.name.as_str());
+        let mut roles: Vec<&Role> = self
+            .roles
+            .iter()
+            .filter(|v| {
+                filter.is_empty()
+                    || v.name.to_lowercase().contains(&filter)
+                    || v.summary().to_lowercase().contains(&filter)
+            })
+            .collect();
+        roles.sort_by(|a, b| a.name.cmp(&b.name));
+        roles
+            .into_iter()
+            .map(|v| {
+                let mark = if Some(v.name.as_str()) == active {
+                    '*'
+                } else {
+                    ' '
+                };
+                format!("{mark} {:<22} {}", v.name, v.summary())
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// Copy the role `src` to a new role `dst` and save it
+    pub fn clone_role(&mut self, src: &str, dst: &str, force: bool) -> Result<()> {
+        let mut role = self.retrieve_role(src)?;
+        if self.roles.iter().any(|v| v.name == dst) && !force {
+            bail!("Role '{dst}' already exists, use --force to overwrite it");
+        }
+        role.name = dst.to_string();
+        let path = self.save_role(role)?;
+        println!("✨ Cloned role '{src}' to '{dst}' in '{}'", path.display());
+        Ok(())
+    }
+
+    /// Save a role to its own file if it has one, or if it is new and the roles dir exists, otherwise to the roles file
+    pub fn save_role(&mut self, role: Role) -> Result<PathBuf> {
+        let name = role.name.clone();
+        let path = match self.role_files.get(&name) {
+            Some(path) => Some(path.clone()),
+            None if !self.roles.iter().any(|v| v.name == name) && Self::roles_dir()?.is_dir() => {
+                Some(Self::roles_dir()?.join(format!("{name}.yaml")))
+            }
+            None => None,
+        };
+        let content = match &path {
+            Some(_) => serde_yaml::to_string(&role),
+            None => {
+                let roles: Vec<&Role> = self
+                    .roles
+                    .iter()
+                    .filter(|v| v.name != name && !self.role_files.contains_key(&v.name))
+                    .chain(std::iter::once(&role))
+                    .collect();
+                serde_yaml::to_string(&roles)
+            }
+        }
+        .with_context(|| format!("Failed to serde role '{name}'"))?;
+        let path = match path {
+            Some(path) => path,
+            None => Self::roles_file()?,
+        };
+        ensure_parent_exists(&path)?;
+        std::fs::write(&path, content)
+            .with_context(|| format!("Failed to write roles to {}", path.display()))?;
+        if path.starts_with(Self::roles_dir()?) {
+            self.role_files.insert(name.clone(), path.clone());
+        }
+        match self.roles.iter().position(|v| v.name == name) {
+            Some(index) => self.roles[index] = role,
+            None => self.roles.push(role),
+        }
         Ok(path)
     }
 
@@ -304,6 +694,9 @@ impl Config {
 
     pub fn clear_role(&mut self) -> Result<()> {
         self.role = None;
+        if let Some(session) = self.session.as_mut() {
+            session.default_role = None;
+        }
         Ok(())
     }
 
@@ -332,6 +725,7 @@ impl Config {
             role.set_temperature(value);
         } else {
             self.temperature = value;
+            self.sampling_overridden.0 = value.is_some();
         }
     }
 
@@ -342,7 +736,19 @@ impl Config {
             role.set_top_p(value);
         } else {
             self.top_p = value;
+            self.sampling_overridden.1 = value.is_some();
+        }
+    }
+
+    pub fn set_cache_prompt(&mut self, value: Option<bool>) -> Result<()> {
+        if let Some(session) = self.session.as_mut() {
+            session.set_cache_prompt(value);
+        } else if let Some(role) = self.role.as_mut() {
+            role.set_cache_prompt(value);
+        } else {
+            bail!("Prompt caching can only be set for a role or session");
         }
+        Ok(())
     }
 
     pub fn set_save_session(&mut self, value: Option<bool>) {
@@ -361,6 +767,59 @@ impl Config {
         }
     }
 
+    /// A threshold below 1000 never compresses, and one above the context window can't be reached
+    fn check_compress_threshold(&self, value: Option<usize>) -> Result<()> {
+        let Some(value) = value else {
+            return Ok(());
+        };
+        if value < 1000 {
+            bail!("compress_threshold must be at least 1000, or null to unset it");
+        }
+        if let Some(max_input_tokens) = self.model.max_input_tokens {
+            if value >= max_input_tokens {
+                bail!(
+                    "compress_threshold must be below the context window of '{}' ({max_input_tokens} tokens)",
+                    self.model.id()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the compression policy of the current session from `<threshold> [keep_last]` or `null`
+    pub fn set_session_autocompact(&mut self, value: &str) -> Result<()> {
+        if self.session.is_none() {
+            bail!("No session");
+        }
+        let parts: Vec<&str> = value.split_whitespace().collect();
+        let policy = match parts.as_slice() {
+            ["null"] => None,
+            [threshold] | [threshold, _] => {
+                let threshold = threshold
+                    .parse()
+                    .with_context(|| format!("Invalid threshold '{threshold}'"))?;
+                self.check_compress_threshold(Some(threshold))?;
+                let keep_last = match parts.get(1) {
+                    Some(keep_last) => Some(
+                        keep_last
+                            .parse()
+                            .with_context(|| format!("Invalid keep_last '{keep_last}'"))?,
+                    ),
+                    None => None,
+                };
+                Some(AutocompactPolicy {
+                    threshold,
+                    keep_last,
+                })
+            }
+            _ => bail!("Usage: .session autocompact <threshold> [keep_last] | null"),
+        };
+        if let Some(session) = self.session.as_mut() {
+            session.set_autocompact(policy);
+        }
+        Ok(())
+    }
+
     pub fn echo_messages(&self, input: &Input) -> String {
         if let Some(session) = input.session(&self.session) {
             session.echo_messages(input)
@@ -372,14 +831,35 @@ impl Config {
     }
 
     pub fn build_messages(&self, input: &Input) -> Result<Vec<Message>> {
-        let messages = if let Some(session) = input.session(&self.session) {
-            session.build_emssages(input)
+        let mut messages = if let Some(session) = input.session(&self.session) {
+            match input.continuation() {
+                Some(reply) => session.build_continue_messages(reply)?,
+                None if input.is_summarize() => {
+                    session.build_summarize_messages(input, self.compress_keep_last)
+                }
+                None => session.build_emssages(input),
+            }
         } else if let Some(role) = input.role() {
             role.build_messages(input)
         } else {
             let message = Message::new(input);
             vec![message]
         };
+        if let Some(reply) = input.continuation() {
+            if input.session(&self.session).is_none() {
+                messages.push(Message {
+                    role: MessageRole::Assistant,
+                    content: MessageContent::Text(reply.to_string()),
+                });
+            }
+            messages.push(Message {
+                role: MessageRole::User,
+                content: MessageContent::Text(CONTINUE_PROMPT.into()),
+            });
+        }
+        if self.compact {
+            add_compact_prompt(&mut messages);
+        }
         Ok(messages)
     }
 
@@ -397,19 +877,73 @@ impl Config {
         Ok(())
     }
 
-    pub fn set_model(&mut self, value: &str) -> Result<()> {
+    /// Look up a model by its id or a part of it that matches a single model
+    pub fn find_model(&self, value: &str) -> Result<Model> {
         let models = list_models(self);
-        let model = Model::find(&models, value);
-        match model {
-            None => bail!("Invalid model '{}'", value),
-            Some(model) => {
-                if let Some(session) = self.session.as_mut() {
-                    session.set_model(model.clone())?;
+        let model = match Model::find(&models, value) {
+            Some(model) => model,
+            None => match Model::search(&models, value).as_slice() {
+                [] => bail!("{}", unknown_model(&models, value)),
+                [model] => (*model).clone(),
+                found => {
+                    let ids: Vec<String> = found.iter().map(|v| format!("  {}", v.id())).collect();
+                    bail!("Ambiguous model '{}', matches:\n{}", value, ids.join("\n"))
                 }
-                self.model = model;
-                Ok(())
-            }
+            },
+        };
+        Ok(model)
+    }
+
+    /// Switch to the model `value`, which can also be part of the id of a single model
+    pub fn set_model(&mut self, value: &str) -> Result<()> {
+        let model = self.find_model(value)?;
+        if let Some(session) = self.session.as_mut() {
+            session.set_model(model.clone())?;
         }
+        self.model = model;
+        Ok(())
+    }
+
+    /// Switch between the two `swap_models`, to the first one unless it is the current model
+    pub fn swap_model(&mut self) -> Result<String> {
+        let [first, second] = self.swap_models.as_slice() else {
+            bail!("Set two models in `swap_models` to swap between them");
+        };
+        let target = if self.model.id() == *first {
+            second.clone()
+        } else {
+            first.clone()
+        };
+        self.set_model(&target)?;
+        self.remember_project_model()?;
+        Ok(self.model.id())
+    }
+
+    /// The model remembered for the current directory, if `project_models` is on
+    fn project_model(&self) -> Option<String> {
+        if !self.project_models {
+            return None;
+        }
+        let cwd = env::current_dir().ok()?;
+        let mut models = load_project_models().ok()?;
+        models.remove(&cwd.display().to_string())
+    }
+
+    /// Remember the current model for the current directory, if `project_models` is on
+    pub fn remember_project_model(&self) -> Result<()> {
+        if !self.project_models {
+            return Ok(());
+        }
+        let cwd = env::current_dir().with_context(|| "Failed to get the current directory")?;
+        let mut models = load_project_models()?;
+        models.insert(cwd.display().to_string(), self.model.id());
+        let path = Self::project_models_file()?;
+        ensure_parent_exists(&path)?;
+        let content =
+            serde_yaml::to_string(&models).with_context(|| "Failed to serde project models")?;
+        std::fs::write(&path, content)
+            .with_context(|| format!("Failed to write project models to {}", path.display()))?;
+        Ok(())
     }
 
     pub fn system_info(&self) -> Result<String> {
@@ -418,32 +952,157 @@ impl Config {
             .wrap
             .clone()
             .map_or_else(|| String::from("no"), |v| v.to_string());
+        let swap_models = (!self.swap_models.is_empty()).then(|| self.swap_models.join(", "));
         let items = vec![
             ("model", self.model.id()),
             ("temperature", format_option(&self.temperature)),
             ("top_p", format_option(&self.top_p)),
+            ("seed", format_option(&self.seed)),
+            ("request_user", format_option(&self.request_user)),
+            (
+                "blocked_fallback_model",
+                format_option(&self.blocked_fallback_model),
+            ),
+            ("on_disconnect", self.on_disconnect.stringify().into()),
             ("dry_run", self.dry_run.to_string()),
+            ("offline", self.offline.to_string()),
+            ("verbose", self.verbose.to_string()),
             ("save", self.save.to_string()),
             ("save_session", format_option(&self.save_session)),
+            (
+                "save_session_on_exit",
+                self.save_session_on_exit.to_string(),
+            ),
+            ("on_exit", format_option(&self.on_exit)),
             ("highlight", self.highlight.to_string()),
             ("light_theme", self.light_theme.to_string()),
             ("wrap", wrap),
             ("wrap_code", self.wrap_code.to_string()),
+            ("soft_wrap", self.soft_wrap.to_string()),
+            ("code_lang", format_option(&self.code_lang)),
+            ("instant_first_token", self.instant_first_token.to_string()),
+            ("adaptive_batch", self.adaptive_batch.to_string()),
+            ("show_model", self.show_model.to_string()),
+            ("turn_separator", self.turn_separator.stringify().into()),
+            ("compact", self.compact.to_string()),
+            ("max_stream_rows", self.max_stream_rows.to_string()),
+            ("pipe_output", self.pipe_output.stringify().into()),
+            ("reply_ansi", self.reply_ansi.stringify().into()),
+            ("tee_output", format_option(&self.tee_output)),
+            ("tee_append", self.tee_append.to_string()),
+            ("hyperlinks", self.hyperlinks.to_string()),
+            ("citations", self.citations.to_string()),
+            ("truncation_indicator", self.truncation_indicator.clone()),
             ("auto_copy", self.auto_copy.to_string()),
             ("keybindings", self.keybindings.stringify().into()),
+            ("ctrl_d", self.ctrl_d.stringify().into()),
+            ("submit_key", self.submit_key.stringify().into()),
+            ("swap_models", format_option(&swap_models)),
+            ("swap_models_key", self.swap_models_key.clone()),
+            ("empty_input", self.empty_input.stringify().into()),
+            ("crlf_files", self.crlf_files.stringify().into()),
             ("prelude", format_option(&self.prelude)),
+            ("startup_file", format_option(&self.startup_file)),
+            ("default_role", format_option(&self.default_role)),
+            ("project_models", self.project_models.to_string()),
+            ("max_input_chars", format_option(&self.max_input_chars)),
+            ("batch_delimiter", format_option(&self.batch_delimiter)),
+            ("copy_history", self.copy_history.to_string()),
             ("compress_threshold", self.compress_threshold.to_string()),
+            ("compress_timeout", self.compress_timeout.to_string()),
+            ("compress_retries", self.compress_retries.to_string()),
+            ("compress_keep_last", self.compress_keep_last.to_string()),
+            ("compress_idle", format_option(&self.compress_idle)),
+            ("staged_files", format_staged_files(&self.staged_files)),
             ("config_file", display_path(&Self::config_file()?)),
             ("roles_file", display_path(&Self::roles_file()?)),
+            ("roles_dir", display_path(&Self::roles_dir()?)),
+            ("models_file", display_path(&Self::models_file()?)),
+            ("macros_file", display_path(&Self::macros_file()?)),
+            ("colors_file", display_path(&Self::colors_file()?)),
+            (
+                "project_models_file",
+                display_path(&Self::project_models_file()?),
+            ),
             ("messages_file", display_path(&Self::messages_file()?)),
             ("sessions_dir", display_path(&Self::sessions_dir()?)),
         ];
-        let output = items
+        Ok(format_info(&items))
+    }
+
+    /// List the resolved paths in use and whether they exist, for debugging env overrides
+    pub fn paths_info(&self) -> Result<String> {
+        let items = [
+            ("config_dir", Self::config_dir()?),
+            ("config_file", Self::config_file()?),
+            ("config_overlay_file", Self::config_overlay_file()?),
+            ("roles_file", Self::roles_file()?),
+            ("roles_dir", Self::roles_dir()?),
+            ("models_file", Self::models_file()?),
+            ("macros_file", Self::macros_file()?),
+            ("colors_file", Self::colors_file()?),
+            ("project_models_file", Self::project_models_file()?),
+            ("messages_file", Self::messages_file()?),
+            ("sessions_dir", Self::sessions_dir()?),
+        ];
+        let items: Vec<_> = items
             .iter()
-            .map(|(name, value)| format!("{name:<20}{value}"))
-            .collect::<Vec<String>>()
-            .join("\n");
-        Ok(output)
+            .map(|(name, path)| {
+                let state = match (*name, path.exists()) {
+                    (_, false) => " (missing)",
+                    ("config_overlay_file", true) if self.overlay_merged => " (merged)",
+                    ("config_overlay_file", true) => " (not merged)",
+                    _ => "",
+                };
+                (*name, format!("{}{state}", path.display()))
+            })
+            .collect();
+        Ok(format_info(&items))
+    }
+
+    pub fn model_info(&self) -> Result<String> {
+        let input = Input::from_str("", self.input_context());
+        let ((temperature, _), (top_p, _)) = self.sampling_params(&input);
+        let mut items = model_items(&self.model);
+        items.push(("temperature", format_option(&temperature)));
+        items.push(("top_p", format_option(&top_p)));
+        Ok(format_info(&items))
+    }
+
+    /// Inspect a model of the registry without switching to it
+    pub fn model_info_of(&self, name: &str) -> Result<String> {
+        let model = self.find_model(name)?;
+        Ok(format_info(&model_items(&model)))
+    }
+
+    /// The parameters the next request is sent with, and where each comes from
+    pub fn params_info(&self) -> Result<String> {
+        let input = Input::from_str("", self.input_context());
+        let ((temperature, temperature_source), (top_p, top_p_source)) =
+            self.sampling_params(&input);
+        let session = input.session(&self.session);
+        let role = input.role();
+        let cache_prompt = session
+            .and_then(|v| v.cache_prompt())
+            .or_else(|| role.and_then(|v| v.cache_prompt))
+            .unwrap_or_default();
+        let with_source = |value: String, source: &str| match source {
+            "" => value,
+            _ => format!("{value} ({source})"),
+        };
+        let items = [
+            ("model", self.model.id()),
+            (
+                "temperature",
+                with_source(format_option(&temperature), temperature_source),
+            ),
+            ("top_p", with_source(format_option(&top_p), top_p_source)),
+            ("max_tokens", format_option(&self.model.max_output_tokens)),
+            ("seed", format_option(&self.seed)),
+            ("user", format_option(&self.request_user())),
+            ("cache_prompt", cache_prompt.to_string()),
+        ];
+        Ok(format_info(&items))
     }
 
     pub fn role_info(&self) -> Result<String> {
@@ -458,12 +1117,25 @@ impl Config {
         if let Some(session) = &self.session {
             let render_options = self.get_render_options()?;
             let mut markdown_render = MarkdownRender::init(render_options)?;
-            session.info(&mut markdown_render)
+            session.info(&mut markdown_render, self.verbose)
         } else {
             bail!("No session")
         }
     }
 
+    /// Write the session rendered with ANSI escapes to `path`
+    pub fn export_session_ansi(&self, path: &str) -> Result<()> {
+        let session = match &self.session {
+            Some(session) => session,
+            None => bail!("No session"),
+        };
+        let render_options = self.get_render_options()?;
+        let mut markdown_render = MarkdownRender::init(render_options)?;
+        let output = session.export_ansi(&mut markdown_render)?;
+        std::fs::write(path, output).with_context(|| format!("Failed to write to '{path}'"))?;
+        Ok(())
+    }
+
     pub fn info(&self) -> Result<String> {
         if let Some(session) = &self.session {
             session.export()
@@ -481,25 +1153,85 @@ impl Config {
             .unwrap_or_default()
     }
 
+    /// Get a recent reply, 1 is the latest
+    pub fn nth_reply(&self, index: usize) -> Result<&str> {
+        match index.checked_sub(1).and_then(|i| self.reply_history.get(i)) {
+            Some(reply) => Ok(reply),
+            None => bail!(
+                "No reply #{index}, {} replies are kept",
+                self.reply_history.len()
+            ),
+        }
+    }
+
+    pub fn session_transcript(&self) -> Result<String> {
+        match &self.session {
+            Some(session) => session.transcript(),
+            None => bail!("No session"),
+        }
+    }
+
+    /// Summarize the recent replies, if any is cut short the full list is kept for `.full`
+    pub fn list_replies(&mut self) -> String {
+        let mut truncated = false;
+        let output = self
+            .reply_history
+            .iter()
+            .enumerate()
+            .map(|(i, reply)| {
+                let text = reply.split_whitespace().collect::<Vec<&str>>().join(" ");
+                let mut summary: String = text.chars().take(70).collect();
+                if summary.len() < text.len() {
+                    summary.push_str(&self.truncation_indicator);
+                    truncated = true;
+                }
+                format!("{:<4}{summary}", i + 1)
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        if truncated {
+            let full = self
+                .reply_history
+                .iter()
+                .enumerate()
+                .map(|(i, reply)| format!("#{}\n{reply}", i + 1))
+                .collect::<Vec<String>>()
+                .join("\n\n");
+            self.full_output = Some(full);
+        }
+        output
+    }
+
+    /// The last output that was cut short, or else the last reply
+    pub fn full_output(&self) -> &str {
+        self.full_output.as_deref().unwrap_or(self.last_reply())
+    }
+
     pub fn repl_complete(&self, cmd: &str, args: &[&str]) -> Vec<String> {
         let (values, filter) = if args.len() == 1 {
             let values = match cmd {
-                ".role" => self.roles.iter().map(|v| v.name.clone()).collect(),
-                ".model" => list_models(self).into_iter().map(|v| v.id()).collect(),
+                ".role" => ["list ", "clone "]
+                    .into_iter()
+                    .map(|v| v.to_string())
+                    .chain(self.roles.iter().map(|v| v.name.clone()))
+                    .collect(),
+                ".model" => ["info "]
+                    .into_iter()
+                    .map(|v| v.to_string())
+                    .chain(list_models(self).into_iter().map(|v| v.id()))
+                    .collect(),
                 ".session" => self.list_sessions(),
-                ".set" => vec![
-                    "temperature ",
-                    "top_p ",
-                    "compress_threshold",
-                    "save ",
-                    "save_session ",
-                    "highlight ",
-                    "dry_run ",
-                    "auto_copy ",
-                ]
-                .into_iter()
-                .map(|v| v.to_string())
-                .collect(),
+                ".file" => vec!["add ", "clear"]
+                    .into_iter()
+                    .map(|v| v.to_string())
+                    .collect(),
+                ".full" => vec!["pager".to_string()],
+                ".export" => vec!["ansi ".to_string()],
+                ".macro" => vec!["record ", "stop", "run "]
+                    .into_iter()
+                    .map(|v| v.to_string())
+                    .collect(),
+                ".set" => SET_KEYS.iter().map(|v| format!("{v} ")).collect(),
                 _ => vec![],
             };
             (values, args[0])
@@ -516,7 +1248,23 @@ impl Config {
                 }
                 "highlight" => complete_bool(self.highlight),
                 "dry_run" => complete_bool(self.dry_run),
+                "offline" => complete_bool(self.offline),
+                "cache_prompt" => {
+                    let cache_prompt = if let Some(session) = &self.session {
+                        session.cache_prompt()
+                    } else {
+                        self.role.as_ref().and_then(|role| role.cache_prompt)
+                    };
+                    complete_option_bool(cache_prompt)
+                }
                 "auto_copy" => complete_bool(self.auto_copy),
+                "show_model" => complete_bool(self.show_model),
+                "compact" => complete_bool(self.compact),
+                "merge" if cmd == ".session" => self.list_sessions(),
+                "run" if cmd == ".macro" => self.macros.keys().cloned().collect(),
+                "info" if cmd == ".model" => {
+                    list_models(self).into_iter().map(|v| v.id()).collect()
+                }
                 _ => vec![],
             };
             (values, args[1])
@@ -545,10 +1293,32 @@ impl Config {
                 let value = parse_value(value)?;
                 self.set_top_p(value);
             }
+            "seed" => {
+                self.seed = parse_value(value)?;
+            }
+            "request_user" => {
+                self.request_user = parse_value(value)?;
+            }
+            "tee_output" => {
+                self.tee_output = parse_value(value)?;
+            }
             "compress_threshold" => {
-                let value = parse_value(value)?;
+                let value = parse_value(value).map_err(|_| {
+                    anyhow!("Invalid compress_threshold '{value}', expected a positive integer")
+                })?;
+                self.check_compress_threshold(value)?;
                 self.set_compress_threshold(value);
             }
+            "cache_prompt" => {
+                let value = parse_value(value)?;
+                self.set_cache_prompt(value)?;
+            }
+            "max_input_chars" => {
+                self.max_input_chars = parse_value(value)?;
+            }
+            "batch_delimiter" => {
+                self.batch_delimiter = parse_value(value)?;
+            }
             "save" => {
                 let value = value.parse().with_context(|| "Invalid value")?;
                 self.save = value;
@@ -565,11 +1335,23 @@ impl Config {
                 let value = value.parse().with_context(|| "Invalid value")?;
                 self.dry_run = value;
             }
+            "offline" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.offline = value;
+            }
             "auto_copy" => {
                 let value = value.parse().with_context(|| "Invalid value")?;
                 self.auto_copy = value;
             }
-            _ => bail!("Unknown key `{key}`"),
+            "show_model" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.show_model = value;
+            }
+            "compact" => {
+                let value = value.parse().with_context(|| "Invalid value")?;
+                self.compact = value;
+            }
+            _ => bail!("{}", unknown_set_key(key)),
         }
         Ok(())
     }
@@ -603,6 +1385,7 @@ impl Config {
                 }
             }
         }
+        self.apply_default_role()?;
         if let Some(session) = self.session.as_mut() {
             if session.is_empty() {
                 if let Some((input, output)) = &self.last_message {
@@ -620,6 +1403,23 @@ impl Config {
         Ok(())
     }
 
+    /// Apply `default_role` to a new session started without a role
+    fn apply_default_role(&mut self) -> Result<()> {
+        let Some(name) = self.default_role.clone() else {
+            return Ok(());
+        };
+        match &self.session {
+            Some(session) if session.is_empty() && self.role.is_none() => {}
+            _ => return Ok(()),
+        }
+        self.set_role(&name)
+            .with_context(|| format!("Failed to apply default_role '{name}'"))?;
+        if let Some(session) = self.session.as_mut() {
+            session.default_role = Some(name);
+        }
+        Ok(())
+    }
+
     pub fn end_session(&mut self) -> Result<()> {
         if let Some(mut session) = self.session.take() {
             self.last_message = None;
@@ -643,6 +1443,26 @@ impl Config {
         Ok(())
     }
 
+    /// End the session when leaving the REPL, saving unsaved changes without asking if `save_session_on_exit` is set
+    pub fn end_session_on_exit(&mut self) -> Result<()> {
+        if self.save_session_on_exit {
+            if let Some(session) = self.session.as_mut() {
+                if session.dirty && session.save_session() != Some(false) {
+                    if session.is_temp() || session.name().is_empty() {
+                        let name = Text::new("Session name (leave empty to discard):").prompt()?;
+                        session.name = name.trim().to_string();
+                    }
+                    if session.name().is_empty() {
+                        session.dirty = false;
+                    } else {
+                        Self::save_session_to_file(session)?;
+                    }
+                }
+            }
+        }
+        self.end_session()
+    }
+
     pub fn save_session(&mut self, name: &str) -> Result<()> {
         if let Some(session) = self.session.as_mut() {
             if !name.is_empty() {
@@ -657,13 +1477,161 @@ impl Config {
         self.session.is_some()
     }
 
-    pub fn clear_session_messages(&mut self) -> Result<()> {
+    pub fn clear_session_messages(&mut self, all: bool) -> Result<()> {
         if let Some(session) = self.session.as_mut() {
-            session.clear_messages();
+            session.clear_messages(all);
+            if all {
+                self.role = None;
+            }
         }
         Ok(())
     }
 
+    /// Add a system message to the session history, which is sent with the following requests
+    pub fn add_system_message(&mut self, text: &str) -> Result<()> {
+        let session = match self.session.as_mut() {
+            Some(session) => session,
+            None => bail!("No session"),
+        };
+        if session.is_empty() && self.role.is_some() {
+            bail!("Cannot add a system message before the first message of a session with a role");
+        }
+        session.add_system_message(text);
+        Ok(())
+    }
+
+    pub fn add_session_note(&mut self, text: &str) -> Result<()> {
+        match self.session.as_mut() {
+            Some(session) => session.add_note(text),
+            None => bail!("No session"),
+        }
+        Ok(())
+    }
+
+    /// Append the messages of another session, or with `before_if_older` put them first if its file is older
+    pub fn merge_session(&mut self, name: &str, before_if_older: bool) -> Result<()> {
+        let session = match self.session.as_mut() {
+            Some(session) => session,
+            None => bail!("No session"),
+        };
+        if session.name() == name {
+            bail!("Cannot merge the session '{name}' into itself");
+        }
+        let session_path = Self::session_file(name)?;
+        if !session_path.exists() {
+            bail!("Unknown session '{name}'");
+        }
+        let other = Session::load(name, &session_path)?;
+        if other.model() != session.model() {
+            println!(
+                "⚠️ The session '{name}' uses a different model '{}'",
+                other.model()
+            );
+        }
+        // Messages carry no timestamps, so sessions are ordered by the modification time of their files
+        let prepend = before_if_older && {
+            let modified = |path: &Path| path.metadata().and_then(|v| v.modified()).ok();
+            match (
+                modified(&session_path),
+                session.path.as_ref().and_then(|v| modified(Path::new(v))),
+            ) {
+                (Some(other_time), Some(current_time)) => other_time < current_time,
+                (Some(_), None) => true,
+                _ => false,
+            }
+        };
+        if session.merge(other, prepend) {
+            println!("⚠️ The system message of the session '{name}' differs and was dropped");
+        }
+        Ok(())
+    }
+
+    pub fn start_recording_macro(&mut self, name: &str) -> Result<()> {
+        if let Some((name, _)) = &self.recording_macro {
+            bail!("Already recording macro '{name}', use `.macro stop` first");
+        }
+        self.recording_macro = Some((name.to_string(), vec![]));
+        Ok(())
+    }
+
+    pub fn stop_recording_macro(&mut self) -> Result<()> {
+        let (name, steps) = self
+            .recording_macro
+            .take()
+            .ok_or_else(|| anyhow!("No macro is being recorded"))?;
+        if steps.is_empty() {
+            bail!("Nothing recorded, macro '{name}' is discarded");
+        }
+        self.macros.insert(name.clone(), steps);
+        let path = Self::macros_file()?;
+        ensure_parent_exists(&path)?;
+        let content = serde_yaml::to_string(&self.macros)
+            .with_context(|| format!("Failed to serde macro '{name}'"))?;
+        std::fs::write(&path, content)
+            .with_context(|| format!("Failed to write macros to {}", path.display()))?;
+        println!("✨ Saved macro '{name}' to '{}'", path.display());
+        Ok(())
+    }
+
+    /// Append a successfully handled REPL line to the macro being recorded
+    pub fn record_macro_step(&mut self, line: &str) {
+        if let Some((_, steps)) = self.recording_macro.as_mut() {
+            let trimed_line = line.trim();
+            if trimed_line.starts_with(".macro record") || trimed_line.starts_with(".macro stop") {
+                return;
+            }
+            steps.push(line.to_string());
+        }
+    }
+
+    pub fn get_macro(&self, name: &str) -> Result<Vec<String>> {
+        self.macros
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyhow!("Unknown macro '{name}'"))
+    }
+
+    /// Keep files attached to every following message until `.file clear`
+    pub fn stage_files(&mut self, files: Vec<String>) -> Result<()> {
+        Input::new("", files.clone(), self.input_context())?;
+        for file in files {
+            if !self.staged_files.contains(&file) {
+                self.staged_files.push(file);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn clear_staged_files(&mut self) {
+        self.staged_files.clear();
+    }
+
+    /// Build the input for a message, attaching the staged files before the extra ones
+    pub fn new_input(&self, text: &str, files: Vec<String>) -> Result<Input> {
+        self.new_input_with_context(text, files, self.input_context())
+    }
+
+    /// An input sent with the role alone, outside of the current session, like `.role <name> <text>`
+    pub fn new_role_input(&self, role: Role, text: &str, files: Vec<String>) -> Result<Input> {
+        let context = InputContext::new(Some(role), false).with_crlf(self.crlf_files);
+        self.new_input_with_context(text, files, context)
+    }
+
+    fn new_input_with_context(
+        &self,
+        text: &str,
+        files: Vec<String>,
+        context: InputContext,
+    ) -> Result<Input> {
+        let mut all_files = self.staged_files.clone();
+        all_files.extend(files.into_iter().filter(|v| !self.staged_files.contains(v)));
+        if all_files.is_empty() {
+            Ok(Input::from_str(text, context))
+        } else {
+            Input::new(text, all_files, context)
+        }
+    }
+
     pub fn list_sessions(&self) -> Vec<String> {
         let sessions_dir = match Self::sessions_dir() {
             Ok(dir) => dir,
@@ -685,20 +1653,80 @@ impl Config {
         }
     }
 
+    /// Summarize the saved sessions, to help decide which ones to prune
+    pub fn session_stats(&self) -> Result<String> {
+        let mut sessions = 0;
+        let mut unreadable = 0;
+        let mut tokens = 0;
+        let mut size = 0;
+        let mut oldest: Option<(SystemTime, String)> = None;
+        let mut newest: Option<(SystemTime, String)> = None;
+        for name in self.list_sessions() {
+            let path = Self::session_file(&name)?;
+            sessions += 1;
+            if let Ok(metadata) = path.metadata() {
+                size += metadata.len();
+                if let Ok(modified) = metadata.modified() {
+                    if oldest.as_ref().is_none_or(|(v, _)| modified < *v) {
+                        oldest = Some((modified, name.clone()));
+                    }
+                    if newest.as_ref().is_none_or(|(v, _)| modified > *v) {
+                        newest = Some((modified, name.clone()));
+                    }
+                }
+            }
+            match Session::load(&name, &path) {
+                Ok(session) => tokens += session.tokens(),
+                Err(_) => unreadable += 1,
+            }
+        }
+        if sessions == 0 {
+            bail!("No saved sessions")
+        }
+        let format_time = |value: Option<(SystemTime, String)>| match value {
+            Some((time, name)) => {
+                let time: chrono::DateTime<chrono::Local> = time.into();
+                format!("{name} ({})", time.format("%Y-%m-%d %H:%M"))
+            }
+            None => "-".into(),
+        };
+        let mut items = vec![
+            ("sessions", sessions.to_string()),
+            ("tokens", tokens.to_string()),
+            ("disk_usage", format_size(size)),
+            ("oldest", format_time(oldest)),
+            ("newest", format_time(newest)),
+        ];
+        if unreadable > 0 {
+            items.push(("unreadable", unreadable.to_string()));
+        }
+        Ok(format_info(&items))
+    }
+
     pub fn should_compress_session(&mut self) -> bool {
+        if !self.need_compress_session() {
+            return false;
+        }
         if let Some(session) = self.session.as_mut() {
-            if session.need_compress(self.compress_threshold) {
-                session.compressing = true;
-                return true;
-            }
+            session.compressing = true;
         }
-        false
+        true
+    }
+
+    pub fn need_compress_session(&self) -> bool {
+        self.session
+            .as_ref()
+            .map(|v| !v.compressing && v.need_compress(self.compress_threshold))
+            .unwrap_or_default()
     }
 
     pub fn compress_session(&mut self, summary: &str) {
         if let Some(session) = self.session.as_mut() {
             let summary_prompt = self.summary_prompt.as_deref().unwrap_or(SUMMARY_PROMPT);
-            session.compress(format!("{}{}", summary_prompt, summary));
+            session.compress(
+                format!("{}{}", summary_prompt, summary),
+                self.compress_keep_last,
+            );
         }
     }
 
@@ -744,59 +1772,154 @@ impl Config {
         } else {
             None
         };
-        let truecolor = matches!(
-            env::var("COLORTERM").as_ref().map(|v| v.as_str()),
-            Ok("truecolor")
-        );
-        Ok(RenderOptions::new(theme, wrap, self.wrap_code, truecolor))
+        Ok(RenderOptions {
+            citations: self.citations,
+            truncation_indicator: Some(self.truncation_indicator.clone()),
+            code_color: self.color_theme.code.clone(),
+            soft_wrap: self.soft_wrap && stdout().is_terminal(),
+            ..RenderOptions::new(
+                theme,
+                wrap,
+                self.wrap_code,
+                truecolor(),
+                self.code_lang.clone(),
+                self.heading_styles.clone(),
+                self.hyperlinks,
+            )
+        })
     }
 
-    pub fn render_prompt_left(&self) -> String {
-        let variables = self.generate_prompt_context();
-        let left_prompt = self.left_prompt.as_deref().unwrap_or(LEFT_PROMPT);
-        render_prompt(left_prompt, &variables)
+    /// The colors of `colors.yaml`, falling back to the defaults of the light or dark theme
+    pub fn colors(&self) -> Colors {
+        self.color_theme.resolve(self.light_theme, truecolor())
     }
 
-    pub fn render_prompt_right(&self) -> String {
-        let variables = self.generate_prompt_context();
-        let right_prompt = self.right_prompt.as_deref().unwrap_or(RIGHT_PROMPT);
-        render_prompt(right_prompt, &variables)
+    /// The color errors are printed in, `None` if highlighting is off
+    pub fn error_color(&self) -> Option<Color> {
+        self.highlight.then(|| self.colors().error)
     }
 
-    pub fn prepare_send_data(&self, input: &Input, stream: bool) -> Result<SendData> {
-        let messages = self.build_messages(input)?;
-        let temperature = if let Some(session) = input.session(&self.session) {
-            session.temperature()
-        } else if let Some(role) = input.role() {
-            role.temperature
-        } else {
-            self.temperature
+    pub fn render_prompt_left(&self) -> String {
+        let (left_prompt, _) = self.render_prompts(None);
+        left_prompt
+    }
+
+    /// Render the left and right prompts, dropping the variables of `prompt_drop_order`
+    /// one by one while they are together wider than `max_width`
+    pub fn render_prompts(&self, max_width: Option<usize>) -> (String, String) {
+        let mut variables = self.generate_prompt_context();
+        let left_template = self.left_prompt.as_deref().unwrap_or(LEFT_PROMPT);
+        let right_template = self.right_prompt.as_deref().unwrap_or(RIGHT_PROMPT);
+        let render = |variables: &HashMap<&str, String>| {
+            (
+                render_prompt(left_template, variables),
+                render_prompt(right_template, variables),
+            )
+        };
+        let mut prompts = render(&variables);
+        let Some(max_width) = max_width else {
+            return prompts;
+        };
+        for name in &self.prompt_drop_order {
+            if prompt_width(&prompts.0) + prompt_width(&prompts.1) <= max_width {
+                break;
+            }
+            variables.remove(name.as_str());
+            prompts = render(&variables);
+        }
+        prompts
+    }
+
+    /// Resolve temperature and top_p, each with where it comes from: explicit `.set` > role/session > model default > global default
+    pub fn sampling_params(&self, input: &Input) -> (SamplingParam, SamplingParam) {
+        let (temperature_overridden, top_p_overridden) = self.sampling_overridden;
+        let or = |value: Option<f64>, source, fallback: SamplingParam| match value {
+            Some(_) => (value, source),
+            None => fallback,
+        };
+        let model = |value: Option<f64>| or(value, "model", (None, ""));
+        let global = |overridden, model_value, value| match overridden {
+            true => (value, ".set"),
+            false => or(model_value, "model", or(value, "config", (None, ""))),
         };
-        let top_p = if let Some(session) = input.session(&self.session) {
-            session.top_p()
+        let global_temperature = global(
+            temperature_overridden,
+            self.model.default_temperature,
+            self.temperature,
+        );
+        let global_top_p = global(top_p_overridden, self.model.default_top_p, self.top_p);
+        if let Some(session) = input.session(&self.session) {
+            (
+                or(session.temperature(), "session", global_temperature),
+                or(session.top_p(), "session", global_top_p),
+            )
         } else if let Some(role) = input.role() {
-            role.top_p
+            (
+                or(
+                    role.temperature,
+                    "role",
+                    model(self.model.default_temperature),
+                ),
+                or(role.top_p, "role", model(self.model.default_top_p)),
+            )
         } else {
-            self.top_p
-        };
+            (global_temperature, global_top_p)
+        }
+    }
+
+    pub fn prepare_send_data(&self, input: &Input, stream: bool) -> Result<SendData> {
+        let messages = self.build_messages(input)?;
+        let ((temperature, _), (top_p, _)) = self.sampling_params(input);
+        let cache_prompt = input
+            .session(&self.session)
+            .and_then(|session| session.cache_prompt())
+            .or_else(|| input.role().and_then(|role| role.cache_prompt))
+            .unwrap_or_default();
         self.model.max_input_tokens_limit(&messages)?;
+        let thread = input.session(&self.session).map(|session| session.thread());
         Ok(SendData {
             messages,
             temperature,
             top_p,
+            seed: self.seed,
+            user: self.request_user(),
             stream,
+            cache_prompt,
+            thread,
         })
     }
 
+    /// The `user` sent to the provider, `machine` stands for a hash of the machine id
+    pub fn request_user(&self) -> Option<String> {
+        match self.request_user.as_deref()? {
+            "" => None,
+            "machine" => Some(sha256sum(&format!("aichat:{}", machine_id()))),
+            v => Some(v.to_string()),
+        }
+    }
+
     pub fn input_context(&self) -> InputContext {
-        InputContext::new(self.role.clone(), self.has_session())
+        InputContext::new(self.role.clone(), self.has_session()).with_crlf(self.crlf_files)
     }
 
     pub fn maybe_print_send_tokens(&self, input: &Input) {
         if self.dry_run {
+            let file_tokens = input.file_tokens();
+            if !file_tokens.is_empty() {
+                let files: Vec<String> = file_tokens
+                    .into_iter()
+                    .map(|(file, tokens)| format!("{file} ({tokens})"))
+                    .collect();
+                println!(">>> Files: {} <<<", files.join(", "));
+            }
             if let Ok(messages) = self.build_messages(input) {
                 let tokens = self.model.total_tokens(&messages);
-                println!(">>> This message consumes {tokens} tokens. <<<");
+                match self.model.estimate_cost(tokens, 0) {
+                    Some(cost) => {
+                        println!(">>> This message consumes {tokens} tokens (~${cost:.6}). <<<")
+                    }
+                    None => println!(">>> This message consumes {tokens} tokens. <<<"),
+                }
             }
         }
     }
@@ -845,8 +1968,12 @@ impl Config {
             output.insert("consume_percent", percent.to_string());
             output.insert("user_messages_len", session.user_messages_len().to_string());
         }
+        if !self.staged_files.is_empty() {
+            output.insert("staged_files", self.staged_files.len().to_string());
+        }
 
         if self.highlight {
+            output.insert("color.prompt", self.colors().prompt.prefix().to_string());
             output.insert("color.reset", "\u{1b}[0m".to_string());
             output.insert("color.black", "\u{1b}[30m".to_string());
             output.insert("color.dark_gray", "\u{1b}[90m".to_string());
@@ -895,38 +2022,151 @@ impl Config {
         Ok(())
     }
 
-    fn load_config(config_path: &Path) -> Result<Self> {
+    /// Load the config file with the overlay, if it exists, deep merged on top
+    fn load_config(config_path: &Path, overlay_path: &Path) -> Result<Self> {
         let ctx = || format!("Failed to load config at {}", config_path.display());
         let content = read_to_string(config_path).with_context(ctx)?;
 
-        let config: Self = serde_yaml::from_str(&content)
+        let overlay_merged = overlay_path.exists();
+        let config: Result<Self, _> = if overlay_merged {
+            let overlay_ctx = || {
+                format!(
+                    "Failed to load config overlay at {}",
+                    overlay_path.display()
+                )
+            };
+            let overlay_content = read_to_string(overlay_path).with_context(overlay_ctx)?;
+            let overlay: serde_yaml::Value =
+                serde_yaml::from_str(&overlay_content).with_context(overlay_ctx)?;
+            let mut value: serde_yaml::Value = serde_yaml::from_str(&content).with_context(ctx)?;
+            if value.is_null() {
+                value = serde_yaml::Value::Mapping(Default::default());
+            }
+            merge_yaml(&mut value, overlay);
+            serde_yaml::from_value(value)
+        } else {
+            serde_yaml::from_str(&content)
+        };
+        let mut config: Self = config
             .map_err(|err| {
                 let err_msg = err.to_string();
-                if err_msg.starts_with(&format!("{}: ", CLIENTS_FIELD)) {
+                if err_msg.starts_with(&format!("{}: ", CLIENTS_FIELD))
+                    && !err_msg.contains(JSON_OBJECT_ERROR)
+                {
                     anyhow!("clients: invalid value")
                 } else {
                     anyhow!("{err_msg}")
                 }
             })
             .with_context(ctx)?;
+        config.overlay_merged = overlay_merged;
 
         Ok(config)
     }
 
     fn load_roles(&mut self) -> Result<()> {
         let path = Self::roles_file()?;
+        if path.exists() {
+            let content = read_to_string(&path)
+                .with_context(|| format!("Failed to load roles at {}", path.display()))?;
+            let roles: Vec<Role> =
+                serde_yaml::from_str(&content).with_context(|| "Invalid roles config")?;
+            self.roles = roles;
+        }
+        self.load_roles_dir(&Self::roles_dir()?)
+    }
+
+    /// Load one role per yaml file in `dir`, the name defaults to the file stem
+    fn load_roles_dir(&mut self, dir: &Path) -> Result<()> {
+        let Ok(rd) = read_dir(dir) else {
+            return Ok(());
+        };
+        let mut paths: Vec<PathBuf> = rd
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|ext| ext == "yaml" || ext == "yml")
+            })
+            .collect();
+        paths.sort();
+        for path in paths {
+            let content = read_to_string(&path)
+                .with_context(|| format!("Failed to load role at {}", path.display()))?;
+            let mut value: serde_yaml::Value = serde_yaml::from_str(&content)
+                .with_context(|| format!("Invalid role at {}", path.display()))?;
+            if let (Some(map), Some(stem)) = (value.as_mapping_mut(), path.file_stem()) {
+                if !map.contains_key("name") {
+                    map.insert("name".into(), stem.to_string_lossy().into());
+                }
+            }
+            let role: Role = serde_yaml::from_value(value)
+                .with_context(|| format!("Invalid role at {}", path.display()))?;
+            if self.roles.iter().any(|v| v.name == role.name) {
+                let other = match self.role_files.get(&role.name) {
+                    Some(other) => other.clone(),
+                    None => Self::roles_file()?,
+                };
+                bail!(
+                    "Role '{}' is defined in both {} and {}",
+                    role.name,
+                    other.display(),
+                    path.display()
+                );
+            }
+            self.role_files.insert(role.name.clone(), path);
+            self.roles.push(role);
+        }
+        Ok(())
+    }
+
+    fn load_macros(&mut self) -> Result<()> {
+        let path = Self::macros_file()?;
+        if !path.exists() {
+            return Ok(());
+        }
+        let content = read_to_string(&path)
+            .with_context(|| format!("Failed to load macros at {}", path.display()))?;
+        self.macros = serde_yaml::from_str(&content).with_context(|| "Invalid macros config")?;
+        Ok(())
+    }
+
+    fn load_color_theme(&mut self) -> Result<()> {
+        let path = Self::colors_file()?;
+        if !path.exists() {
+            return Ok(());
+        }
+        let content = read_to_string(&path)
+            .with_context(|| format!("Failed to load colors at {}", path.display()))?;
+        let theme: ColorTheme = serde_yaml::from_str(&content)
+            .with_context(|| format!("Invalid colors at {}", path.display()))?;
+        theme
+            .validate()
+            .with_context(|| format!("Invalid colors at {}", path.display()))?;
+        self.color_theme = theme;
+        Ok(())
+    }
+
+    fn load_model_registry(&mut self) -> Result<()> {
+        let path = Self::models_file()?;
         if !path.exists() {
             return Ok(());
         }
         let content = read_to_string(&path)
-            .with_context(|| format!("Failed to load roles at {}", path.display()))?;
-        let roles: Vec<Role> =
-            serde_yaml::from_str(&content).with_context(|| "Invalid roles config")?;
-        self.roles = roles;
+            .with_context(|| format!("Failed to load models at {}", path.display()))?;
+        let registry: Vec<ModelRegistry> =
+            serde_yaml::from_str(&content).with_context(|| "Invalid models config")?;
+        self.model_registry = registry;
         Ok(())
     }
 
     fn setup_model(&mut self) -> Result<()> {
+        if let Some(model) = self.project_model() {
+            match self.set_model(&model) {
+                Ok(()) => return Ok(()),
+                Err(err) => eprintln!("⚠️ Ignored the model remembered for this directory: {err}"),
+            }
+        }
         let model = match &self.model_id {
             Some(v) => v.clone(),
             None => {
@@ -967,6 +2207,12 @@ impl Config {
         Ok(())
     }
 
+    fn setup_offline(&mut self) {
+        if let Ok(value) = env::var(get_env_name("offline")) {
+            set_bool(&mut self.offline, &value);
+        }
+    }
+
     fn compat_old_config(&mut self, config_path: &PathBuf) -> Result<()> {
         let content = read_to_string(config_path)?;
         let value: serde_json::Value = serde_yaml::from_str(&content)?;
@@ -1026,6 +2272,181 @@ impl Keybindings {
     }
 }
 
+/// What Ctrl+D does when the input line is not empty, it always exits on an empty line
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+pub enum CtrlDAction {
+    #[serde(rename = "delete")]
+    #[default]
+    Delete,
+    #[serde(rename = "submit")]
+    Submit,
+    #[serde(rename = "exit")]
+    Exit,
+}
+
+impl CtrlDAction {
+    pub fn stringify(&self) -> &str {
+        match self {
+            CtrlDAction::Delete => "delete",
+            CtrlDAction::Submit => "submit",
+            CtrlDAction::Exit => "exit",
+        }
+    }
+}
+
+/// The key that submits the REPL input, Enter inserts a newline when it is not the one
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+pub enum SubmitKey {
+    #[serde(rename = "enter")]
+    #[default]
+    Enter,
+    #[serde(rename = "alt_enter")]
+    AltEnter,
+    #[serde(rename = "ctrl_enter")]
+    CtrlEnter,
+}
+
+impl SubmitKey {
+    pub fn stringify(&self) -> &str {
+        match self {
+            SubmitKey::Enter => "enter",
+            SubmitKey::AltEnter => "alt_enter",
+            SubmitKey::CtrlEnter => "ctrl_enter",
+        }
+    }
+}
+
+/// What submitting an empty line does in the REPL
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+pub enum EmptyInputAction {
+    #[serde(rename = "ignore")]
+    #[default]
+    Ignore,
+    #[serde(rename = "regenerate")]
+    Regenerate,
+    #[serde(rename = "hint")]
+    Hint,
+}
+
+impl EmptyInputAction {
+    pub fn stringify(&self) -> &str {
+        match self {
+            EmptyInputAction::Ignore => "ignore",
+            EmptyInputAction::Regenerate => "regenerate",
+            EmptyInputAction::Hint => "hint",
+        }
+    }
+}
+
+/// What to do with attached text files that have CRLF line endings
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+pub enum CrlfAction {
+    #[serde(rename = "keep")]
+    Keep,
+    #[serde(rename = "warn")]
+    #[default]
+    Warn,
+    #[serde(rename = "normalize")]
+    Normalize,
+}
+
+impl CrlfAction {
+    pub fn stringify(&self) -> &str {
+        match self {
+            CrlfAction::Keep => "keep",
+            CrlfAction::Warn => "warn",
+            CrlfAction::Normalize => "normalize",
+        }
+    }
+}
+
+/// What is printed before each reply in the REPL to set the turns apart
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+pub enum TurnSeparator {
+    #[serde(rename = "none")]
+    #[default]
+    None,
+    /// An empty line
+    #[serde(rename = "blank")]
+    Blank,
+    /// A dim horizontal rule as wide as the terminal, or the `wrap` width if narrower
+    #[serde(rename = "rule")]
+    Rule,
+}
+
+impl TurnSeparator {
+    pub fn stringify(&self) -> &str {
+        match self {
+            TurnSeparator::None => "none",
+            TurnSeparator::Blank => "blank",
+            TurnSeparator::Rule => "rule",
+        }
+    }
+}
+
+/// How replies are printed when stdout is not a terminal
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+pub enum PipeOutput {
+    /// The markdown source as it arrives
+    #[serde(rename = "raw")]
+    #[default]
+    Raw,
+    /// Markdown rendered with ANSI escapes, line by line
+    #[serde(rename = "ansi")]
+    Ansi,
+}
+
+impl PipeOutput {
+    pub fn stringify(&self) -> &str {
+        match self {
+            PipeOutput::Raw => "raw",
+            PipeOutput::Ansi => "ansi",
+        }
+    }
+}
+
+/// What to do with ANSI escape codes in the text of a reply rendered to the terminal
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+pub enum ReplyAnsi {
+    /// Remove them, they could move the cursor or break the layout
+    #[serde(rename = "strip")]
+    #[default]
+    Strip,
+    /// Print them, taking no room in the layout
+    #[serde(rename = "passthrough")]
+    Passthrough,
+}
+
+impl ReplyAnsi {
+    pub fn stringify(&self) -> &str {
+        match self {
+            ReplyAnsi::Strip => "strip",
+            ReplyAnsi::Passthrough => "passthrough",
+        }
+    }
+}
+
+/// What to do when the connection is lost while a reply is streaming
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+pub enum DisconnectAction {
+    /// Report the error and drop the partial reply
+    #[serde(rename = "error")]
+    #[default]
+    Error,
+    /// Save the partial reply as truncated, so `.continue` can finish it
+    #[serde(rename = "keep")]
+    Keep,
+}
+
+impl DisconnectAction {
+    pub fn stringify(&self) -> &str {
+        match self {
+            DisconnectAction::Error => "error",
+            DisconnectAction::Keep => "keep",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum WorkingMode {
     Command,
@@ -1143,6 +2564,13 @@ fn set_bool(target: &mut bool, value: &str) {
     }
 }
 
+fn truecolor() -> bool {
+    matches!(
+        env::var("COLORTERM").as_ref().map(|v| v.as_str()),
+        Ok("truecolor")
+    )
+}
+
 fn parse_value<T>(value: &str) -> Result<Option<T>>
 where
     T: std::str::FromStr,
@@ -1159,6 +2587,20 @@ where
     Ok(value)
 }
 
+/// Lay out `name value` lines, with the values aligned one space past the longest name
+pub(crate) fn format_info(items: &[(&str, String)]) -> String {
+    let width = items
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or_default();
+    items
+        .iter()
+        .map(|(name, value)| format!("{name:<width$} {value}"))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
 fn format_option<T>(value: &Option<T>) -> String
 where
     T: std::fmt::Display,
@@ -1169,6 +2611,129 @@ where
     }
 }
 
+/// The models remembered per directory, keyed by the directory path
+fn load_project_models() -> Result<BTreeMap<String, String>> {
+    let path = Config::project_models_file()?;
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let content = read_to_string(&path)
+        .with_context(|| format!("Failed to load project models at {}", path.display()))?;
+    serde_yaml::from_str(&content)
+        .with_context(|| format!("Invalid project models at {}", path.display()))
+}
+
+/// Explain an unknown `.set` key, suggesting the closest valid key if one is close enough
+fn unknown_set_key(key: &str) -> String {
+    let mut keys = SET_KEYS.to_vec();
+    keys.sort_unstable();
+    let suggestion = keys
+        .iter()
+        .map(|v| (edit_distance(key, v), v))
+        .filter(|(distance, _)| *distance <= 2)
+        .min_by_key(|(distance, _)| *distance);
+    let mut message = format!("Unknown key `{key}`");
+    if let Some((_, v)) = suggestion {
+        message.push_str(&format!(", did you mean `{v}`?"));
+    }
+    message.push_str(&format!("\nValid keys: {}", keys.join(", ")));
+    message
+}
+
+/// What the registry knows about a model, see `.info model` and `.model info`
+fn model_items(model: &Model) -> Vec<(&'static str, String)> {
+    vec![
+        ("model", model.id()),
+        ("capabilities", model.capabilities.stringify()),
+        ("max_input_tokens", format_option(&model.max_input_tokens)),
+        ("max_output_tokens", format_option(&model.max_output_tokens)),
+        ("input_price", format_option(&model.input_price)),
+        ("output_price", format_option(&model.output_price)),
+        (
+            "default_temperature",
+            format_option(&model.default_temperature),
+        ),
+        ("default_top_p", format_option(&model.default_top_p)),
+    ]
+}
+
+/// Explain an unknown model, suggesting the closest model ids if any are close enough
+fn unknown_model(models: &[Model], value: &str) -> String {
+    let mut suggestions: Vec<(usize, String)> = models
+        .iter()
+        .map(|v| {
+            let distance = edit_distance(value, &v.id()).min(edit_distance(value, &v.name));
+            (distance, v.id())
+        })
+        .filter(|(distance, _)| *distance <= 3)
+        .collect();
+    suggestions.sort();
+    let mut message = format!("Invalid model '{value}'");
+    if !suggestions.is_empty() {
+        let ids: Vec<String> = suggestions
+            .into_iter()
+            .take(3)
+            .map(|(_, id)| format!("  {id}"))
+            .collect();
+        message.push_str(&format!(", did you mean:\n{}", ids.join("\n")));
+    }
+    message
+}
+
+/// Ask for a terse answer, in the leading system message since some providers accept no other
+fn add_compact_prompt(messages: &mut Vec<Message>) {
+    match messages.first_mut() {
+        Some(Message {
+            role: MessageRole::System,
+            content: MessageContent::Text(text),
+        }) => {
+            text.push_str("\n\n");
+            text.push_str(COMPACT_PROMPT);
+        }
+        _ => messages.insert(
+            0,
+            Message {
+                role: MessageRole::System,
+                content: MessageContent::Text(COMPACT_PROMPT.into()),
+            },
+        ),
+    }
+}
+
+/// Merge `overlay` into `base` key by key, any value other than a mapping replaces the base one
+fn merge_yaml(base: &mut serde_yaml::Value, overlay: serde_yaml::Value) {
+    match (base, overlay) {
+        (_, serde_yaml::Value::Null) => {}
+        (serde_yaml::Value::Mapping(base), serde_yaml::Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(base_value) if value.is_mapping() => merge_yaml(base_value, value),
+                    _ => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{bytes} B"),
+        1024..=1048575 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MB", bytes as f64 / 1048576.0),
+    }
+}
+
+fn format_staged_files(files: &[String]) -> String {
+    if files.is_empty() {
+        "-".to_string()
+    } else {
+        files.join(" ")
+    }
+}
+
 fn complete_bool(value: bool) -> Vec<String> {
     vec![(!value).to_string()]
 }
@@ -1180,3 +2745,9 @@ fn complete_option_bool(value: Option<bool>) -> Vec<String> {
         None => vec!["true".to_string(), "false".to_string()],
     }
 }
+
+/// The builtin dark theme, for the tests that render with highlighting
+#[cfg(test)]
+pub(crate) fn dark_theme() -> syntect::highlighting::Theme {
+    bincode::deserialize_from(DARK_THEME).unwrap()
+}
@@ -17,9 +17,11 @@ pub const INPUT_PLACEHOLDER: &str = "__INPUT__";
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Role {
     pub name: String,
+    pub description: Option<String>,
     pub prompt: String,
     pub temperature: Option<f64>,
     pub top_p: Option<f64>,
+    pub cache_prompt: Option<bool>,
 }
 
 impl Role {
@@ -27,8 +29,18 @@ impl Role {
         Self {
             name: TEMP_ROLE.into(),
             prompt: prompt.into(),
+            description: None,
             temperature: None,
             top_p: None,
+            cache_prompt: None,
+        }
+    }
+
+    /// The description, or the first line of the prompt when there is none
+    pub fn summary(&self) -> &str {
+        match &self.description {
+            Some(description) => description.trim(),
+            None => self.prompt.trim().lines().next().unwrap_or_default(),
         }
     }
 
@@ -65,8 +77,10 @@ Ensure the output is a valid {shell} command. {combine}
 If there is a lack of details, provide most logical solution.
 Output plain text only, without any markdown formatting."#
             ),
+            description: None,
             temperature: None,
             top_p: None,
+            cache_prompt: None,
         }
     }
 
@@ -78,8 +92,10 @@ Describe each argument and option of the command.
 Provide short responses in about 80 words.
 APPLY MARKDOWN formatting when possible."#
                 .into(),
+            description: None,
             temperature: None,
             top_p: None,
+            cache_prompt: None,
         }
     }
 
@@ -89,8 +105,10 @@ APPLY MARKDOWN formatting when possible."#
             prompt: r#"Provide only code, without comments or explanations.
 If there is a lack of details, provide most logical solution, without requesting further clarification."#
                 .into(),
+            description: None,
             temperature: None,
             top_p: None,
+            cache_prompt: None,
         }
     }
 
@@ -112,6 +130,10 @@ If there is a lack of details, provide most logical solution, without requesting
         self.top_p = value;
     }
 
+    pub fn set_cache_prompt(&mut self, value: Option<bool>) {
+        self.cache_prompt = value;
+    }
+
     pub fn complete_prompt_args(&mut self, name: &str) {
         self.name = name.to_string();
         self.prompt = complete_prompt_args(&self.prompt, &self.name);
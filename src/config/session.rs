@@ -1,8 +1,9 @@
 use super::input::resolve_data_url;
-use super::{Config, Input, Model};
+use super::{format_info, format_size, Config, Input, Model};
 
-use crate::client::{Message, MessageContent, MessageRole};
+use crate::client::{Message, MessageContent, MessageRole, SessionThread, TokenUsage};
 use crate::render::MarkdownRender;
+use crate::utils::now;
 
 use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
@@ -24,9 +25,22 @@ pub struct Session {
     messages: Vec<Message>,
     #[serde(default)]
     data_urls: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<Attachment>,
     #[serde(default)]
     compressed_messages: Vec<Message>,
+    #[serde(default)]
+    compressions: Vec<Compression>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    notes: Vec<Note>,
     compress_threshold: Option<usize>,
+    #[serde(default)]
+    autocompact: Option<AutocompactPolicy>,
+    #[serde(default)]
+    cache_prompt: Option<bool>,
+    /// The last reply in the server-side thread of the provider, valid while the local history matches it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thread_id: Option<String>,
     #[serde(skip)]
     pub name: String,
     #[serde(skip)]
@@ -37,24 +51,92 @@ pub struct Session {
     pub compressing: bool,
     #[serde(skip)]
     pub model: Model,
+    #[serde(skip)]
+    usage: Option<UsageStats>,
+    /// The `default_role` applied when the session started
+    #[serde(skip)]
+    pub default_role: Option<String>,
+}
+
+/// Session-specific compression policy, it takes precedence over `compress_threshold`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AutocompactPolicy {
+    /// Compress when the session tokens exceed this, values below 1000 disable it
+    pub threshold: usize,
+    /// Number of the latest user/assistant rounds kept uncompressed, `compress_keep_last` if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_last: Option<usize>,
+}
+
+impl AutocompactPolicy {
+    pub fn stringify(&self) -> String {
+        match self.keep_last {
+            Some(keep_last) => format!("threshold {}, keep last {keep_last}", self.threshold),
+            None => format!("threshold {}", self.threshold),
+        }
+    }
 }
 
+/// Token usage reported by the API and the local estimate for the same replies
+#[derive(Debug, Clone, Copy, Default)]
+struct UsageStats {
+    replies: usize,
+    reported: TokenUsage,
+    estimated: TokenUsage,
+}
+
+/// A compression of the session, recorded to explain what the summary replaced
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Compression {
+    time: String,
+    /// Number of messages replaced by the summary
+    messages: usize,
+    summary: String,
+}
+
+/// An annotation kept with the session for the user, it is never sent to the model
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Note {
+    time: String,
+    text: String,
+}
+
+/// Metadata of a file or image attached to a user turn, the content lives in the messages
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+struct Attachment {
+    /// The user turn it was attached to, counting from 1
+    turn: usize,
+    name: String,
+    size: Option<u64>,
+}
+
+/// Discrepancies above this percentage are flagged in `.info session`
+const USAGE_DISCREPANCY_PERCENT: f64 = 10.0;
+
 impl Session {
     pub fn new(config: &Config, name: &str) -> Self {
         Self {
             model_id: config.model.id(),
-            temperature: config.temperature,
-            top_p: config.top_p,
+            temperature: None,
+            top_p: None,
             save_session: config.save_session,
             messages: vec![],
             compressed_messages: vec![],
+            compressions: vec![],
+            notes: vec![],
             compress_threshold: None,
+            autocompact: None,
+            cache_prompt: None,
+            thread_id: None,
             data_urls: Default::default(),
+            attachments: vec![],
             name: name.to_string(),
             path: None,
             dirty: false,
             compressing: false,
             model: config.model.clone(),
+            usage: None,
+            default_role: None,
         }
     }
 
@@ -90,10 +172,32 @@ impl Session {
         self.save_session
     }
 
+    pub fn cache_prompt(&self) -> Option<bool> {
+        self.cache_prompt
+    }
+
+    pub fn thread(&self) -> SessionThread {
+        match &self.thread_id {
+            Some(id) => SessionThread::Continue(id.clone()),
+            None => SessionThread::Start,
+        }
+    }
+
+    /// Set after the local history got the reply the id belongs to, anything else changing the history clears it
+    pub fn set_thread_id(&mut self, value: Option<String>) {
+        if self.thread_id != value {
+            self.thread_id = value;
+            self.dirty = true;
+        }
+    }
+
     pub fn need_compress(&self, current_compress_threshold: usize) -> bool {
-        let threshold = self
-            .compress_threshold
-            .unwrap_or(current_compress_threshold);
+        let threshold = match self.autocompact {
+            Some(policy) => policy.threshold,
+            None => self
+                .compress_threshold
+                .unwrap_or(current_compress_threshold),
+        };
         threshold >= 1000 && self.tokens() > threshold
     }
 
@@ -105,6 +209,15 @@ impl Session {
         self.messages.iter().filter(|v| v.role.is_user()).count()
     }
 
+    /// Number of user turns, the compressed ones included
+    fn user_turns(&self) -> usize {
+        self.compressed_messages
+            .iter()
+            .chain(self.messages.iter())
+            .filter(|v| v.role.is_user())
+            .count()
+    }
+
     pub fn export(&self) -> Result<String> {
         if self.path.is_none() {
             bail!("Not found session '{}'", self.name)
@@ -130,6 +243,9 @@ impl Session {
         if percent != 0.0 {
             data["total/max"] = format!("{}%", percent).into();
         }
+        if !self.notes.is_empty() {
+            data["notes"] = json!(self.notes);
+        }
         data["messages"] = json!(self.messages);
 
         let output = serde_yaml::to_string(&data)
@@ -137,7 +253,43 @@ impl Session {
         Ok(output)
     }
 
-    pub fn info(&self, render: &mut MarkdownRender) -> Result<String> {
+    /// The conversation as a single code fence with a `[role]` line before each message, for pasting into another chat
+    pub fn transcript(&self) -> Result<String> {
+        if self.messages.is_empty() {
+            bail!("No messages in the session '{}'", self.name)
+        }
+        let messages: Vec<(MessageRole, String)> = self
+            .messages
+            .iter()
+            .map(|v| (v.role, v.content.to_text()))
+            .collect();
+        // The fence must be longer than any backtick run inside, or a code block in a message would close it
+        let longest_run = messages
+            .iter()
+            .flat_map(|(_, text)| text.split(|c| c != '`'))
+            .map(|v| v.len())
+            .max()
+            .unwrap_or_default();
+        let fence = "`".repeat(longest_run.max(2) + 1);
+        let body = messages
+            .iter()
+            .map(|(role, text)| {
+                let role = match role {
+                    MessageRole::System => "system",
+                    MessageRole::User => "user",
+                    MessageRole::Assistant => "assistant",
+                };
+                format!("[{role}]\n{}", text.trim_end())
+            })
+            .collect::<Vec<String>>()
+            .join("\n\n");
+        Ok(format!(
+            "The following is a chat transcript. Each message starts with a line naming its role: [system], [user] or [assistant].\n\n{fence}\n{body}\n{fence}\n"
+        ))
+    }
+
+    /// With `verbose`, list the attachments of each user turn
+    pub fn info(&self, render: &mut MarkdownRender, verbose: bool) -> Result<String> {
         let mut items = vec![];
 
         if let Some(path) = &self.path {
@@ -146,6 +298,10 @@ impl Session {
 
         items.push(("model", self.model.id()));
 
+        if let Some(role) = &self.default_role {
+            items.push(("role", format!("{role} (default_role)")));
+        }
+
         if let Some(temperature) = self.temperature() {
             items.push(("temperature", temperature.to_string()));
         }
@@ -161,42 +317,146 @@ impl Session {
             items.push(("compress_threshold", compress_threshold.to_string()));
         }
 
+        if let Some(autocompact) = &self.autocompact {
+            items.push(("autocompact", autocompact.stringify()));
+        }
+
+        if !self.compressions.is_empty() {
+            items.push(("compressions", self.compressions.len().to_string()));
+        }
+
+        if let Some(cache_prompt) = self.cache_prompt {
+            items.push(("cache_prompt", cache_prompt.to_string()));
+        }
+
+        if let Some(thread_id) = &self.thread_id {
+            items.push(("thread_id", thread_id.clone()));
+        }
+
         if let Some(max_input_tokens) = self.model.max_input_tokens {
             items.push(("max_input_tokens", max_input_tokens.to_string()));
         }
 
-        let mut lines: Vec<String> = items
-            .iter()
-            .map(|(name, value)| format!("{name:<20}{value}"))
-            .collect();
+        if let Some(usage) = &self.usage {
+            let TokenUsage {
+                input_tokens,
+                output_tokens,
+                cached_tokens,
+            } = usage.reported;
+            let mut value = format!("{input_tokens} input, {output_tokens} output");
+            if cached_tokens > 0 {
+                value.push_str(&format!(", {cached_tokens} cached"));
+            }
+            value.push_str(&format!(" ({} replies)", usage.replies));
+            items.push(("api_usage", value));
+            let TokenUsage {
+                input_tokens: estimated_input,
+                output_tokens: estimated_output,
+                ..
+            } = usage.estimated;
+            let mut value = format!("{estimated_input} input, {estimated_output} output");
+            let diff = [
+                (input_tokens, estimated_input),
+                (output_tokens, estimated_output),
+            ]
+            .into_iter()
+            .filter(|(reported, _)| *reported > 0)
+            .map(|(reported, estimated)| {
+                (estimated as f64 - reported as f64).abs() * 100.0 / reported as f64
+            })
+            .fold(0.0, f64::max);
+            if diff > USAGE_DISCREPANCY_PERCENT {
+                value.push_str(&format!(" ⚠️ off by {diff:.0}%"));
+            }
+            items.push(("local_estimate", value));
+        }
+
+        let mut lines = vec![format_info(&items)];
+
+        if verbose && !self.attachments.is_empty() {
+            lines.push("".into());
+            lines.push("attachments:".into());
+            for attachment in &self.attachments {
+                let size = attachment
+                    .size
+                    .map(format_size)
+                    .unwrap_or_else(|| "-".into());
+                lines.push(format!(
+                    "  turn {:<4}{} ({size})",
+                    attachment.turn, attachment.name
+                ));
+            }
+        }
+
+        if !self.notes.is_empty() {
+            lines.push("".into());
+            lines.push("notes:".into());
+            for note in &self.notes {
+                lines.push(format!("  {}  {}", note.time, note.text));
+            }
+        }
+
+        for (i, compression) in self.compressions.iter().enumerate() {
+            lines.push("".into());
+            lines.push(format!(
+                "compression #{} at {}, replaced {} messages with:",
+                i + 1,
+                compression.time,
+                compression.messages
+            ));
+            lines.push(render.render(&compression.summary));
+        }
 
         if !self.is_empty() {
             lines.push("".into());
-            let resolve_url_fn = |url: &str| resolve_data_url(&self.data_urls, url.to_string());
+            lines.extend(self.render_messages(render));
+        }
 
-            for message in &self.messages {
-                match message.role {
-                    MessageRole::System => {
-                        lines.push(render.render(&message.content.render_input(resolve_url_fn)));
-                    }
-                    MessageRole::Assistant => {
-                        if let MessageContent::Text(text) = &message.content {
-                            lines.push(render.render(text));
-                        }
-                        lines.push("".into());
-                    }
-                    MessageRole::User => {
-                        lines.push(format!(
-                            "{}）{}",
-                            self.name,
-                            message.content.render_input(resolve_url_fn)
-                        ));
+        let output = lines.join("\n");
+        Ok(output)
+    }
+
+    /// The conversation as the REPL shows it, with the user inputs after a `name）` prompt
+    fn render_messages(&self, render: &mut MarkdownRender) -> Vec<String> {
+        let mut lines = vec![];
+        let resolve_url_fn = |url: &str| resolve_data_url(&self.data_urls, url.to_string());
+        for (i, message) in self.messages.iter().enumerate() {
+            match message.role {
+                MessageRole::System if i > 0 => {
+                    lines.push(format!(
+                        "{}）.system {}",
+                        self.name,
+                        message.content.render_input(resolve_url_fn)
+                    ));
+                }
+                MessageRole::System => {
+                    lines.push(render.render(&message.content.render_input(resolve_url_fn)));
+                }
+                MessageRole::Assistant => {
+                    if let MessageContent::Text(text) = &message.content {
+                        lines.push(render.render(text));
                     }
+                    lines.push("".into());
+                }
+                MessageRole::User => {
+                    lines.push(format!(
+                        "{}）{}",
+                        self.name,
+                        message.content.render_input(resolve_url_fn)
+                    ));
                 }
             }
         }
+        lines
+    }
 
-        let output = lines.join("\n");
+    /// The rendered conversation with its ANSI escapes, `cat` it to replay the look in a terminal
+    pub fn export_ansi(&self, render: &mut MarkdownRender) -> Result<String> {
+        if self.messages.is_empty() {
+            bail!("No messages in the session '{}'", self.name)
+        }
+        let mut output = self.render_messages(render).join("\n");
+        output.push_str("\x1b[0m\n");
         Ok(output)
     }
 
@@ -233,6 +493,13 @@ impl Session {
         }
     }
 
+    pub fn set_cache_prompt(&mut self, value: Option<bool>) {
+        if self.cache_prompt != value {
+            self.cache_prompt = value;
+            self.dirty = true;
+        }
+    }
+
     pub fn set_compress_threshold(&mut self, value: Option<usize>) {
         if self.compress_threshold != value {
             self.compress_threshold = value;
@@ -240,22 +507,70 @@ impl Session {
         }
     }
 
+    pub fn set_autocompact(&mut self, value: Option<AutocompactPolicy>) {
+        if self.autocompact != value {
+            self.autocompact = value;
+            self.dirty = true;
+        }
+    }
+
     pub fn set_model(&mut self, model: Model) -> Result<()> {
         let model_id = model.id();
         if self.model_id != model_id {
             self.model_id = model_id;
+            self.thread_id = None;
             self.dirty = true;
         }
         self.model = model;
         Ok(())
     }
 
-    pub fn compress(&mut self, prompt: String) {
+    pub fn record_usage(&mut self, reported: TokenUsage, estimated: TokenUsage) {
+        let usage = self.usage.get_or_insert_with(UsageStats::default);
+        usage.replies += 1;
+        usage.reported.input_tokens += reported.input_tokens;
+        usage.reported.output_tokens += reported.output_tokens;
+        usage.reported.cached_tokens += reported.cached_tokens;
+        usage.estimated.input_tokens += estimated.input_tokens;
+        usage.estimated.output_tokens += estimated.output_tokens;
+    }
+
+    /// Index of the first message kept verbatim by a compression, the rest is summarized.
+    /// It keeps the latest `keep_last` rounds, but always leaves at least one round to summarize.
+    fn compress_split(&self, current_keep_last: usize) -> usize {
+        let keep_last = self
+            .autocompact
+            .and_then(|v| v.keep_last)
+            .unwrap_or(current_keep_last);
+        let user_indexes: Vec<usize> = self
+            .messages
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| v.role.is_user())
+            .map(|(i, _)| i)
+            .collect();
+        let keep = keep_last.min(user_indexes.len().saturating_sub(1));
+        if keep == 0 {
+            return self.messages.len();
+        }
+        user_indexes[user_indexes.len() - keep]
+    }
+
+    pub fn compress(&mut self, prompt: String, current_keep_last: usize) {
+        let split = self.compress_split(current_keep_last);
+        let kept_messages = self.messages.split_off(split);
+        self.compressions.push(Compression {
+            time: now(),
+            messages: self.messages.len(),
+            summary: prompt.clone(),
+        });
         self.compressed_messages.append(&mut self.messages);
         self.messages.push(Message {
             role: MessageRole::System,
             content: MessageContent::Text(prompt),
         });
+        self.messages.extend(kept_messages);
+        self.thread_id = None;
         self.dirty = true;
     }
 
@@ -307,6 +622,14 @@ impl Session {
             });
         }
         self.data_urls.extend(input.data_urls());
+        self.thread_id = None;
+        let turn = self.user_turns();
+        self.attachments.extend(
+            input
+                .attachments()
+                .into_iter()
+                .map(|(name, size)| Attachment { turn, name, size }),
+        );
         self.messages.push(Message {
             role: MessageRole::Assistant,
             content: MessageContent::Text(output.to_string()),
@@ -315,10 +638,117 @@ impl Session {
         Ok(())
     }
 
-    pub fn clear_messages(&mut self) {
+    /// Append a system message that steers the following replies
+    pub fn add_system_message(&mut self, text: &str) {
+        self.messages.push(Message {
+            role: MessageRole::System,
+            content: MessageContent::Text(text.to_string()),
+        });
+        self.thread_id = None;
+        self.dirty = true;
+    }
+
+    /// Keep a note with the session, apart from the messages
+    pub fn add_note(&mut self, text: &str) {
+        self.notes.push(Note {
+            time: now(),
+            text: text.to_string(),
+        });
+        self.dirty = true;
+    }
+
+    /// Build the messages to continue the last reply, which must still end the session
+    pub fn build_continue_messages(&self, output: &str) -> Result<Vec<Message>> {
+        match self.messages.last() {
+            Some(Message {
+                role: MessageRole::Assistant,
+                content: MessageContent::Text(text),
+            }) if text == output => Ok(self.messages.clone()),
+            _ => bail!("The last reply is no longer in the session"),
+        }
+    }
+
+    /// Append the continuation to the last reply
+    pub fn extend_last_reply(&mut self, output: &str) {
+        if let Some(Message {
+            role: MessageRole::Assistant,
+            content: MessageContent::Text(text),
+        }) = self.messages.last_mut()
+        {
+            text.push_str(output);
+            self.thread_id = None;
+            self.dirty = true;
+        }
+    }
+
+    /// Merge the messages of another session into this one.
+    ///
+    /// The current system message is kept. Return true if the system message of the other session differs and is dropped.
+    pub fn merge(&mut self, mut other: Session, prepend: bool) -> bool {
+        let mut dropped = false;
+        let has_system = self
+            .messages
+            .first()
+            .map(|v| v.role.is_system())
+            .unwrap_or_default();
+        if other
+            .messages
+            .first()
+            .map(|v| v.role.is_system())
+            .unwrap_or_default()
+        {
+            let system_message = other.messages.remove(0);
+            if !has_system {
+                self.messages.insert(0, system_message);
+            } else if self.messages[0].content.to_text() != system_message.content.to_text() {
+                dropped = true;
+            }
+        }
+        let has_system = !self.messages.is_empty() && self.messages[0].role.is_system();
+        let (own_turns, other_turns) = (self.user_turns(), other.user_turns());
+        if prepend {
+            for attachment in &mut self.attachments {
+                attachment.turn += other_turns;
+            }
+            self.attachments.splice(0..0, other.attachments);
+        } else {
+            for attachment in &mut other.attachments {
+                attachment.turn += own_turns;
+            }
+            self.attachments.extend(other.attachments);
+        }
+        if prepend {
+            let index = if has_system { 1 } else { 0 };
+            self.messages.splice(index..index, other.messages);
+        } else {
+            self.messages.extend(other.messages);
+        }
+        self.data_urls.extend(other.data_urls);
+        self.notes.extend(other.notes);
+        self.thread_id = None;
+        self.dirty = true;
+        dropped
+    }
+
+    /// Clear the messages, keeping the system prompt unless `all` is set
+    pub fn clear_messages(&mut self, all: bool) {
+        let system_message = if all {
+            None
+        } else {
+            // After compression the system prompt is the first compressed message
+            self.compressed_messages
+                .first()
+                .or(self.messages.first())
+                .filter(|v| v.role.is_system())
+                .cloned()
+        };
         self.messages.clear();
         self.compressed_messages.clear();
+        self.compressions.clear();
         self.data_urls.clear();
+        self.attachments.clear();
+        self.thread_id = None;
+        self.messages.extend(system_message);
         self.dirty = true;
     }
 
@@ -327,6 +757,21 @@ impl Session {
         serde_yaml::to_string(&messages).unwrap_or_else(|_| "Unable to echo message".into())
     }
 
+    /// The messages a compression summarizes, followed by the summarize prompt
+    pub fn build_summarize_messages(
+        &self,
+        input: &Input,
+        current_keep_last: usize,
+    ) -> Vec<Message> {
+        let split = self.compress_split(current_keep_last);
+        let mut messages = self.messages[..split].to_vec();
+        messages.push(Message {
+            role: MessageRole::User,
+            content: input.to_message_content(),
+        });
+        messages
+    }
+
     pub fn build_emssages(&self, input: &Input) -> Vec<Message> {
         let mut messages = self.messages.clone();
         let mut need_add_msg = true;
@@ -349,3 +794,238 @@ impl Session {
         messages
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_session(name: &str, messages: &[(MessageRole, &str)]) -> Session {
+        let mut session = Session::new(&Config::default(), name);
+        session.messages = messages
+            .iter()
+            .map(|(role, text)| Message {
+                role: *role,
+                content: MessageContent::Text(text.to_string()),
+            })
+            .collect();
+        session
+    }
+
+    fn texts(session: &Session) -> Vec<String> {
+        session
+            .messages
+            .iter()
+            .map(|v| v.content.to_text())
+            .collect()
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut session = create_session(
+            "a",
+            &[(MessageRole::System, "sys"), (MessageRole::User, "a1")],
+        );
+        let other = create_session(
+            "b",
+            &[(MessageRole::System, "sys2"), (MessageRole::User, "b1")],
+        );
+        assert!(session.merge(other.clone(), false));
+        assert_eq!(texts(&session), ["sys", "a1", "b1"]);
+
+        let mut session = create_session("a", &[(MessageRole::User, "a1")]);
+        assert!(!session.merge(other, true));
+        assert_eq!(texts(&session), ["sys2", "b1", "a1"]);
+        assert!(session.dirty);
+    }
+
+    #[test]
+    fn test_merge_attachments() {
+        let attachment = |turn: usize, name: &str| Attachment {
+            turn,
+            name: name.into(),
+            size: Some(1),
+        };
+        let mut session = create_session("a", &[(MessageRole::User, "a1")]);
+        session.attachments = vec![attachment(1, "a.md")];
+        let mut other =
+            create_session("b", &[(MessageRole::User, "b1"), (MessageRole::User, "b2")]);
+        other.attachments = vec![attachment(2, "b.md")];
+        session.merge(other.clone(), false);
+        assert_eq!(
+            session.attachments,
+            [attachment(1, "a.md"), attachment(3, "b.md")]
+        );
+
+        let mut session = create_session("a", &[(MessageRole::User, "a1")]);
+        session.attachments = vec![attachment(1, "a.md")];
+        session.merge(other, true);
+        assert_eq!(
+            session.attachments,
+            [attachment(2, "b.md"), attachment(3, "a.md")]
+        );
+    }
+
+    #[test]
+    fn test_thread_id() {
+        let mut session = create_session(
+            "a",
+            &[(MessageRole::User, "a1"), (MessageRole::Assistant, "r1")],
+        );
+        assert_eq!(session.thread(), SessionThread::Start);
+        session.set_thread_id(Some("resp_1".into()));
+        assert_eq!(session.thread(), SessionThread::Continue("resp_1".into()));
+        session.extend_last_reply(" more");
+        assert_eq!(session.thread(), SessionThread::Start);
+        session.set_thread_id(Some("resp_2".into()));
+        session.add_system_message("be brief");
+        assert_eq!(session.thread(), SessionThread::Start);
+    }
+
+    #[test]
+    fn test_notes_not_sent() {
+        let mut session = create_session(
+            "a",
+            &[(MessageRole::User, "a1"), (MessageRole::Assistant, "r1")],
+        );
+        session.add_note("why this session exists");
+        session.compress("summary".into(), 0);
+        let input = Input::from_str("a2", Default::default());
+        let messages = serde_json::to_string(&session.build_emssages(&input)).unwrap();
+        assert!(!messages.contains("why this session exists"));
+        let yaml = serde_yaml::to_string(&session).unwrap();
+        let session: Session = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(session.notes[0].text, "why this session exists");
+    }
+
+    #[test]
+    fn test_export_ansi() {
+        let session = create_session(
+            "a",
+            &[
+                (MessageRole::User, "hi"),
+                (MessageRole::Assistant, "# Hello"),
+            ],
+        );
+        let options = crate::render::RenderOptions {
+            theme: Some(crate::config::dark_theme()),
+            ..Default::default()
+        };
+        let mut render = MarkdownRender::init(options).unwrap();
+        let output = session.export_ansi(&mut render).unwrap();
+        assert!(output.starts_with("a）hi\n\x1b["));
+        assert!(output.ends_with("\x1b[0m\n"));
+    }
+
+    #[test]
+    fn test_transcript() {
+        let session = create_session(
+            "a",
+            &[
+                (MessageRole::User, "show a fence"),
+                (MessageRole::Assistant, "````md\n```\n````\n"),
+            ],
+        );
+        let transcript = session.transcript().unwrap();
+        let (header, body) = transcript.split_once("\n\n").unwrap();
+        assert!(header.contains("[assistant]"));
+        assert_eq!(
+            body,
+            "`````\n[user]\nshow a fence\n\n[assistant]\n````md\n```\n````\n`````\n"
+        );
+        assert!(create_session("b", &[]).transcript().is_err());
+    }
+
+    #[test]
+    fn test_compress_keep_last() {
+        let messages = [
+            (MessageRole::User, "u1"),
+            (MessageRole::Assistant, "a1"),
+            (MessageRole::User, "u2"),
+            (MessageRole::Assistant, "a2"),
+        ];
+        let mut session = create_session("a", &messages);
+        session.compress("summary".into(), 0);
+        assert_eq!(texts(&session), ["summary"]);
+
+        let mut session = create_session("a", &messages);
+        let input = Input::from_str("summarize", Default::default());
+        let summarized = session.build_summarize_messages(&input, 1);
+        assert_eq!(summarized.len(), 3);
+        assert_eq!(summarized[1].content.to_text(), "a1");
+        // Always leave a round to summarize
+        assert_eq!(session.build_summarize_messages(&input, 5).len(), 3);
+        session.compress("summary".into(), 1);
+        assert_eq!(texts(&session), ["summary", "u2", "a2"]);
+        assert_eq!(session.compressed_messages.len(), 2);
+        assert_eq!(session.compressions.len(), 1);
+        assert_eq!(session.compressions[0].messages, 2);
+
+        // The session policy takes precedence over `compress_keep_last`
+        let mut session = create_session("a", &messages);
+        session.set_autocompact(Some(AutocompactPolicy {
+            threshold: 1000,
+            keep_last: Some(0),
+        }));
+        session.compress("summary".into(), 1);
+        assert_eq!(texts(&session), ["summary"]);
+
+        // A policy without keep_last falls back to `compress_keep_last`
+        let mut session = create_session("a", &messages);
+        session.set_autocompact(Some(AutocompactPolicy {
+            threshold: 1000,
+            keep_last: None,
+        }));
+        session.compress("summary".into(), 1);
+        assert_eq!(texts(&session), ["summary", "u2", "a2"]);
+    }
+
+    #[test]
+    fn test_continue_last_reply() {
+        let mut session = create_session(
+            "a",
+            &[(MessageRole::User, "u1"), (MessageRole::Assistant, "a1")],
+        );
+        assert!(session.build_continue_messages("a2").is_err());
+        assert_eq!(session.build_continue_messages("a1").unwrap().len(), 2);
+        session.extend_last_reply(" more");
+        assert_eq!(texts(&session), ["u1", "a1 more"]);
+        assert!(session.dirty);
+    }
+
+    #[test]
+    fn test_clear_messages() {
+        let messages = [
+            (MessageRole::System, "sys"),
+            (MessageRole::User, "u1"),
+            (MessageRole::Assistant, "a1"),
+        ];
+        let mut session = create_session("a", &messages);
+        session.clear_messages(false);
+        assert_eq!(texts(&session), ["sys"]);
+
+        let mut session = create_session("a", &messages);
+        session.compress("summary".into(), 0);
+        session.clear_messages(false);
+        assert_eq!(texts(&session), ["sys"]);
+        assert!(session.compressed_messages.is_empty());
+
+        let mut session = create_session("a", &messages);
+        session.clear_messages(true);
+        assert!(session.is_empty());
+    }
+
+    #[test]
+    fn test_sampling_params_saved() {
+        let config = Config {
+            temperature: Some(0.5),
+            ..Default::default()
+        };
+        let mut session = Session::new(&config, "a");
+        assert_eq!(session.temperature(), None);
+        session.set_temperature(Some(1.2));
+        let content = serde_yaml::to_string(&session).unwrap();
+        let session: Session = serde_yaml::from_str(&content).unwrap();
+        assert_eq!(session.temperature(), Some(1.2));
+        assert_eq!(session.top_p(), None);
+    }
+}
@@ -12,9 +12,11 @@ mod utils;
 extern crate log;
 
 use crate::cli::Cli;
-use crate::client::{ensure_model_capabilities, init_client, list_models, send_stream};
+use crate::client::{
+    ensure_model_capabilities, init_client, list_models, send_stream, ClientError,
+};
 use crate::config::{
-    Config, GlobalConfig, Input, WorkingMode, CODE_ROLE, EXPLAIN_ROLE, SHELL_ROLE,
+    Config, GlobalConfig, Input, PipeOutput, WorkingMode, CODE_ROLE, EXPLAIN_ROLE, SHELL_ROLE,
 };
 use crate::render::{render_error, MarkdownRender};
 use crate::repl::Repl;
@@ -23,7 +25,7 @@ use crate::utils::{
     CODE_BLOCK_RE,
 };
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use clap::Parser;
 use inquire::{Select, Text};
 use is_terminal::IsTerminal;
@@ -33,6 +35,14 @@ use std::process;
 use std::sync::Arc;
 use tokio::sync::oneshot;
 
+/// Exit codes, listed in the README
+const EXIT_ERROR: i32 = 1;
+const EXIT_API_ERROR: i32 = 3;
+const EXIT_AUTH_ERROR: i32 = 4;
+const EXIT_RATE_LIMITED: i32 = 5;
+const EXIT_NETWORK_ERROR: i32 = 6;
+const EXIT_ABORTED: i32 = 130;
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
@@ -77,9 +87,15 @@ async fn main() -> Result<()> {
     if cli.light_theme {
         config.write().light_theme = true;
     }
+    if cli.ansi {
+        config.write().pipe_output = PipeOutput::Ansi;
+    }
     if cli.dry_run {
         config.write().dry_run = true;
     }
+    if cli.quiet {
+        config.write().quiet = true;
+    }
     if let Some(name) = &cli.role {
         config.write().set_role(name)?;
     } else if cli.execute {
@@ -112,7 +128,9 @@ async fn main() -> Result<()> {
             bail!("No input");
         }
         let input = create_input(&config, text, file)?;
-        execute(&config, input).await?;
+        if let Err(err) = execute(&config, input).await {
+            exit_with_error(&config, err);
+        }
         return Ok(());
     }
     config.write().apply_prelude()?;
@@ -123,12 +141,41 @@ async fn main() -> Result<()> {
         }
         true => start_interactive(&config).await,
     } {
-        let highlight = stderr().is_terminal() && config.read().highlight;
-        render_error(err, highlight)
+        exit_with_error(&config, err);
     }
     Ok(())
 }
 
+/// Print the error and exit with the code of its category, see `exit_code`
+fn exit_with_error(config: &GlobalConfig, err: anyhow::Error) -> ! {
+    let color = match stderr().is_terminal() {
+        true => config.read().error_color(),
+        false => None,
+    };
+    let code = exit_code(&err);
+    render_error(err, color);
+    process::exit(code)
+}
+
+/// The exit code for an error, telling scripts the API, auth, rate limit and network failures apart
+fn exit_code(err: &anyhow::Error) -> i32 {
+    for cause in err.chain() {
+        if let Some(err) = cause.downcast_ref::<ClientError>() {
+            return match err {
+                ClientError::Api { .. } => EXIT_API_ERROR,
+                ClientError::Auth(_) => EXIT_AUTH_ERROR,
+                ClientError::RateLimited(_) => EXIT_RATE_LIMITED,
+                ClientError::Network(_) => EXIT_NETWORK_ERROR,
+                ClientError::Other(err) => exit_code(err),
+            };
+        }
+        if cause.downcast_ref::<reqwest::Error>().is_some() {
+            return EXIT_NETWORK_ERROR;
+        }
+    }
+    EXIT_ERROR
+}
+
 async fn start_directive(
     config: &GlobalConfig,
     input: Input,
@@ -140,28 +187,40 @@ async fn start_directive(
     config.read().maybe_print_send_tokens(&input);
     let is_terminal_stdout = stdout().is_terminal();
     let extract_code = !is_terminal_stdout && code_mode;
+    let abort = create_abort_signal();
     let output = if no_stream || extract_code {
-        let output = client.send_message(input.clone()).await?;
+        let output = client
+            .send_message(input.clone())
+            .await
+            .with_context(|| "Failed to get answer")?;
         let output = if extract_code && output.trim_start().starts_with("```") {
             extract_block(&output)
         } else {
             output.clone()
         };
-        if is_terminal_stdout {
+        let render_markdown =
+            is_terminal_stdout || (!extract_code && config.read().pipe_output == PipeOutput::Ansi);
+        if render_markdown && !config.read().compact {
             let render_options = config.read().get_render_options()?;
-            let mut markdown_render = MarkdownRender::init(render_options)?;
-            println!("{}", markdown_render.render(&output).trim());
+            let output = MarkdownRender::render_once(&output, render_options)?;
+            println!("{}", output.trim());
+        } else if config.read().quiet {
+            println!("{}", output.trim_end());
         } else {
             println!("{}", output);
         }
         output
     } else {
-        let abort = create_abort_signal();
-        send_stream(&input, client.as_ref(), config, abort).await?
+        send_stream(&input, client.as_ref(), config, abort.clone())
+            .await
+            .with_context(|| "Failed to get answer")?
     };
     // Save the message/session
     config.write().save_message(input, &output)?;
     config.write().end_session()?;
+    if abort.aborted() {
+        process::exit(EXIT_ABORTED);
+    }
     Ok(())
 }
 
@@ -176,10 +235,12 @@ async fn execute(config: &GlobalConfig, mut input: Input) -> Result<()> {
     let client = init_client(config)?;
     config.read().maybe_print_send_tokens(&input);
     let (spinner_tx, spinner_rx) = oneshot::channel();
-    tokio::spawn(run_spinner(" Generating", spinner_rx));
+    if !config.read().quiet {
+        tokio::spawn(run_spinner(" Generating", spinner_rx));
+    }
     let ret = client.send_message(input.clone()).await;
     let _ = spinner_tx.send(());
-    let mut eval_str = ret?;
+    let mut eval_str = ret.with_context(|| "Failed to get answer")?;
     if let Ok(true) = CODE_BLOCK_RE.is_match(&eval_str) {
         eval_str = extract_block(&eval_str);
     }
@@ -221,7 +282,9 @@ async fn execute(config: &GlobalConfig, mut input: Input) -> Result<()> {
                     }
                     let input = Input::from_str(&eval_str, config.read().input_context());
                     let abort = create_abort_signal();
-                    send_stream(&input, client.as_ref(), config, abort).await?;
+                    send_stream(&input, client.as_ref(), config, abort)
+                        .await
+                        .with_context(|| "Failed to get answer")?;
                     explain = true;
                     continue;
                 }
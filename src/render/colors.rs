@@ -0,0 +1,125 @@
+use super::markdown::parse_color;
+
+use anyhow::{bail, Result};
+use crossterm::style::Color as CrosstermColor;
+use nu_ansi_term::Color;
+use serde::Deserialize;
+
+/// Colors of the parts of the UI, read from `colors.yaml`, unset fields keep the light or dark default
+///
+/// A color is a name such as `yellow` or `dark_green`, or `#rrggbb`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ColorTheme {
+    /// REPL commands in the input line
+    pub command: Option<String>,
+    pub error: Option<String>,
+    /// `{color.prompt}` in `left_prompt` and `right_prompt`
+    pub prompt: Option<String>,
+    /// Code blocks without a recognized language
+    pub code: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Colors {
+    pub command: Color,
+    pub error: Color,
+    pub prompt: Color,
+}
+
+impl ColorTheme {
+    pub fn validate(&self) -> Result<()> {
+        let fields = [
+            ("command", &self.command),
+            ("error", &self.error),
+            ("prompt", &self.prompt),
+            ("code", &self.code),
+        ];
+        for (name, value) in fields {
+            if let Some(value) = value {
+                if parse_color(value, true).is_none() {
+                    bail!("Invalid {name} color '{value}'");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn resolve(&self, light_theme: bool, truecolor: bool) -> Colors {
+        let defaults = if light_theme {
+            Colors {
+                command: Color::Fixed(28),
+                error: Color::Fixed(160),
+                prompt: Color::Fixed(28),
+            }
+        } else {
+            Colors {
+                command: Color::Green,
+                error: Color::Red,
+                prompt: Color::Green,
+            }
+        };
+        let parse = |value: &Option<String>, default: Color| {
+            value
+                .as_deref()
+                .and_then(|v| parse_color(v, truecolor))
+                .map(to_ansi_term_color)
+                .unwrap_or(default)
+        };
+        Colors {
+            command: parse(&self.command, defaults.command),
+            error: parse(&self.error, defaults.error),
+            prompt: parse(&self.prompt, defaults.prompt),
+        }
+    }
+}
+
+fn to_ansi_term_color(color: CrosstermColor) -> Color {
+    match color {
+        CrosstermColor::Reset => Color::Default,
+        CrosstermColor::Black => Color::Fixed(0),
+        CrosstermColor::DarkRed => Color::Fixed(1),
+        CrosstermColor::DarkGreen => Color::Fixed(2),
+        CrosstermColor::DarkYellow => Color::Fixed(3),
+        CrosstermColor::DarkBlue => Color::Fixed(4),
+        CrosstermColor::DarkMagenta => Color::Fixed(5),
+        CrosstermColor::DarkCyan => Color::Fixed(6),
+        CrosstermColor::Grey => Color::Fixed(7),
+        CrosstermColor::DarkGrey => Color::Fixed(8),
+        CrosstermColor::Red => Color::Fixed(9),
+        CrosstermColor::Green => Color::Fixed(10),
+        CrosstermColor::Yellow => Color::Fixed(11),
+        CrosstermColor::Blue => Color::Fixed(12),
+        CrosstermColor::Magenta => Color::Fixed(13),
+        CrosstermColor::Cyan => Color::Fixed(14),
+        CrosstermColor::White => Color::Fixed(15),
+        CrosstermColor::Rgb { r, g, b } => Color::Rgb(r, g, b),
+        CrosstermColor::AnsiValue(v) => Color::Fixed(v),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let theme = ColorTheme {
+            command: Some("dark_blue".into()),
+            error: Some("#ff0000".into()),
+            ..Default::default()
+        };
+        assert!(theme.validate().is_ok());
+        let colors = theme.resolve(false, true);
+        assert_eq!(colors.command, Color::Fixed(4));
+        assert_eq!(colors.error, Color::Rgb(255, 0, 0));
+        assert_eq!(colors.prompt, Color::Green);
+        assert_eq!(theme.resolve(true, true).prompt, Color::Fixed(28));
+
+        let theme = ColorTheme {
+            code: Some("nope".into()),
+            ..Default::default()
+        };
+        assert!(theme.validate().is_err());
+    }
+}
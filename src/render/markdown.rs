@@ -2,15 +2,21 @@ use ansi_colours::AsRGB;
 use anyhow::{anyhow, Context, Result};
 use crossterm::style::{Color, Stylize};
 use crossterm::terminal;
+use fancy_regex::Regex;
 use lazy_static::lazy_static;
+use serde::Deserialize;
 use std::collections::HashMap;
+use std::path::PathBuf;
 use syntect::highlighting::{Color as SyntectColor, FontStyle, Style, Theme};
 use syntect::parsing::SyntaxSet;
 use syntect::{easy::HighlightLines, parsing::SyntaxReference};
+use unicode_width::UnicodeWidthStr;
 
 /// Comes from https://github.com/sharkdp/bat/raw/5e77ca37e89c873e4490b42ff556370dc5c6ba4f/assets/syntaxes.bin
 const SYNTAXES: &[u8] = include_bytes!("../../assets/syntaxes.bin");
 
+const DIFF_SYNTAX: &str = "Diff";
+
 lazy_static! {
     static ref LANG_MAPS: HashMap<String, String> = {
         let mut m = HashMap::new();
@@ -18,16 +24,63 @@ lazy_static! {
         m.insert("php".into(), "PHP Source".into());
         m
     };
+    /// URLs, or paths with at least one `/`, which are linked only if they exist
+    static ref LINK_RE: Regex =
+        Regex::new(r#"https?://[^\s<>"'`]+|(?:~|\.{1,2})?/?[\w.-]+(?:/[\w.-]+)+/?"#).unwrap();
+    /// An inline citation such as `[1]`, but not a link text like `[1](...)` or a definition like `[1]:`
+    static ref CITATION_RE: Regex = Regex::new(r"\[(\d+)\](?![(:])").unwrap();
+    /// A source entry such as `[1]: https://...`, `[1] Title - https://...` or `[1] [Title](https://...)`
+    static ref CITATION_SOURCE_RE: Regex =
+        Regex::new(r"^\[(\d+)\]:?\s+(.*?)\s*[(<]?(https?://[^\s<>()]+)[)>]?\s*$").unwrap();
+    /// Unambiguous line prefixes used to guess the language of untagged code blocks
+    static ref LANG_HINTS: Vec<(&'static str, &'static str)> = vec![
+        ("fn ", "rust"),
+        ("pub fn ", "rust"),
+        ("use std::", "rust"),
+        ("impl ", "rust"),
+        ("#[derive(", "rust"),
+        ("def ", "python"),
+        ("from __future__ ", "python"),
+        ("#include ", "cpp"),
+        ("package main", "go"),
+        ("func ", "go"),
+        ("public class ", "java"),
+        ("<?php", "php"),
+        ("<!DOCTYPE html", "html"),
+        ("<html", "html"),
+        ("SELECT ", "sql"),
+        ("CREATE TABLE ", "sql"),
+        ("function ", "js"),
+        ("console.log(", "js"),
+        ("#!/bin/bash", "bash"),
+        ("#!/bin/sh", "bash"),
+        ("diff --git ", "diff"),
+        ("--- a/", "diff"),
+        ("@@ -", "diff"),
+    ];
 }
 
+/// Renders markdown into text with ANSI styles for the terminal
+///
+/// Replies are streamed through `render_stream`, `render_line` and `finish`, while `render_once` renders any text in one go.
+/// With `RenderOptions::default()` the output carries no escape codes at all.
 pub struct MarkdownRender {
     options: RenderOptions,
     syntax_set: SyntaxSet,
     code_color: Option<Color>,
+    inline_code_bg: Option<Color>,
+    heading_styles: Vec<HeadingRender>,
     md_syntax: SyntaxReference,
     code_syntax: Option<SyntaxReference>,
+    code_fence: Option<String>,
+    /// The URLs of the citation sources seen so far, by number
+    citation_sources: HashMap<String, String>,
     prev_line_type: LineType,
+    /// Rows of a streamed table held back until the table ends, so it is laid out as a whole
+    table_lines: Vec<String>,
     wrap_width: Option<u16>,
+    /// Wrap prose lines wider than this when `wrap_width` is unset, see `RenderOptions::soft_wrap`
+    soft_wrap_width: Option<u16>,
 }
 
 impl MarkdownRender {
@@ -35,10 +88,27 @@ impl MarkdownRender {
         let syntax_set: SyntaxSet = bincode::deserialize_from(SYNTAXES)
             .with_context(|| "MarkdownRender: invalid syntaxes binary")?;
 
-        let code_color = options
-            .theme
-            .as_ref()
-            .map(|theme| get_code_color(theme, options.truecolor));
+        let code_color = options.theme.as_ref().map(|theme| {
+            options
+                .code_color
+                .as_deref()
+                .and_then(|v| parse_color(v, options.truecolor))
+                .unwrap_or_else(|| get_code_color(theme, options.truecolor))
+        });
+        let inline_code_bg = options.theme.as_ref().and_then(|theme| {
+            theme
+                .settings
+                .line_highlight
+                .or(theme.settings.gutter)
+                .map(|c| convert_color(c, options.truecolor))
+        });
+        let heading_styles = (0..6)
+            .map(|i| {
+                let style = default_heading_style(i + 1)
+                    .merge(options.heading_styles.get(i).cloned().unwrap_or_default());
+                HeadingRender::new(style, options.truecolor)
+            })
+            .collect::<Result<Vec<_>>>()?;
         let md_syntax = syntax_set.find_syntax_by_extension("md").unwrap().clone();
         let line_type = LineType::Normal;
         let wrap_width = match options.wrap.as_deref() {
@@ -57,97 +127,235 @@ impl MarkdownRender {
                 Err(_) => None,
             },
         };
+        let soft_wrap_width = match (options.soft_wrap, wrap_width) {
+            (true, None) => terminal::size().ok().map(|(columns, _)| columns),
+            _ => None,
+        };
         Ok(Self {
             syntax_set,
             code_color,
+            inline_code_bg,
+            heading_styles,
             md_syntax,
             code_syntax: None,
+            code_fence: None,
+            citation_sources: HashMap::new(),
             prev_line_type: line_type,
+            table_lines: vec![],
             wrap_width,
+            soft_wrap_width,
             options,
         })
     }
 
+    /// Render the text in one go, a shortcut for `init` followed by `render`
+    pub fn render_once(text: &str, options: RenderOptions) -> Result<String> {
+        Ok(Self::init(options)?.render(text))
+    }
+
+    /// Render complete lines, keeping track of open code blocks for the lines that follow
     pub fn render(&mut self, text: &str) -> String {
-        text.split('\n')
-            .map(|line| self.render_line_mut(line))
-            .collect::<Vec<String>>()
-            .join("\n")
+        let mut output = self.render_lines(text);
+        output.extend(self.flush_table());
+        output.join("\n")
+    }
+
+    /// Render complete lines of a streamed reply, `None` while all of them are table rows held back
+    pub fn render_stream(&mut self, text: &str) -> Option<String> {
+        let output = self.render_lines(text);
+        (!output.is_empty()).then(|| output.join("\n"))
+    }
+
+    /// The table rows held back by `render_stream`, shown as they are until the table ends
+    pub fn held_table(&self) -> Option<String> {
+        (!self.table_lines.is_empty()).then(|| {
+            self.table_lines
+                .iter()
+                .map(|line| self.render_text_line(line))
+                .collect::<Vec<String>>()
+                .join("\n")
+        })
+    }
+
+    fn render_lines(&mut self, text: &str) -> Vec<String> {
+        let lines: Vec<&str> = text.split('\n').collect();
+        if self.options.citations {
+            // Sources usually follow the text citing them, so collect them first
+            for line in &lines {
+                if let Some((number, _, url)) = parse_citation_source(line) {
+                    self.citation_sources.insert(number, url);
+                }
+            }
+        }
+        let mut output = vec![];
+        for line in lines {
+            let in_text = matches!(self.prev_line_type, LineType::Normal | LineType::CodeEnd);
+            if in_text && line.trim_start().starts_with('|') {
+                self.table_lines.push(line.to_string());
+                // Without a separator below the first row it is no table, but the second row may start one
+                if self.table_lines.len() == 2 && !is_table_separator(&self.table_lines[1]) {
+                    let first = self.table_lines.remove(0);
+                    output.push(self.render_line_mut(&first));
+                }
+                continue;
+            }
+            output.extend(self.flush_table());
+            output.push(self.render_line_mut(line));
+        }
+        output
     }
 
+    /// Lay out the held table rows, or render them line by line if they don't make a table
+    fn flush_table(&mut self) -> Vec<String> {
+        let lines = std::mem::take(&mut self.table_lines);
+        let lines: Vec<&str> = lines.iter().map(|v| v.as_str()).collect();
+        if !lines.is_empty() && detect_table(&lines) == lines.len() {
+            self.prev_line_type = LineType::Normal;
+            vec![self.render_table(&lines)]
+        } else {
+            lines
+                .into_iter()
+                .map(|line| self.render_line_mut(line))
+                .collect()
+        }
+    }
+
+    /// Render the last line of a reply, which has no line feed, and close a code block the reply left open.
+    /// A table still held back is laid out first.
+    pub fn finish(&mut self, tail: &str) -> String {
+        let output = if tail.is_empty() {
+            match self.flush_table() {
+                lines if lines.is_empty() => String::new(),
+                lines => format!("{}\n", lines.join("\n")),
+            }
+        } else {
+            self.render(tail)
+        };
+        if matches!(
+            self.prev_line_type,
+            LineType::CodeBegin | LineType::CodeInner
+        ) {
+            self.prev_line_type = LineType::CodeEnd;
+            self.code_syntax = None;
+            self.code_fence = None;
+        }
+        output
+    }
+
+    /// Render a line that may still grow, without changing the state
     pub fn render_line(&self, line: &str) -> String {
-        let (_, code_syntax, is_code) = self.check_line(line);
-        if is_code {
+        let (_, code_syntax, _, is_code) = self.check_line(line);
+        let output = if is_code {
             self.highlight_code_line(line, &code_syntax)
         } else {
-            self.highlight_line(line, &self.md_syntax, false)
-        }
+            self.render_text_line(line)
+        };
+        self.link_line(output)
     }
 
     fn render_line_mut(&mut self, line: &str) -> String {
-        let (line_type, code_syntax, is_code) = self.check_line(line);
+        let (line_type, code_syntax, code_fence, is_code) = self.check_line(line);
         let output = if is_code {
             self.highlight_code_line(line, &code_syntax)
         } else {
-            self.highlight_line(line, &self.md_syntax, false)
+            self.render_text_line(line)
         };
         self.prev_line_type = line_type;
         self.code_syntax = code_syntax;
-        output
+        self.code_fence = code_fence;
+        self.link_line(output)
+    }
+
+    /// Highlight a line outside of code blocks, with `citations` the sources are compacted and citations linked
+    fn render_text_line(&self, line: &str) -> String {
+        if !self.options.citations {
+            return self.highlight_line(line, &self.md_syntax, false);
+        }
+        let output = match parse_citation_source(line) {
+            Some((number, title, url)) => self.highlight_line(
+                &compact_citation_source(
+                    &number,
+                    &title,
+                    &url,
+                    self.options.truncation_indicator.as_deref().unwrap_or("…"),
+                ),
+                &self.md_syntax,
+                false,
+            ),
+            None => self.highlight_line(line, &self.md_syntax, false),
+        };
+        link_visible(&output, &CITATION_RE, |text| {
+            let number = text.trim_matches(|c| c == '[' || c == ']');
+            let url = self.citation_sources.get(number)?;
+            Some((text.len(), url.clone()))
+        })
     }
 
-    fn check_line(&self, line: &str) -> (LineType, Option<SyntaxReference>, bool) {
+    fn link_line(&self, line: String) -> String {
+        if self.options.hyperlinks {
+            add_hyperlinks(&line)
+        } else {
+            line
+        }
+    }
+
+    /// A code block only ends at a fence of the same kind and at least as long as the one that opened it
+    fn check_line(&self, line: &str) -> (LineType, Option<SyntaxReference>, Option<String>, bool) {
         let mut line_type = self.prev_line_type;
         let mut code_syntax = self.code_syntax.clone();
+        let mut code_fence = self.code_fence.clone();
         let mut is_code = false;
-        if let Some(lang) = detect_code_block(line) {
-            match line_type {
-                LineType::Normal | LineType::CodeEnd => {
+        match line_type {
+            LineType::Normal | LineType::CodeEnd => {
+                if let Some((fence, lang)) = detect_code_block(line) {
                     line_type = LineType::CodeBegin;
                     code_syntax = if lang.is_empty() {
                         None
                     } else {
                         self.find_syntax(&lang).cloned()
                     };
+                    code_fence = Some(fence);
+                } else {
+                    line_type = LineType::Normal;
                 }
-                LineType::CodeBegin | LineType::CodeInner => {
+            }
+            LineType::CodeBegin | LineType::CodeInner => {
+                if is_closing_fence(line, code_fence.as_deref().unwrap_or("```")) {
                     line_type = LineType::CodeEnd;
                     code_syntax = None;
-                }
-            }
-        } else {
-            match line_type {
-                LineType::Normal => {}
-                LineType::CodeEnd => {
-                    line_type = LineType::Normal;
-                }
-                LineType::CodeBegin => {
-                    if code_syntax.is_none() {
-                        if let Some(syntax) = self.syntax_set.find_syntax_by_first_line(line) {
-                            code_syntax = Some(syntax.clone());
-                        }
+                    code_fence = None;
+                } else {
+                    if line_type == LineType::CodeBegin && code_syntax.is_none() {
+                        code_syntax = self.guess_syntax(line).cloned();
                     }
                     line_type = LineType::CodeInner;
                     is_code = true;
                 }
-                LineType::CodeInner => {
-                    is_code = true;
-                }
             }
         }
-        (line_type, code_syntax, is_code)
+        (line_type, code_syntax, code_fence, is_code)
     }
 
     fn highlight_line(&self, line: &str, syntax: &SyntaxReference, is_code: bool) -> String {
         let ws: String = line.chars().take_while(|c| c.is_whitespace()).collect();
         let trimed_line: &str = &line[ws.len()..];
         let mut line_highlighted = None;
+        if let (false, Some(theme)) = (is_code, &self.options.theme) {
+            if let Some((level, text)) = parse_heading(trimed_line) {
+                let line = self.render_heading(trimed_line, level, text, syntax, theme);
+                return self.wrap_line(format!("{ws}{line}"), false);
+            }
+        }
         if let Some(theme) = &self.options.theme {
             let mut highlighter = HighlightLines::new(syntax, theme);
             if let Ok(ranges) = highlighter.highlight_line(trimed_line, &self.syntax_set) {
+                let code_spans = match (is_code, self.inline_code_bg) {
+                    (false, Some(bg)) => Some((bg, find_inline_code_spans(trimed_line))),
+                    _ => None,
+                };
                 line_highlighted = Some(format!(
                     "{ws}{}",
-                    as_terminal_escaped(&ranges, self.options.truecolor)
+                    as_terminal_escaped(&ranges, self.options.truecolor, code_spans)
                 ))
             }
         }
@@ -155,8 +363,47 @@ impl MarkdownRender {
         self.wrap_line(line, is_code)
     }
 
+    /// Style a heading line, the color falls back to the one the theme gives headings
+    fn render_heading(
+        &self,
+        line: &str,
+        level: usize,
+        text: &str,
+        syntax: &SyntaxReference,
+        theme: &Theme,
+    ) -> String {
+        let style = &self.heading_styles[level - 1];
+        let color = style.color.or_else(|| {
+            let mut highlighter = HighlightLines::new(syntax, theme);
+            let ranges = highlighter.highlight_line(line, &self.syntax_set).ok()?;
+            let (style, _) = ranges.first()?;
+            Some(convert_color(
+                blend_fg_color(style.foreground, style.background),
+                self.options.truecolor,
+            ))
+        });
+        let text = match &style.prefix {
+            Some(prefix) => format!("{prefix}{text}"),
+            None => line.to_string(),
+        };
+        let mut output = text.stylize();
+        if let Some(color) = color {
+            output = output.with(color);
+        }
+        if style.bold {
+            output = output.bold();
+        }
+        if style.underline {
+            output = output.underlined();
+        }
+        output.to_string()
+    }
+
     fn highlight_code_line(&self, line: &str, code_syntax: &Option<SyntaxReference>) -> String {
         if let Some(syntax) = code_syntax {
+            if syntax.name == DIFF_SYNTAX && self.options.theme.is_some() {
+                return self.wrap_line(highlight_diff_line(line), true);
+            }
             self.highlight_line(line, syntax, true)
         } else {
             let line = match self.code_color {
@@ -167,17 +414,129 @@ impl MarkdownRender {
         }
     }
 
+    /// Lay out a markdown table with aligned columns, wrapping cells to fit the terminal.
+    ///
+    /// Falls back to the original lines if the columns cannot fit.
+    fn render_table(&self, lines: &[&str]) -> String {
+        let mut rows: Vec<Vec<String>> = lines.iter().map(|line| split_table_row(line)).collect();
+        let aligns: Vec<Alignment> = rows.remove(1).iter().map(|v| parse_alignment(v)).collect();
+        let columns = rows.iter().map(|v| v.len()).max().unwrap_or_default();
+        for row in rows.iter_mut() {
+            row.resize(columns, String::new());
+        }
+        let mut widths: Vec<usize> = (0..columns)
+            .map(|i| {
+                rows.iter()
+                    .map(|row| row[i].width())
+                    .max()
+                    .unwrap_or_default()
+            })
+            .map(|v| v.max(MIN_TABLE_COLUMN_WIDTH))
+            .collect();
+        let max_width = self
+            .wrap_width
+            .or_else(|| terminal::size().ok().map(|(columns, _)| columns))
+            .map(|v| v as usize);
+        if let Some(max_width) = max_width {
+            let available = max_width.saturating_sub(3 * columns + 1);
+            if available < MIN_TABLE_COLUMN_WIDTH * columns {
+                return lines
+                    .iter()
+                    .map(|line| self.highlight_line(line, &self.md_syntax, false))
+                    .collect::<Vec<String>>()
+                    .join("\n");
+            }
+            while widths.iter().sum::<usize>() > available {
+                if let Some(width) = widths.iter_mut().max() {
+                    *width -= 1;
+                }
+            }
+        }
+        let separator: Vec<String> = widths
+            .iter()
+            .zip(aligns.iter().chain(std::iter::repeat(&Alignment::Left)))
+            .map(|(width, align)| {
+                let dashes = "-".repeat(*width);
+                match align {
+                    Alignment::Left => format!("-{dashes}-"),
+                    Alignment::Center => format!(":{dashes}:"),
+                    Alignment::Right => format!("-{dashes}:"),
+                }
+            })
+            .collect();
+        let mut output = vec![];
+        for (i, row) in rows.iter().enumerate() {
+            let cells: Vec<Vec<String>> = row
+                .iter()
+                .zip(widths.iter())
+                .map(|(cell, width)| {
+                    textwrap::wrap(cell, *width)
+                        .into_iter()
+                        .map(|v| v.to_string())
+                        .collect()
+                })
+                .collect();
+            let height = cells
+                .iter()
+                .map(|v| v.len())
+                .max()
+                .unwrap_or_default()
+                .max(1);
+            for line_index in 0..height {
+                let line: Vec<String> = cells
+                    .iter()
+                    .zip(widths.iter())
+                    .zip(aligns.iter().chain(std::iter::repeat(&Alignment::Left)))
+                    .map(|((cell, width), align)| {
+                        let text = cell.get(line_index).map(|v| v.as_str()).unwrap_or_default();
+                        align_cell(text, *width, *align)
+                    })
+                    .collect();
+                let line = format!("| {} |", line.join(" | "));
+                output.push(self.highlight_line(&line, &self.md_syntax, false));
+            }
+            if i == 0 {
+                output.push(format!("|{}|", separator.join("|")));
+            }
+        }
+        output.join("\n")
+    }
+
     fn wrap_line(&self, line: String, is_code: bool) -> String {
         if let Some(width) = self.wrap_width {
             if is_code && !self.options.wrap_code {
                 return line;
             }
             wrap(&line, width as usize)
+        } else if let Some(width) = self.soft_wrap_width.filter(|_| !is_code) {
+            if textwrap::core::display_width(&line) > width as usize {
+                wrap(&line, width as usize)
+            } else {
+                line
+            }
         } else {
             line
         }
     }
 
+    /// Best-effort syntax for a code block without a language tag, `None` renders it plainly
+    fn guess_syntax(&self, first_line: &str) -> Option<&SyntaxReference> {
+        if let Some(syntax) = self.syntax_set.find_syntax_by_first_line(first_line) {
+            return Some(syntax);
+        }
+        match self.options.code_lang.as_deref() {
+            None => None,
+            Some("auto") => {
+                let line = first_line.trim_start();
+                LANG_HINTS
+                    .iter()
+                    .find(|(prefix, _)| line.starts_with(prefix))
+                    .and_then(|(_, lang)| self.find_syntax(lang))
+            }
+            Some(lang) => self.find_syntax(lang),
+        }
+    }
+
     fn find_syntax(&self, lang: &str) -> Option<&SyntaxReference> {
         if let Some(new_lang) = LANG_MAPS.get(&lang.to_ascii_lowercase()) {
             self.syntax_set.find_syntax_by_name(new_lang)
@@ -189,6 +548,190 @@ impl MarkdownRender {
     }
 }
 
+/// Wrap the URLs and existing file paths of a rendered line in OSC 8 hyperlinks.
+fn add_hyperlinks(line: &str) -> String {
+    link_visible(line, &LINK_RE, |text| {
+        let text = text.trim_end_matches(|c| ".,;:!?)]}".contains(c));
+        let target = link_target(text)?;
+        Some((text.len(), target))
+    })
+}
+
+/// Wrap the matches of `re` in a rendered line in OSC 8 hyperlinks, `target` gives the length to link and the target
+///
+/// Matching runs on the visible text so that color codes are skipped, the visible text itself is unchanged.
+fn link_visible(
+    line: &str,
+    re: &Regex,
+    target: impl Fn(&str) -> Option<(usize, String)>,
+) -> String {
+    let mut visible = String::new();
+    // The position in `line` of each byte of `visible`
+    let mut offsets = vec![];
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c == '\x1b' && matches!(chars.peek(), Some((_, '['))) {
+            chars.next();
+            for (_, c) in chars.by_ref() {
+                if ('\x40'..='\x7e').contains(&c) {
+                    break;
+                }
+            }
+            continue;
+        }
+        visible.push(c);
+        offsets.extend(i..i + c.len_utf8());
+    }
+    let mut output = String::new();
+    let mut last = 0;
+    for m in re.find_iter(&visible).flatten() {
+        let Some((len, target)) = target(m.as_str()).filter(|(len, _)| *len > 0) else {
+            continue;
+        };
+        let (start, end) = (offsets[m.start()], offsets[m.start() + len - 1] + 1);
+        output.push_str(&line[last..start]);
+        output.push_str(&format!("\x1b]8;;{target}\x1b\\"));
+        output.push_str(&line[start..end]);
+        output.push_str("\x1b]8;;\x1b\\");
+        last = end;
+    }
+    output.push_str(&line[last..]);
+    output
+}
+
+/// Parse a citation source line into its number, title and URL
+fn parse_citation_source(line: &str) -> Option<(String, String, String)> {
+    let caps = CITATION_SOURCE_RE.captures(line.trim()).ok()??;
+    let title = caps[2]
+        .trim_matches(|c: char| c.is_whitespace() || "[]-–—:".contains(c))
+        .to_string();
+    Some((caps[1].to_string(), title, caps[3].to_string()))
+}
+
+/// Shorten a citation source to `[n] Title (host)`, or `[n] host/path` if it has no title
+fn compact_citation_source(number: &str, title: &str, url: &str, indicator: &str) -> String {
+    let address = url.split_once("://").map_or(url, |(_, v)| v);
+    let address = address.split(['?', '#']).next().unwrap_or(address);
+    let address = address.strip_prefix("www.").unwrap_or(address);
+    if title.is_empty() {
+        let address = address.trim_end_matches('/');
+        let mut short: String = address.chars().take(40).collect();
+        if short.len() < address.len() {
+            short.push_str(indicator);
+        }
+        format!("[{number}] {short}")
+    } else {
+        let host = address.split('/').next().unwrap_or(address);
+        format!("[{number}] {title} ({host})")
+    }
+}
+
+fn link_target(text: &str) -> Option<String> {
+    if text.starts_with("http://") || text.starts_with("https://") {
+        return Some(text.to_string());
+    }
+    let path = match text.strip_prefix("~/") {
+        Some(rest) => dirs::home_dir()?.join(rest),
+        None => std::env::current_dir().ok()?.join(text),
+    };
+    if !path.exists() {
+        return None;
+    }
+    let path: PathBuf = path.canonicalize().ok()?;
+    Some(format!("file://{}", path.display()))
+}
+
+/// Color unified diff lines, additions green and deletions red
+fn highlight_diff_line(line: &str) -> String {
+    let color = if line.starts_with("+++") || line.starts_with("---") {
+        Some(Color::White)
+    } else if line.starts_with('+') {
+        Some(Color::Green)
+    } else if line.starts_with('-') {
+        Some(Color::Red)
+    } else if line.starts_with("@@") {
+        Some(Color::Cyan)
+    } else {
+        None
+    };
+    match color {
+        Some(color) => line.with(color).to_string(),
+        None => line.to_string(),
+    }
+}
+
+const MIN_TABLE_COLUMN_WIDTH: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Alignment {
+    Left,
+    Center,
+    Right,
+}
+
+/// Return the number of lines of the markdown table at the start of `lines`, 0 if there is none
+fn detect_table(lines: &[&str]) -> usize {
+    let len = lines
+        .iter()
+        .take_while(|line| line.trim_start().starts_with('|'))
+        .count();
+    if len >= 2 && is_table_separator(lines[1]) {
+        len
+    } else {
+        0
+    }
+}
+
+fn is_table_separator(line: &str) -> bool {
+    split_table_row(line).iter().all(|cell| {
+        let cell = cell.strip_prefix(':').unwrap_or(cell);
+        let cell = cell.strip_suffix(':').unwrap_or(cell);
+        !cell.is_empty() && cell.chars().all(|c| c == '-')
+    })
+}
+
+fn split_table_row(line: &str) -> Vec<String> {
+    let line = line.trim();
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let line = match line.strip_suffix('|') {
+        Some(v) if !v.ends_with('\\') => v,
+        _ => line,
+    };
+    let mut cells = vec![];
+    let mut cell = String::new();
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'|') => {
+                cell.push(c);
+                cell.extend(chars.next());
+            }
+            '|' => cells.push(std::mem::take(&mut cell).trim().to_string()),
+            _ => cell.push(c),
+        }
+    }
+    cells.push(cell.trim().to_string());
+    cells
+}
+
+fn parse_alignment(cell: &str) -> Alignment {
+    match (cell.starts_with(':'), cell.ends_with(':')) {
+        (true, true) => Alignment::Center,
+        (false, true) => Alignment::Right,
+        _ => Alignment::Left,
+    }
+}
+
+fn align_cell(text: &str, width: usize, align: Alignment) -> String {
+    let padding = width.saturating_sub(text.width());
+    let (left, right) = match align {
+        Alignment::Left => (0, padding),
+        Alignment::Center => (padding / 2, padding - padding / 2),
+        Alignment::Right => (padding, 0),
+    };
+    format!("{}{text}{}", " ".repeat(left), " ".repeat(right))
+}
+
 fn wrap(text: &str, width: usize) -> String {
     let indent: usize = text.chars().take_while(|c| *c == ' ').count();
     let wrap_options = textwrap::Options::new(width)
@@ -197,30 +740,134 @@ fn wrap(text: &str, width: usize) -> String {
     textwrap::wrap(&text[indent..], wrap_options).join("\n")
 }
 
+/// How `MarkdownRender` styles the text, the default uses no colors, wrapping or links
 #[derive(Debug, Clone, Default)]
 pub struct RenderOptions {
     pub theme: Option<Theme>,
     pub wrap: Option<String>,
     pub wrap_code: bool,
     pub truecolor: bool,
+    pub code_lang: Option<String>,
+    pub heading_styles: Vec<HeadingStyle>,
+    pub hyperlinks: bool,
+    /// Compact the `[n]` citation sources and link the citations to them
+    pub citations: bool,
+    /// Ends text that was shortened, `…` if unset
+    pub truncation_indicator: Option<String>,
+    /// Color of code blocks without a recognized language, see `ColorTheme::code`
+    pub code_color: Option<String>,
+    /// Without `wrap`, still wrap prose lines wider than the terminal at word boundaries
+    pub soft_wrap: bool,
 }
 
 impl RenderOptions {
-    pub(crate) fn new(
+    pub fn new(
         theme: Option<Theme>,
         wrap: Option<String>,
         wrap_code: bool,
         truecolor: bool,
+        code_lang: Option<String>,
+        heading_styles: Vec<HeadingStyle>,
+        hyperlinks: bool,
     ) -> Self {
         Self {
             theme,
             wrap,
             wrap_code,
             truecolor,
+            code_lang,
+            heading_styles,
+            hyperlinks,
+            citations: false,
+            truncation_indicator: None,
+            code_color: None,
+            soft_wrap: false,
         }
     }
 }
 
+/// Style of a heading level, unset fields keep the default
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct HeadingStyle {
+    /// A color name such as `yellow`, or `#rrggbb`
+    pub color: Option<String>,
+    pub bold: Option<bool>,
+    pub underline: Option<bool>,
+    /// Replaces the leading `#`s
+    pub prefix: Option<String>,
+}
+
+impl HeadingStyle {
+    fn merge(self, other: Self) -> Self {
+        Self {
+            color: other.color.or(self.color),
+            bold: other.bold.or(self.bold),
+            underline: other.underline.or(self.underline),
+            prefix: other.prefix.or(self.prefix),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct HeadingRender {
+    color: Option<Color>,
+    bold: bool,
+    underline: bool,
+    prefix: Option<String>,
+}
+
+impl HeadingRender {
+    fn new(style: HeadingStyle, truecolor: bool) -> Result<Self> {
+        let color = match style.color.as_deref() {
+            Some(value) => Some(
+                parse_color(value, truecolor)
+                    .ok_or_else(|| anyhow!("Invalid heading color '{value}'"))?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            color,
+            bold: style.bold.unwrap_or_default(),
+            underline: style.underline.unwrap_or_default(),
+            prefix: style.prefix,
+        })
+    }
+}
+
+/// Every level is bold, h1 is also underlined, the `#`s tell the deeper levels apart
+fn default_heading_style(level: usize) -> HeadingStyle {
+    HeadingStyle {
+        bold: Some(true),
+        underline: Some(level == 1),
+        ..Default::default()
+    }
+}
+
+/// The level and text of an ATX heading such as `## Title`
+fn parse_heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.chars().take_while(|c| *c == '#').count();
+    if !(1..=6).contains(&level) {
+        return None;
+    }
+    let rest = &line[level..];
+    if rest.is_empty() {
+        return Some((level, rest));
+    }
+    rest.strip_prefix([' ', '\t']).map(|text| (level, text))
+}
+
+pub(super) fn parse_color(value: &str, truecolor: bool) -> Option<Color> {
+    match value.strip_prefix('#') {
+        Some(hex) if hex.len() == 6 => {
+            let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+            let (r, g, b) = (channel(0)?, channel(2)?, channel(4)?);
+            Some(convert_color(SyntectColor { r, g, b, a: 255 }, truecolor))
+        }
+        Some(_) => None,
+        None => Color::try_from(value).ok(),
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LineType {
     Normal,
@@ -229,22 +876,81 @@ pub enum LineType {
     CodeEnd,
 }
 
-fn as_terminal_escaped(ranges: &[(Style, &str)], truecolor: bool) -> String {
+/// Escape the highlighted ranges, `code_spans` are the byte ranges of inline code painted with a background
+fn as_terminal_escaped(
+    ranges: &[(Style, &str)],
+    truecolor: bool,
+    code_spans: Option<(Color, Vec<(usize, usize)>)>,
+) -> String {
     let mut output = String::new();
+    let mut offset = 0;
     for (style, text) in ranges {
-        let fg = blend_fg_color(style.foreground, style.background);
-        let mut text = text.with(convert_color(fg, truecolor));
-        if style.font_style.contains(FontStyle::BOLD) {
-            text = text.bold();
-        }
-        if style.font_style.contains(FontStyle::UNDERLINE) {
-            text = text.underlined();
+        let fg = convert_color(
+            blend_fg_color(style.foreground, style.background),
+            truecolor,
+        );
+        let start = offset;
+        let end = start + text.len();
+        offset = end;
+        let mut pos = start;
+        while pos < end {
+            let (next, bg) = match code_spans
+                .as_ref()
+                .and_then(|(bg, spans)| spans.iter().find(|(_, e)| *e > pos).map(|v| (bg, v)))
+            {
+                Some((bg, (s, e))) if *s <= pos => ((*e).min(end), Some(*bg)),
+                Some((_, (s, _))) => ((*s).min(end), None),
+                None => (end, None),
+            };
+            let mut part = text[pos - start..next - start].with(fg);
+            if let Some(bg) = bg {
+                part = part.on(bg);
+            }
+            if style.font_style.contains(FontStyle::BOLD) {
+                part = part.bold();
+            }
+            if style.font_style.contains(FontStyle::UNDERLINE) {
+                part = part.underlined();
+            }
+            output.push_str(&part.to_string());
+            pos = next;
         }
-        output.push_str(&text.to_string());
     }
     output
 }
 
+/// Byte ranges of the inline code spans in a line, including the backticks
+fn find_inline_code_spans(line: &str) -> Vec<(usize, usize)> {
+    let bytes = line.as_bytes();
+    let backtick_run = |start: usize| bytes[start..].iter().take_while(|v| **v == b'`').count();
+    let mut spans = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'`' {
+            i += 1;
+            continue;
+        }
+        let len = backtick_run(i);
+        let mut j = i + len;
+        let mut closed = false;
+        while j < bytes.len() {
+            if bytes[j] == b'`' {
+                let close_len = backtick_run(j);
+                if close_len == len {
+                    spans.push((i, j + len));
+                    closed = true;
+                    break;
+                }
+                j += close_len;
+            } else {
+                j += 1;
+            }
+        }
+        i = if closed { j + len } else { i + len };
+    }
+    spans
+}
+
 fn convert_color(c: SyntectColor, truecolor: bool) -> Color {
     if truecolor {
         Color::Rgb {
@@ -279,16 +985,44 @@ fn blend_fg_color(fg: SyntectColor, bg: SyntectColor) -> SyntectColor {
     }
 }
 
-fn detect_code_block(line: &str) -> Option<String> {
-    if !line.starts_with("```") {
+/// Parse an opening code fence, a run of at least 3 backticks or tildes, into the fence and the language
+fn detect_code_block(line: &str) -> Option<(String, String)> {
+    let (fence, info) = split_fence(line)?;
+    if fence.starts_with('`') && info.contains('`') {
         return None;
     }
-    let lang = line
+    let lang = info
+        .trim_start()
         .chars()
-        .skip(3)
         .take_while(|v| v.is_alphanumeric())
         .collect();
-    Some(lang)
+    Some((fence.to_string(), lang))
+}
+
+/// Whether the line closes a code block opened with `fence`
+fn is_closing_fence(line: &str, fence: &str) -> bool {
+    match split_fence(line) {
+        Some((closing, info)) => {
+            closing.starts_with(&fence[..1])
+                && closing.len() >= fence.len()
+                && info.trim().is_empty()
+        }
+        None => false,
+    }
+}
+
+/// Split a fence line, indented by at most 3 spaces, into the fence and the text after it
+fn split_fence(line: &str) -> Option<(&str, &str)> {
+    let trimmed = line.trim_start_matches(' ');
+    if line.len() - trimmed.len() > 3 {
+        return None;
+    }
+    let ch = trimmed.chars().next().filter(|v| matches!(v, '`' | '~'))?;
+    let len = trimmed.len() - trimmed.trim_start_matches(ch).len();
+    if len < 3 {
+        return None;
+    }
+    Some(trimmed.split_at(len))
 }
 
 fn get_code_color(theme: &Theme, truecolor: bool) -> Color {
@@ -305,7 +1039,9 @@ fn get_code_color(theme: &Theme, truecolor: bool) -> Color {
 
 #[cfg(test)]
 mod tests {
+    use super::super::stream::strip_ansi;
     use super::*;
+    use crate::config::dark_theme;
 
     const TEXT: &str = r#"
 To unzip a file in Rust, you can use the `zip` crate. Here's an example code that shows how to unzip a file:
@@ -360,6 +1096,75 @@ std::error::Error>> {
         assert_eq!(TEXT, output);
     }
 
+    #[test]
+    fn finish_open_fence() {
+        let options = RenderOptions {
+            theme: Some(dark_theme()),
+            ..Default::default()
+        };
+        let mut render = MarkdownRender::init(options).unwrap();
+        render.render("```diff\n-old");
+        let output = render.finish("+new");
+        assert_eq!(output, "+new".with(Color::Green).to_string());
+        assert_eq!(render.prev_line_type, LineType::CodeEnd);
+        assert_eq!(
+            render.render("-text"),
+            render.highlight_line("-text", &render.md_syntax, false)
+        );
+    }
+
+    #[test]
+    fn render_citations() {
+        let text = "Rust is fast [1], see [2](x).\n\n[1]: https://www.example.com/a/b?utm=1\n[2] [The Book](https://doc.rust-lang.org/book/)";
+        let output = MarkdownRender::render_once(text, RenderOptions::default()).unwrap();
+        assert_eq!(output, text);
+
+        let options = RenderOptions {
+            citations: true,
+            ..Default::default()
+        };
+        let output = MarkdownRender::render_once(text, options).unwrap();
+        let lines: Vec<&str> = output.split('\n').collect();
+        let link = "\x1b]8;;https://www.example.com/a/b?utm=1\x1b\\[1]\x1b]8;;\x1b\\";
+        assert_eq!(lines[0], format!("Rust is fast {link}, see [2](x)."));
+        assert_eq!(lines[2], format!("{link} example.com/a/b"));
+        assert_eq!(strip_ansi(lines[3]), "[2] The Book (doc.rust-lang.org)");
+        assert_eq!(
+            parse_citation_source("[3] Title - https://a.com/x"),
+            Some(("3".into(), "Title".into(), "https://a.com/x".into()))
+        );
+        assert_eq!(parse_citation_source("[3] no url"), None);
+        assert_eq!(
+            compact_citation_source("4", "", &format!("https://a.com/{}", "x".repeat(50)), "[…]"),
+            format!("[4] a.com/{}[…]", "x".repeat(34))
+        );
+    }
+
+    #[test]
+    fn no_color() {
+        let text = "# Title\n\n| a | b |\n|---|---|\n| 1 | 2 |\n\n```diff\n-old\n+new\n```";
+        let output = MarkdownRender::render_once(text, RenderOptions::default()).unwrap();
+        assert!(!output.contains('\x1b'));
+        assert!(output.contains("# Title") && output.contains("+new"));
+    }
+
+    #[test]
+    fn soft_wrap() {
+        let paragraph = "Rust is a general-purpose programming language emphasizing performance, type safety and concurrency, without a garbage collector.";
+        let code = "let sum: u64 = numbers.iter().filter(|v| *v % 2 == 0).map(|v| v * v).sum();";
+        let text = format!("{paragraph}\n\n```rust\n{code}\n```\n\n  - {paragraph}");
+        let mut render = MarkdownRender::init(RenderOptions::default()).unwrap();
+        render.soft_wrap_width = Some(40);
+        let output = render.render(&text);
+        let lines: Vec<&str> = output.split('\n').collect();
+        assert!(lines.iter().all(|v| v.width() <= 40 || *v == code));
+        assert!(lines.contains(&code));
+        let (prose, list) = output.split_once("```\n\n").unwrap();
+        let words = |text: &str| text.split_whitespace().collect::<Vec<_>>().join(" ");
+        assert!(words(prose).starts_with(&words(paragraph)));
+        assert_eq!(words(list), words(&format!("- {paragraph}")));
+    }
+
     #[test]
     fn no_wrap_code() {
         let options = RenderOptions::default();
@@ -380,4 +1185,199 @@ std::error::Error>> {
         let output = render.render(TEXT);
         assert_eq!(TEXT_WRAP_ALL, output);
     }
+
+    #[test]
+    fn render_diff() {
+        let options = RenderOptions {
+            theme: Some(dark_theme()),
+            ..Default::default()
+        };
+        let mut render = MarkdownRender::init(options).unwrap();
+        let output = render.render("```diff\n-old\n+new\n ctx\n```");
+        let lines: Vec<&str> = output.split('\n').collect();
+        assert_eq!(lines[1], "-old".with(Color::Red).to_string());
+        assert_eq!(lines[2], "+new".with(Color::Green).to_string());
+        assert_eq!(lines[3], " ctx");
+    }
+
+    #[test]
+    fn nested_fences() {
+        let mut render = MarkdownRender::init(RenderOptions::default()).unwrap();
+        render.render("````markdown\n```rust\nfn main() {}\n```");
+        assert_eq!(render.prev_line_type, LineType::CodeInner);
+        render.render("````");
+        assert_eq!(render.prev_line_type, LineType::CodeEnd);
+
+        let mut render = MarkdownRender::init(RenderOptions::default()).unwrap();
+        render.render("~~~\n```\n````\n~~~~ not a fence");
+        assert_eq!(render.prev_line_type, LineType::CodeInner);
+        render.render("  ~~~~");
+        assert_eq!(render.prev_line_type, LineType::CodeEnd);
+
+        assert_eq!(
+            detect_code_block("```` rust"),
+            Some(("````".into(), "rust".into()))
+        );
+        assert_eq!(detect_code_block("``"), None);
+        assert_eq!(detect_code_block("    ```"), None);
+        assert_eq!(detect_code_block("``` a`b"), None);
+        assert!(!is_closing_fence("```", "````"));
+        assert!(!is_closing_fence("````rust", "```"));
+        assert!(is_closing_fence("````` ", "```"));
+    }
+
+    #[test]
+    fn guess_code_lang() {
+        let render = MarkdownRender::init(RenderOptions::default()).unwrap();
+        assert!(render.guess_syntax("fn main() {").is_none());
+
+        let options = RenderOptions {
+            code_lang: Some("auto".into()),
+            ..Default::default()
+        };
+        let render = MarkdownRender::init(options).unwrap();
+        assert_eq!(render.guess_syntax("fn main() {").unwrap().name, "Rust");
+        assert!(render.guess_syntax("hello world").is_none());
+
+        let options = RenderOptions {
+            code_lang: Some("python".into()),
+            ..Default::default()
+        };
+        let render = MarkdownRender::init(options).unwrap();
+        assert_eq!(render.guess_syntax("x = 1").unwrap().name, "Python");
+    }
+
+    #[test]
+    fn render_table() {
+        let mut render = MarkdownRender::init(RenderOptions::default()).unwrap();
+        render.wrap_width = Some(80);
+        let output = render.render("| a | bb |\n|:-:|--:|\n| ccc | d |");
+        assert_eq!(output, "|  a  |  bb |\n|:---:|----:|\n| ccc |   d |");
+
+        let output = render.render("a\n| x |\n|---|\n| y | z |\n\nb");
+        assert_eq!(
+            output,
+            "a\n| x   |     |\n|-----|-----|\n| y   | z   |\n\nb"
+        );
+
+        render.wrap_width = Some(20);
+        let output = render.render("| key | value |\n|---|---|\n| k | a long value here |");
+        assert_eq!(
+            output,
+            "| key | value      |\n|-----|------------|\n| k   | a long     |\n|     | value here |"
+        );
+
+        render.wrap_width = Some(15);
+        let text = "| a | b | c |\n|---|---|---|\n| 1 | 2 | 3 |";
+        assert_eq!(render.render(text), text);
+    }
+
+    #[test]
+    fn stream_table() {
+        let mut render = MarkdownRender::init(RenderOptions::default()).unwrap();
+        render.wrap_width = Some(80);
+        let mut output = vec![];
+        for line in ["a", "| a | bb |", "|:-:|--:|", "| ccc | d |", "b", "| x |"] {
+            output.extend(render.render_stream(line));
+            if line.starts_with('|') {
+                assert!(render.held_table().is_some());
+            }
+        }
+        output.push(render.finish("| y |"));
+        assert_eq!(
+            output.join("\n"),
+            "a\n|  a  |  bb |\n|:---:|----:|\n| ccc |   d |\nb\n| x |\n| y |"
+        );
+        assert!(render.held_table().is_none());
+
+        render.render_stream("| a |\n|---|\n| b |");
+        assert_eq!(render.finish(""), "| a   |\n|-----|\n| b   |\n");
+    }
+
+    #[test]
+    fn skip_table_in_code() {
+        let mut render = MarkdownRender::init(RenderOptions::default()).unwrap();
+        render.wrap_width = Some(80);
+        let text = "```\n| a |\n|---|\n```\n| not a table |";
+        assert_eq!(render.render(text), text);
+    }
+
+    #[test]
+    fn inline_code_spans() {
+        assert_eq!(find_inline_code_spans("a `b` c"), [(2, 5)]);
+        assert_eq!(find_inline_code_spans("``a`b`` `|*x*|`"), [(0, 7), (8, 15)]);
+        assert!(find_inline_code_spans("no `close").is_empty());
+
+        let options = RenderOptions {
+            theme: Some(dark_theme()),
+            ..Default::default()
+        };
+        let mut render = MarkdownRender::init(options).unwrap();
+        let bg = render.inline_code_bg.unwrap();
+        let text = "run `a|*b*` — ok";
+        let output = render.render(text);
+        assert!(output.contains(&format!("{}", crossterm::style::SetBackgroundColor(bg))));
+        assert_eq!(
+            textwrap::core::display_width(&output),
+            textwrap::core::display_width(text)
+        );
+    }
+
+    #[test]
+    fn render_heading() {
+        assert_eq!(parse_heading("## Title"), Some((2, "Title")));
+        assert_eq!(parse_heading("#"), Some((1, "")));
+        assert_eq!(parse_heading("#hashtag"), None);
+        assert_eq!(parse_heading("####### seven"), None);
+
+        let heading_style = HeadingStyle {
+            color: Some("#ff0000".into()),
+            prefix: Some("▍ ".into()),
+            ..Default::default()
+        };
+        let options = RenderOptions {
+            theme: Some(dark_theme()),
+            truecolor: true,
+            heading_styles: vec![HeadingStyle::default(), heading_style],
+            ..Default::default()
+        };
+        let mut render = MarkdownRender::init(options).unwrap();
+        let output = render.render("# Title");
+        assert!(output.contains(&format!("{}", crossterm::style::Attribute::Underlined)));
+        assert_eq!(textwrap::core::display_width(&output), "# Title".len());
+
+        let output = render.render("## Sub");
+        let red = Color::Rgb { r: 255, g: 0, b: 0 };
+        assert!(output.contains(&format!("{}", crossterm::style::SetForegroundColor(red))));
+        assert_eq!(textwrap::core::display_width(&output), "▍ Sub".width());
+
+        let options = RenderOptions {
+            heading_styles: vec![HeadingStyle {
+                color: Some("nope".into()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(MarkdownRender::init(options).is_err());
+    }
+
+    #[test]
+    fn render_hyperlinks() {
+        let options = RenderOptions {
+            hyperlinks: true,
+            ..Default::default()
+        };
+        let mut render = MarkdownRender::init(options).unwrap();
+        let output = render.render("```\nsee https://example.com/a.\n```");
+        let lines: Vec<&str> = output.split('\n').collect();
+        assert_eq!(
+            lines[1],
+            "see \x1b]8;;https://example.com/a\x1b\\https://example.com/a\x1b]8;;\x1b\\."
+        );
+        assert_eq!(strip_ansi(lines[1]), "see https://example.com/a.");
+        assert_eq!(add_hyperlinks("no/such/file here"), "no/such/file here");
+        let output = add_hyperlinks("\x1b[31msrc/main.rs\x1b[0m");
+        assert!(output.starts_with("\x1b[31m\x1b]8;;file://"));
+        assert!(output.ends_with("src/main.rs\x1b]8;;\x1b\\\x1b[0m"));
+    }
 }
@@ -1,38 +1,105 @@
+mod colors;
 mod markdown;
 mod stream;
 
-pub use self::markdown::{MarkdownRender, RenderOptions};
-use self::stream::{markdown_stream, raw_stream};
+pub use self::colors::{ColorTheme, Colors};
+pub use self::markdown::{HeadingStyle, MarkdownRender, RenderOptions};
+pub(crate) use self::stream::{strip_ansi, AnsiFilter};
+
+use self::stream::{
+    ansi_stream, markdown_stream, raw_stream, strip_ansi_stream, tee_stream, BatchWindow,
+};
 
 use crate::utils::AbortSignal;
-use crate::{client::ReplyEvent, config::GlobalConfig};
+use crate::{
+    client::ReplyStream,
+    config::{GlobalConfig, PipeOutput, ReplyAnsi},
+};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use is_terminal::IsTerminal;
 use nu_ansi_term::{Color, Style};
-use std::io::stdout;
-use tokio::sync::mpsc::UnboundedReceiver;
+use std::{fs::OpenOptions, io::stdout};
 
+/// Render the reply stream to the terminal, as markdown if stdout is a terminal or `pipe_output` is ansi
+///
+/// Returns the number of rows after which the markdown render gave way to raw text, see `max_stream_rows`
 pub async fn render_stream(
-    rx: UnboundedReceiver<ReplyEvent>,
+    stream: ReplyStream,
     config: &GlobalConfig,
     abort: AbortSignal,
-) -> Result<()> {
-    if stdout().is_terminal() {
+) -> Result<Option<usize>> {
+    let tee_output = {
+        let config = config.read();
+        config.tee_output.clone().map(|v| (v, config.tee_append))
+    };
+    let stream = match tee_output {
+        Some((path, append)) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(append)
+                .truncate(!append)
+                .open(&path)
+                .with_context(|| format!("Failed to open tee_output '{path}'"))?;
+            tee_stream(stream, file)
+        }
+        None => stream,
+    };
+    let terminal_render = stdout().is_terminal() || config.read().pipe_output == PipeOutput::Ansi;
+    let stream = match config.read().reply_ansi {
+        ReplyAnsi::Strip if terminal_render => strip_ansi_stream(stream),
+        _ => stream,
+    };
+    if config.read().compact {
+        raw_stream(stream, &abort).await?;
+        Ok(None)
+    } else if stdout().is_terminal() {
+        let render_options = config.read().get_render_options()?;
+        let (instant_first_token, adaptive_batch, max_rows, quiet) = {
+            let config = config.read();
+            if config.show_model && !config.quiet {
+                let color = if config.light_theme {
+                    Color::LightGray
+                } else {
+                    Color::DarkGray
+                };
+                println!("{}", color.paint(format!("[{}]", config.model.id())));
+            }
+            (
+                config.instant_first_token,
+                config.adaptive_batch,
+                config.max_stream_rows,
+                config.quiet,
+            )
+        };
+        let mut render = MarkdownRender::init(render_options)?;
+        markdown_stream(
+            stream,
+            &mut render,
+            &abort,
+            instant_first_token,
+            BatchWindow::new(adaptive_batch),
+            max_rows,
+            !quiet,
+        )
+        .await
+    } else if config.read().pipe_output == PipeOutput::Ansi {
         let render_options = config.read().get_render_options()?;
         let mut render = MarkdownRender::init(render_options)?;
-        markdown_stream(rx, &mut render, &abort).await
+        ansi_stream(stream, &mut render, &abort).await?;
+        Ok(None)
     } else {
-        raw_stream(rx, &abort).await
+        raw_stream(stream, &abort).await?;
+        Ok(None)
     }
 }
 
-pub fn render_error(err: anyhow::Error, highlight: bool) {
+/// Print the error to stderr, in the given color if any, see `Config::error_color`
+pub fn render_error(err: anyhow::Error, color: Option<Color>) {
     let err = format!("{err:?}");
-    if highlight {
-        let style = Style::new().fg(Color::Red);
-        eprintln!("{}", style.paint(err));
-    } else {
-        eprintln!("{err}");
+    match color {
+        Some(color) => eprintln!("{}", Style::new().fg(color).paint(err)),
+        None => eprintln!("{err}"),
     }
 }
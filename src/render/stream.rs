@@ -1,5 +1,6 @@
-use super::{MarkdownRender, ReplyEvent};
+use super::MarkdownRender;
 
+use crate::client::{ReplyEvent, ReplyStream};
 use crate::utils::{run_spinner, AbortSignal};
 
 use anyhow::Result;
@@ -10,33 +11,47 @@ use crossterm::{
     terminal::{self, disable_raw_mode, enable_raw_mode},
 };
 use std::{
+    fs::File,
     io::{self, stdout, Stdout, Write},
     time::Duration,
 };
 use textwrap::core::display_width;
-use tokio::sync::{mpsc::UnboundedReceiver, oneshot};
+use tokio::sync::{mpsc::unbounded_channel, oneshot};
+use tokio_stream::{wrappers::UnboundedReceiverStream, StreamExt};
 
 pub async fn markdown_stream(
-    rx: UnboundedReceiver<ReplyEvent>,
+    rx: ReplyStream,
     render: &mut MarkdownRender,
     abort: &AbortSignal,
-) -> Result<()> {
+    instant_first_token: bool,
+    batch_window: BatchWindow,
+    max_rows: usize,
+    spinner: bool,
+) -> Result<Option<usize>> {
     enable_raw_mode()?;
-    let mut stdout = io::stdout();
 
-    let ret = markdown_stream_inner(rx, render, abort, &mut stdout).await;
+    let ret = markdown_stream_inner(
+        rx,
+        render,
+        abort,
+        instant_first_token,
+        batch_window,
+        max_rows,
+        spinner,
+    )
+    .await;
 
     disable_raw_mode()?;
 
     ret
 }
 
-pub async fn raw_stream(mut rx: UnboundedReceiver<ReplyEvent>, abort: &AbortSignal) -> Result<()> {
+pub async fn raw_stream(mut rx: ReplyStream, abort: &AbortSignal) -> Result<()> {
     loop {
         if abort.aborted() {
             return Ok(());
         }
-        if let Some(evt) = rx.recv().await {
+        if let Some(evt) = rx.next().await {
             match evt {
                 ReplyEvent::Text(text) => {
                     print!("{}", text);
@@ -51,31 +66,87 @@ pub async fn raw_stream(mut rx: UnboundedReceiver<ReplyEvent>, abort: &AbortSign
     Ok(())
 }
 
-async fn markdown_stream_inner(
-    mut rx: UnboundedReceiver<ReplyEvent>,
+/// Render each complete line as it arrives, for output that can't be rewritten in place
+pub async fn ansi_stream(
+    mut rx: ReplyStream,
     render: &mut MarkdownRender,
     abort: &AbortSignal,
-    writer: &mut Stdout,
 ) -> Result<()> {
+    let mut buffer = String::new();
+    loop {
+        if abort.aborted() {
+            return Ok(());
+        }
+        match rx.next().await {
+            Some(ReplyEvent::Text(text)) => {
+                buffer.push_str(&text);
+                if let Some((head, tail)) = buffer.rsplit_once('\n') {
+                    if let Some(output) = render.render_stream(head) {
+                        println!("{output}");
+                        stdout().flush()?;
+                    }
+                    buffer = tail.to_string();
+                }
+            }
+            Some(ReplyEvent::Done) | None => break,
+        }
+    }
+    let output = render.finish(&buffer);
+    if !output.is_empty() {
+        print!("{output}");
+        stdout().flush()?;
+    }
+    Ok(())
+}
+
+/// Returns the number of rendered rows at which the stream fell back to appending raw text, if it did
+async fn markdown_stream_inner(
+    mut rx: ReplyStream,
+    render: &mut MarkdownRender,
+    abort: &AbortSignal,
+    instant_first_token: bool,
+    mut batch_window: BatchWindow,
+    max_rows: usize,
+    spinner: bool,
+) -> Result<Option<usize>> {
+    let writer = &mut io::stdout();
     let mut buffer = String::new();
     let mut buffer_rows = 1;
+    let mut rendered_rows = 0;
+    let mut raw_from = None;
 
     let columns = terminal::size()?.0;
 
     let (spinner_tx, spinner_rx) = oneshot::channel();
     let mut spinner_tx = Some(spinner_tx);
-    tokio::spawn(run_spinner(" Generating", spinner_rx));
+    if spinner {
+        tokio::spawn(run_spinner(" Generating", spinner_rx));
+    }
 
     'outer: loop {
         if abort.aborted() {
-            return Ok(());
+            return Ok(raw_from);
+        }
+        // Until the first text shows up the spinner is still running, so skip batching if asked
+        let window = match instant_first_token && spinner_tx.is_some() {
+            true => None,
+            false => Some(batch_window.window),
+        };
+        let (reply_events, chunks) = gather_events(&mut rx, window).await;
+        if window.is_some() {
+            batch_window.update(chunks);
         }
-        for reply_event in gather_events(&mut rx).await {
+        for reply_event in reply_events {
             if let Some(spinner_tx) = spinner_tx.take() {
                 let _ = spinner_tx.send(());
             }
 
             match reply_event {
+                ReplyEvent::Text(text) if raw_from.is_some() => {
+                    // Raw mode does not return the carriage on a line feed
+                    queue!(writer, style::Print(text.replace('\n', "\r\n")))?;
+                    writer.flush()?;
+                }
                 ReplyEvent::Text(mut text) => {
                     // tab width hacking
                     text = text.replace('\t', "    ");
@@ -83,7 +154,7 @@ async fn markdown_stream_inner(
                     let (col, mut row) = cursor::position()?;
 
                     // Fix unexpected duplicate lines on kitty, see https://github.com/sigoden/aichat/issues/105
-                    if col == 0 && row > 0 && display_width(&buffer) == columns as usize {
+                    if col == 0 && row > 0 && visible_width(&buffer) == columns as usize {
                         row -= 1;
                     }
 
@@ -104,14 +175,18 @@ async fn markdown_stream_inner(
                     if text.contains('\n') {
                         let text = format!("{buffer}{text}");
                         let (head, tail) = split_line_tail(&text);
-                        let output = render.render(head);
-                        print_block(writer, &output, columns)?;
+                        if let Some(output) = render.render_stream(head) {
+                            rendered_rows += print_block(writer, &output, columns)? as usize;
+                        }
                         buffer = tail.to_string();
                     } else {
                         buffer = format!("{buffer}{text}");
                     }
 
-                    let output = render.render_line(&buffer);
+                    let output = match render.held_table() {
+                        Some(table) => format!("{table}\n{}", render.render_line(&buffer)),
+                        None => render.render_line(&buffer),
+                    };
                     if output.contains('\n') {
                         let (head, tail) = split_line_tail(&output);
                         buffer_rows = print_block(writer, head, columns)?;
@@ -126,8 +201,32 @@ async fn markdown_stream_inner(
                     }
 
                     writer.flush()?;
+
+                    // Rewinding the cursor over a huge reply thrashes the terminal, so append the rest as it comes
+                    if max_rows > 0 && rendered_rows > max_rows {
+                        raw_from = Some(rendered_rows);
+                    }
                 }
                 ReplyEvent::Done => {
+                    // Redraw the unfinished last line now the reply is over, it may end inside a code block or a table
+                    let output = render.finish(&buffer);
+                    if raw_from.is_none() && !output.is_empty() {
+                        let (col, mut row) = cursor::position()?;
+                        if col == 0 && row > 0 && visible_width(&buffer) == columns as usize {
+                            row -= 1;
+                        }
+                        queue!(
+                            writer,
+                            cursor::MoveTo(0, (row + 1).saturating_sub(buffer_rows)),
+                            terminal::Clear(terminal::ClearType::FromCursorDown),
+                        )?;
+                        let (head, tail) = split_line_tail(&output);
+                        if !head.is_empty() || output.contains('\n') {
+                            print_block(writer, head, columns)?;
+                        }
+                        queue!(writer, style::Print(tail))?;
+                        writer.flush()?;
+                    }
                     break 'outer;
                 }
             }
@@ -153,17 +252,149 @@ async fn markdown_stream_inner(
     if let Some(spinner_tx) = spinner_tx.take() {
         let _ = spinner_tx.send(());
     }
-    Ok(())
+    Ok(raw_from)
 }
 
-async fn gather_events(rx: &mut UnboundedReceiver<ReplyEvent>) -> Vec<ReplyEvent> {
+/// Fork the stream, writing its text to the file as it arrives, stops writing after the first error
+pub fn tee_stream(mut stream: ReplyStream, mut file: File) -> ReplyStream {
+    let (tx, rx) = unbounded_channel();
+    tokio::spawn(async move {
+        let mut writable = true;
+        while let Some(reply_event) = stream.next().await {
+            if let (ReplyEvent::Text(text), true) = (&reply_event, writable) {
+                writable = file
+                    .write_all(text.as_bytes())
+                    .and_then(|_| file.flush())
+                    .is_ok();
+            }
+            if tx.send(reply_event).is_err() {
+                break;
+            }
+        }
+    });
+    UnboundedReceiverStream::new(rx)
+}
+
+/// The time `gather_events` batches text for, each batch costs a redraw of the unfinished line
+#[derive(Debug)]
+pub struct BatchWindow {
+    adaptive: bool,
+    window: Duration,
+}
+
+impl BatchWindow {
+    const MIN: Duration = Duration::from_millis(50);
+    const MAX: Duration = Duration::from_millis(400);
+    /// Chunks per second above which the terminal can't keep up with the redraws
+    const FAST_RATE: f64 = 200.0;
+
+    pub fn new(adaptive: bool) -> Self {
+        Self {
+            adaptive,
+            window: Self::MIN,
+        }
+    }
+
+    /// Double the window while chunks arrive faster than `FAST_RATE`, and halve it once they fall well below
+    fn update(&mut self, chunks: usize) {
+        if !self.adaptive {
+            return;
+        }
+        let rate = chunks as f64 / self.window.as_secs_f64();
+        if rate > Self::FAST_RATE {
+            self.window = (self.window * 2).min(Self::MAX);
+        } else if rate < Self::FAST_RATE / 4.0 {
+            self.window = (self.window / 2).max(Self::MIN);
+        }
+    }
+}
+
+/// Drop the ANSI escape sequences from the stream, which may split them across chunks
+pub fn strip_ansi_stream(mut stream: ReplyStream) -> ReplyStream {
+    let (tx, rx) = unbounded_channel();
+    tokio::spawn(async move {
+        let mut filter = AnsiFilter::default();
+        while let Some(reply_event) = stream.next().await {
+            let reply_event = match reply_event {
+                ReplyEvent::Text(text) => ReplyEvent::Text(filter.filter(&text)),
+                other => other,
+            };
+            if tx.send(reply_event).is_err() {
+                break;
+            }
+        }
+    });
+    UnboundedReceiverStream::new(rx)
+}
+
+/// Remove the ANSI escape sequences, colors and OSC 8 hyperlinks included
+pub(crate) fn strip_ansi(text: &str) -> String {
+    AnsiFilter::default().filter(text)
+}
+
+/// The width of the text on the terminal, escape sequences take no room
+fn visible_width(text: &str) -> usize {
+    display_width(&strip_ansi(text))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum AnsiState {
+    #[default]
+    Text,
+    Escape,
+    /// `ESC [`, ended by a byte in `@`..=`~`
+    Csi,
+    /// `ESC ]`, ended by BEL or `ESC \`
+    Osc,
+    OscEscape,
+}
+
+/// Drops ANSI escape sequences from text that arrives in chunks
+#[derive(Debug, Default)]
+pub(crate) struct AnsiFilter {
+    state: AnsiState,
+}
+
+impl AnsiFilter {
+    fn filter(&mut self, text: &str) -> String {
+        text.chars().filter(|c| self.is_visible(*c)).collect()
+    }
+
+    /// Advance over a char, returning whether it is visible text rather than part of an escape sequence
+    pub(crate) fn is_visible(&mut self, c: char) -> bool {
+        let visible = self.state == AnsiState::Text && c != '\x1b';
+        self.state = match (self.state, c) {
+            (AnsiState::Text, '\x1b') => AnsiState::Escape,
+            (AnsiState::Text, _) => AnsiState::Text,
+            (AnsiState::Escape, '[') => AnsiState::Csi,
+            (AnsiState::Escape, ']') => AnsiState::Osc,
+            (AnsiState::Csi, '\x40'..='\x7e') => AnsiState::Text,
+            (AnsiState::Csi, _) => AnsiState::Csi,
+            (AnsiState::Osc, '\x07') => AnsiState::Text,
+            (AnsiState::Osc, '\x1b') => AnsiState::OscEscape,
+            (AnsiState::Osc, _) => AnsiState::Osc,
+            (AnsiState::Escape | AnsiState::OscEscape, _) => AnsiState::Text,
+        };
+        visible
+    }
+}
+
+/// Collect the events arriving within the window, or return at the first text if there is none
+///
+/// Also returns the number of text chunks collected
+async fn gather_events(rx: &mut ReplyStream, window: Option<Duration>) -> (Vec<ReplyEvent>, usize) {
     let mut texts = vec![];
     let mut done = false;
     tokio::select! {
         _ = async {
-            while let Some(reply_event) = rx.recv().await {
+            while let Some(reply_event) = rx.next().await {
                 match reply_event {
-                    ReplyEvent::Text(v) => texts.push(v),
+                    ReplyEvent::Text(v) => {
+                        texts.push(v);
+                        if window.is_none() {
+                            break;
+                        }
+                    }
                     ReplyEvent::Done => {
                         done = true;
                         break;
@@ -171,8 +402,9 @@ async fn gather_events(rx: &mut UnboundedReceiver<ReplyEvent>) -> Vec<ReplyEvent
                 }
             }
         } => {}
-        _ = tokio::time::sleep(Duration::from_millis(50)) => {}
+        _ = tokio::time::sleep(window.unwrap_or(BatchWindow::MIN)) => {}
     };
+    let chunks = texts.len();
     let mut events = vec![];
     if !texts.is_empty() {
         events.push(ReplyEvent::Text(texts.join("")))
@@ -180,9 +412,10 @@ async fn gather_events(rx: &mut UnboundedReceiver<ReplyEvent>) -> Vec<ReplyEvent
     if done {
         events.push(ReplyEvent::Done)
     }
-    events
+    (events, chunks)
 }
 
+/// Returns the number of rows printed, a line wider than the terminal takes more than one
 fn print_block(writer: &mut Stdout, text: &str, columns: u16) -> Result<u16> {
     let mut num = 0;
     for line in text.split('\n') {
@@ -192,7 +425,7 @@ fn print_block(writer: &mut Stdout, text: &str, columns: u16) -> Result<u16> {
             style::Print("\n"),
             cursor::MoveLeft(columns),
         )?;
-        num += 1;
+        num += need_rows(line, columns);
     }
     Ok(num)
 }
@@ -206,6 +439,44 @@ fn split_line_tail(text: &str) -> (&str, &str) {
 }
 
 fn need_rows(text: &str, columns: u16) -> u16 {
-    let buffer_width = display_width(text).max(1) as u16;
-    (buffer_width + columns - 1) / columns
+    let buffer_width = visible_width(text).max(1) as u16;
+    buffer_width.div_ceil(columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ansi_filter() {
+        let mut filter = AnsiFilter::default();
+        assert_eq!(filter.filter("a\x1b[31mred\x1b["), "ared");
+        assert_eq!(filter.filter("0m b\x1b]8;;https://x\x1b"), " b");
+        assert_eq!(filter.filter("\\link\x1b]8;;\x07 \x1bc!"), "link !");
+        assert_eq!(visible_width("\x1b[1;32m世界\x1b[0m"), 4);
+    }
+
+    #[test]
+    fn test_batch_window() {
+        let mut window = BatchWindow::new(true);
+        window.update(20);
+        assert_eq!(window.window, Duration::from_millis(100));
+        for _ in 0..5 {
+            window.update(100);
+        }
+        assert_eq!(window.window, BatchWindow::MAX);
+        window.update(30);
+        assert_eq!(window.window, Duration::from_millis(400));
+        window.update(1);
+        window.update(1);
+        assert_eq!(window.window, Duration::from_millis(100));
+        for _ in 0..5 {
+            window.update(0);
+        }
+        assert_eq!(window.window, BatchWindow::MIN);
+
+        let mut window = BatchWindow::new(false);
+        window.update(100);
+        assert_eq!(window.window, BatchWindow::MIN);
+    }
 }
@@ -20,10 +20,13 @@ impl ReplHighlighter {
 impl Highlighter for ReplHighlighter {
     fn highlight(&self, line: &str, _cursor: usize) -> StyledText {
         let color = Color::Default;
-        let match_color = if self.config.read().highlight {
-            Color::Green
-        } else {
-            color
+        let match_color = {
+            let config = self.config.read();
+            if config.highlight {
+                config.colors().command
+            } else {
+                color
+            }
         };
 
         let mut styled_text = StyledText::new();
@@ -6,30 +6,72 @@ use self::completer::ReplCompleter;
 use self::highlighter::ReplHighlighter;
 use self::prompt::ReplPrompt;
 
-use crate::client::{ensure_model_capabilities, init_client, send_stream};
-use crate::config::{GlobalConfig, Input, InputContext, State};
+use crate::client::{
+    ask_once, ensure_model_capabilities, init_client, ping, send_stream, ClientError,
+};
+use crate::config::{
+    Config, CtrlDAction, EmptyInputAction, GlobalConfig, Input, InputContext, State, SubmitKey,
+    TurnSeparator,
+};
 use crate::render::render_error;
-use crate::utils::{create_abort_signal, set_text, AbortSignal};
+use crate::utils::{
+    count_tokens, create_abort_signal, extract_code_blocks, get_env_name, page_text, prompt_width,
+    run_command_with_envs, run_spinner, set_text, tokenize, watch_abort, AbortSignal,
+};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use crossterm::{
+    cursor,
+    event::{self, Event},
+    style::Print,
+    terminal::{self, disable_raw_mode, enable_raw_mode},
+    QueueableCommand,
+};
 use fancy_regex::Regex;
+use inquire::Confirm;
 use lazy_static::lazy_static;
-use nu_ansi_term::Color;
+use nu_ansi_term::{Color, Style};
+use parking_lot::Mutex;
 use reedline::{
     default_emacs_keybindings, default_vi_insert_keybindings, default_vi_normal_keybindings,
     ColumnarMenu, EditCommand, EditMode, Emacs, KeyCode, KeyModifiers, Keybindings, Reedline,
     ReedlineEvent, ReedlineMenu, ValidationResult, Validator, Vi,
 };
-use reedline::{MenuBuilder, Signal};
-use std::{env, process};
+use reedline::{MenuBuilder, Prompt, Signal};
+use std::{
+    env,
+    io::{stdout, Write},
+    path::Path,
+    process,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{sync::oneshot, task::JoinHandle};
+use unicode_width::UnicodeWidthStr;
 
 const MENU_NAME: &str = "completion_menu";
+/// Sent by the Alt+Y keybinding to insert a code block of the last reply into the line
+const INSERT_CODE_BLOCK: &str = "__insert_code_block__";
+/// Sent by the `swap_models_key` keybinding to swap between the `swap_models`
+const SWAP_MODEL: &str = "__swap_model__";
 
 lazy_static! {
-    static ref REPL_COMMANDS: [ReplCommand; 16] = [
+    static ref REPL_COMMANDS: [ReplCommand; 40] = [
         ReplCommand::new(".help", "Show this help message", State::all()),
         ReplCommand::new(".info", "View system info", State::all()),
+        ReplCommand::new(".info model", "View model info", State::all()),
+        ReplCommand::new(".info paths", "View the config paths in use", State::all()),
+        ReplCommand::new(
+            ".info params",
+            "View the parameters of the next request",
+            State::all()
+        ),
         ReplCommand::new(".model", "Change the current LLM", State::all()),
+        ReplCommand::new(
+            ".model info",
+            "View a model's info without switching to it",
+            State::all()
+        ),
         ReplCommand::new(
             ".prompt",
             "Make a temporary role using a prompt",
@@ -40,10 +82,42 @@ lazy_static! {
             "Switch to a specific role",
             State::able_change_role()
         ),
+        ReplCommand::new(".role list", "List the roles", State::all()),
+        ReplCommand::new(".role clone", "Copy a role to a new name", State::all()),
         ReplCommand::new(".info role", "View role info", State::in_role(),),
         ReplCommand::new(".exit role", "Leave the role", State::in_role(),),
         ReplCommand::new(".session", "Begin a chat session", State::not_in_session(),),
         ReplCommand::new(".info session", "View session info", State::in_session(),),
+        ReplCommand::new(
+            ".session stats",
+            "Summarize the saved sessions",
+            State::all()
+        ),
+        ReplCommand::new(
+            ".session merge",
+            "Merge another session into the current one",
+            State::in_session(),
+        ),
+        ReplCommand::new(
+            ".session autocompact",
+            "Set the compression policy of the current session",
+            State::in_session(),
+        ),
+        ReplCommand::new(
+            ".system",
+            "Add a system message to the current session",
+            State::in_session(),
+        ),
+        ReplCommand::new(
+            ".note",
+            "Add a note to the session, not sent to the model",
+            State::in_session(),
+        ),
+        ReplCommand::new(
+            ".export ansi",
+            "Write the rendered session with ANSI escapes to a file",
+            State::in_session(),
+        ),
         ReplCommand::new(
             ".save session",
             "Save the chat to file",
@@ -51,7 +125,7 @@ lazy_static! {
         ),
         ReplCommand::new(
             ".clear messages",
-            "Erase messages in the current session",
+            "Erase messages in the current session, keeping the role",
             State::unable_change_role()
         ),
         ReplCommand::new(
@@ -60,8 +134,60 @@ lazy_static! {
             State::in_session(),
         ),
         ReplCommand::new(".file", "Include files with the message", State::all()),
+        ReplCommand::new(
+            ".file add",
+            "Stage files for all following messages",
+            State::all()
+        ),
+        ReplCommand::new(".file clear", "Clear the staged files", State::all()),
+        ReplCommand::new(
+            ".send",
+            "Send text as a message, even if it starts with a dot",
+            State::all()
+        ),
         ReplCommand::new(".set", "Adjust settings", State::all()),
+        ReplCommand::new(
+            ".tokenize",
+            "Show how text or a file is split into tokens",
+            State::all()
+        ),
+        ReplCommand::new(
+            ".ping",
+            "Check that the API key and endpoint work",
+            State::all()
+        ),
+        ReplCommand::new(
+            ".seed",
+            "Set the seed for reproducible outputs",
+            State::all()
+        ),
+        ReplCommand::new(
+            ".verbose",
+            "Toggle printing request details after each reply",
+            State::all()
+        ),
+        ReplCommand::new(
+            ".continue",
+            "Continue the last response if it was cut off",
+            State::all()
+        ),
         ReplCommand::new(".copy", "Copy the last response", State::all()),
+        ReplCommand::new(".copy list", "List the recent responses", State::all()),
+        ReplCommand::new(
+            ".copy session",
+            "Copy the session as a transcript for another chat",
+            State::in_session()
+        ),
+        ReplCommand::new(
+            ".full",
+            "Show the last cut short output in full",
+            State::all()
+        ),
+        ReplCommand::new(
+            ".macro",
+            "Record or replay a sequence of commands",
+            State::all()
+        ),
         ReplCommand::new(".exit", "Exit the REPL", State::all()),
     ];
     static ref COMMAND_RE: Regex = Regex::new(r"^\s*(\.\S*)\s*").unwrap();
@@ -73,6 +199,10 @@ pub struct Repl {
     editor: Reedline,
     prompt: ReplPrompt,
     abort: AbortSignal,
+    compression: Mutex<Option<(AbortSignal, JoinHandle<Result<()>>)>>,
+    /// When the REPL started waiting at the prompt, None while a line is being handled
+    idle_since: Arc<Mutex<Option<Instant>>>,
+    inserted_code_block: Option<(usize, String)>,
 }
 
 impl Repl {
@@ -88,29 +218,48 @@ impl Repl {
             editor,
             prompt,
             abort,
+            compression: Mutex::new(None),
+            idle_since: Arc::new(Mutex::new(None)),
+            inserted_code_block: None,
         })
     }
 
     pub async fn run(&mut self) -> Result<()> {
         self.banner();
 
+        let mut exit = self.run_startup_file().await;
         loop {
+            if exit {
+                break;
+            }
             if self.abort.aborted_ctrld() {
                 break;
             }
+            self.report_compression().await;
+            *self.idle_since.lock() = Some(Instant::now());
             let sig = self.editor.read_line(&self.prompt);
+            *self.idle_since.lock() = None;
             match sig {
+                Ok(Signal::Success(line)) if line == INSERT_CODE_BLOCK => {
+                    self.insert_code_block();
+                }
+                Ok(Signal::Success(line)) if line == SWAP_MODEL => {
+                    self.swap_model();
+                }
                 Ok(Signal::Success(line)) => {
                     self.abort.reset();
                     match self.handle(&line).await {
-                        Ok(exit) => {
-                            if exit {
-                                break;
+                        Ok(value) => {
+                            exit = value;
+                            if !exit {
+                                self.config.write().record_macro_step(&line);
                             }
                         }
                         Err(err) => {
-                            render_error(err, self.config.read().highlight);
-                            println!()
+                            render_error(err, self.config.read().error_color());
+                            if !self.config.read().quiet {
+                                println!()
+                            }
                         }
                     }
                 }
@@ -125,10 +274,131 @@ impl Repl {
                 _ => {}
             }
         }
-        self.handle(".exit session").await?;
+        self.cancel_compression().await;
+        let session = self
+            .config
+            .read()
+            .session
+            .as_ref()
+            .map(|v| v.name().to_string());
+        self.config.write().end_session_on_exit()?;
+        self.run_on_exit(session);
         Ok(())
     }
 
+    /// Run the REPL commands of `startup_file` one by one, reporting errors without stopping
+    ///
+    /// Returns true if one of them exits the REPL
+    async fn run_startup_file(&mut self) -> bool {
+        let Some(path) = self.config.read().startup_file.clone() else {
+            return false;
+        };
+        let path = Path::new(&path);
+        if !path.exists() {
+            return false;
+        }
+        let content = match std::fs::read_to_string(path) {
+            Ok(v) => v,
+            Err(err) => {
+                let err = anyhow!(err).context(format!("Failed to read {}", path.display()));
+                render_error(err, self.config.read().error_color());
+                return false;
+            }
+        };
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            println!("{}{line}", self.config.read().render_prompt_left());
+            match self.handle(line).await {
+                Ok(true) => return true,
+                Ok(false) => {}
+                Err(err) => render_error(err, self.config.read().error_color()),
+            }
+        }
+        false
+    }
+
+    /// Run the `on_exit` command, a failure only prints a warning
+    fn run_on_exit(&self, session: Option<String>) {
+        let Some(command) = self.config.read().on_exit.clone() else {
+            return;
+        };
+        let mut envs = vec![];
+        if let Some(name) = session.filter(|v| !v.is_empty()) {
+            if let Ok(path) = Config::session_file(&name) {
+                if path.exists() {
+                    envs.push((get_env_name("session_file"), path.display().to_string()));
+                }
+            }
+            envs.push((get_env_name("session"), name));
+        }
+        match run_command_with_envs(&command, &envs) {
+            Ok(0) => {}
+            Ok(code) => eprintln!("⚠️ The on_exit command exited with {code}"),
+            Err(err) => eprintln!("⚠️ Failed to run the on_exit command: {err}"),
+        }
+    }
+
+    /// Insert the first code block of the last reply, or replace the inserted one with the next
+    fn insert_code_block(&mut self) {
+        let blocks = match &self.config.read().last_message {
+            Some((_, output)) => extract_code_blocks(output),
+            None => vec![],
+        };
+        if blocks.is_empty() {
+            return;
+        }
+        let index = match &self.inserted_code_block {
+            Some((index, block)) if self.editor.current_buffer_contents() == block => {
+                self.editor.run_edit_commands(&[EditCommand::Clear]);
+                (index + 1) % blocks.len()
+            }
+            _ => 0,
+        };
+        self.editor
+            .run_edit_commands(&[EditCommand::InsertString(blocks[index].clone())]);
+        self.inserted_code_block = Some((index, blocks[index].clone()));
+    }
+
+    /// Swap the model without leaving the line, the new model is printed above the prompt
+    fn swap_model(&mut self) {
+        let rows = self.input_rows_above_cursor();
+        let text = match self.config.write().swap_model() {
+            Ok(model) => format!("Switched to {model}"),
+            Err(err) => {
+                let err = format!("{err}");
+                match self.config.read().error_color() {
+                    Some(color) => Style::new().fg(color).paint(err).to_string(),
+                    None => err,
+                }
+            }
+        };
+        if let Err(err) = print_above_prompt(&text, rows) {
+            debug!("Failed to print above the prompt: {err}");
+        }
+    }
+
+    /// Rows between the first line of the prompt and the cursor, wrapped lines included
+    fn input_rows_above_cursor(&self) -> u16 {
+        let columns = terminal::size()
+            .map(|(v, _)| v.max(1) as usize)
+            .unwrap_or(80);
+        let prompt_width = prompt_width(&self.prompt.render_prompt_left());
+        let buffer = self.editor.current_buffer_contents();
+        let before = &buffer[..self.editor.current_insertion_point()];
+        let rows: usize = before
+            .split('\n')
+            .enumerate()
+            .map(|(i, line)| {
+                let width = if i == 0 { prompt_width } else { 0 } + line.width();
+                width / columns + 1
+            })
+            .sum();
+        (rows - 1) as u16
+    }
+
     async fn handle(&self, mut line: &str) -> Result<bool> {
         if let Ok(Some(captures)) = MULTILINE_RE.captures(line) {
             if let Some(text_match) = captures.get(1) {
@@ -149,6 +419,18 @@ impl Repl {
                         let info = self.config.read().session_info()?;
                         println!("{}", info);
                     }
+                    Some("model") => {
+                        let info = self.config.read().model_info()?;
+                        println!("{}", info);
+                    }
+                    Some("paths") => {
+                        let info = self.config.read().paths_info()?;
+                        println!("{}", info);
+                    }
+                    Some("params") => {
+                        let info = self.config.read().params_info()?;
+                        println!("{}", info);
+                    }
                     Some(_) => unknown_command()?,
                     None => {
                         let output = self.config.read().system_info()?;
@@ -156,8 +438,18 @@ impl Repl {
                     }
                 },
                 ".model" => match args {
+                    Some(args) if args == "info" || args.starts_with("info ") => {
+                        let name = args["info".len()..].trim();
+                        if name.is_empty() {
+                            println!("Usage: .model info <name>");
+                        } else {
+                            let info = self.config.read().model_info_of(name)?;
+                            println!("{info}");
+                        }
+                    }
                     Some(name) => {
                         self.config.write().set_model(name)?;
+                        self.config.read().remember_project_model()?;
                     }
                     None => println!("Usage: .model <name>"),
                 },
@@ -168,22 +460,79 @@ impl Repl {
                     None => println!("Usage: .prompt <text>..."),
                 },
                 ".role" => match args {
-                    Some(args) => match args.split_once(|c| c == '\n' || c == ' ') {
+                    Some(args) if args == "list" || args.starts_with("list ") => {
+                        let filter = args["list".len()..].trim();
+                        let filter = filter.strip_prefix("--filter").unwrap_or(filter).trim();
+                        let roles = self.config.read().list_roles(filter);
+                        if roles.is_empty() {
+                            println!("No roles found");
+                        } else {
+                            println!("{roles}");
+                        }
+                    }
+                    Some(args) if args == "clone" || args.starts_with("clone ") => {
+                        let args = args["clone".len()..].trim();
+                        let (args, force) = match args.strip_suffix("--force") {
+                            Some(args) => (args.trim(), true),
+                            None => (args, false),
+                        };
+                        match args.split_whitespace().collect::<Vec<_>>().as_slice() {
+                            [src, dst] => self.config.write().clone_role(src, dst, force)?,
+                            _ => println!("Usage: .role clone <src> <dst> [--force]"),
+                        }
+                    }
+                    Some(args) => match args.split_once(['\n', ' ']) {
                         Some((name, text)) => {
                             let role = self.config.read().retrieve_role(name.trim())?;
-                            let input =
-                                Input::from_str(text.trim(), InputContext::new(Some(role), false));
+                            let input = match split_role_files(text.trim())? {
+                                Some((files, text)) => {
+                                    self.config.read().new_role_input(role, text, files)?
+                                }
+                                None => Input::from_str(
+                                    text.trim(),
+                                    InputContext::new(Some(role), false),
+                                ),
+                            };
                             self.ask(input).await?;
                         }
                         None => {
                             self.config.write().set_role(args)?;
                         }
                     },
-                    None => println!(r#"Usage: .role <name> [text]..."#),
+                    None => println!(
+                        r#"Usage: .role <name> [text]... | .role <name> -f <files>... [-- <text>...]"#
+                    ),
+                },
+                ".session" => match args.map(|v| match v.split_once(' ') {
+                    Some((subcmd, args)) => (subcmd, args.trim()),
+                    None => (v, ""),
+                }) {
+                    Some(("merge", args)) => {
+                        let (name, before_if_older) = match args.strip_suffix("--before-if-older") {
+                            Some(name) => (name.trim(), true),
+                            None => (args, false),
+                        };
+                        if name.is_empty() {
+                            println!("Usage: .session merge <name> [--before-if-older]");
+                        } else {
+                            self.config.write().merge_session(name, before_if_older)?;
+                        }
+                    }
+                    Some(("stats", "")) => {
+                        let stats = self.config.read().session_stats()?;
+                        println!("{stats}");
+                    }
+                    Some(("autocompact", args)) => {
+                        if args.is_empty() {
+                            println!("Usage: .session autocompact <threshold> [keep_last] | null");
+                        } else {
+                            self.config.write().set_session_autocompact(args)?;
+                        }
+                    }
+                    _ => {
+                        self.config.write().start_session(args)?;
+                    }
                 },
-                ".session" => {
-                    self.config.write().start_session(args)?;
-                }
                 ".save" => {
                     match args.map(|v| match v.split_once(' ') {
                         Some((subcmd, args)) => (subcmd, args.trim()),
@@ -200,33 +549,156 @@ impl Repl {
                 ".set" => match args {
                     Some(args) => {
                         self.config.write().update(args)?;
+                        if args.starts_with("compress_threshold ") {
+                            self.maybe_compress_session();
+                        }
                     }
                     _ => {
                         println!("Usage: .set <key> <value>...")
                     }
                 },
-                ".copy" => {
-                    let config = self.config.read();
-                    self.copy(config.last_reply())
-                        .with_context(|| "Failed to copy the last output")?;
+                ".seed" => match args {
+                    Some(value) => {
+                        let value = if value == "off" { "null" } else { value };
+                        let mut config = self.config.write();
+                        config.update(&format!("seed {value}"))?;
+                        match config.seed {
+                            Some(seed) => println!("Seed is {seed}"),
+                            None => println!("Seed is off"),
+                        }
+                    }
+                    None => println!("Usage: .seed <n>|off"),
+                },
+                ".verbose" => {
+                    let mut config = self.config.write();
+                    config.verbose = !config.verbose;
+                    let state = if config.verbose { "on" } else { "off" };
+                    println!("Verbose mode is {state}");
                 }
-                ".file" => match args {
-                    Some(args) => {
+                ".continue" => {
+                    let (input, output) = match self.config.read().last_message.clone() {
+                        Some(v) => v,
+                        None => bail!("No reply to continue"),
+                    };
+                    if self.config.read().last_reply_truncated {
+                        self.ask(input.continue_reply(&output)).await?;
+                    } else {
+                        println!("The last reply finished normally, nothing to continue");
+                    }
+                }
+                ".copy" => match args {
+                    None => {
+                        let config = self.config.read();
+                        self.copy(config.last_reply())
+                            .with_context(|| "Failed to copy the last output")?;
+                    }
+                    Some("list") => {
+                        let replies = self.config.write().list_replies();
+                        if replies.is_empty() {
+                            println!("No replies to copy");
+                        } else {
+                            println!("{replies}");
+                        }
+                    }
+                    Some("session") => {
+                        let transcript = self.config.read().session_transcript()?;
+                        self.copy(&transcript)
+                            .with_context(|| "Failed to copy the session")?;
+                    }
+                    Some(index) => match index.parse::<usize>() {
+                        Ok(index) => {
+                            let config = self.config.read();
+                            self.copy(config.nth_reply(index)?)
+                                .with_context(|| format!("Failed to copy the reply #{index}"))?;
+                        }
+                        Err(_) => println!("Usage: .copy [<n>|list|session]"),
+                    },
+                },
+                ".full" => {
+                    let text = self.config.read().full_output().to_string();
+                    if text.is_empty() {
+                        bail!("No output to show");
+                    }
+                    match args {
+                        None => println!("{text}"),
+                        Some("pager") => {
+                            if !page_text(&text)? {
+                                println!("{text}");
+                            }
+                        }
+                        Some(_) => println!("Usage: .full [pager]"),
+                    }
+                }
+                ".macro" => match args.map(|v| match v.split_once(' ') {
+                    Some((subcmd, args)) => (subcmd, args.trim()),
+                    None => (v, ""),
+                }) {
+                    Some(("record", name)) if !name.is_empty() => {
+                        self.config.write().start_recording_macro(name)?;
+                    }
+                    Some(("stop", _)) => {
+                        self.config.write().stop_recording_macro()?;
+                    }
+                    Some(("run", name)) if !name.is_empty() => {
+                        let steps = self.config.read().get_macro(name)?;
+                        for (i, step) in steps.iter().enumerate() {
+                            println!("{}{step}", self.config.read().render_prompt_left());
+                            let exit = Box::pin(self.handle(step)).await.with_context(|| {
+                                format!("Macro '{name}' stopped at step {}", i + 1)
+                            })?;
+                            if exit {
+                                return Ok(true);
+                            }
+                            if self.abort.aborted() {
+                                println!("Macro '{name}' aborted at step {}", i + 1);
+                                break;
+                            }
+                        }
+                    }
+                    _ => println!("Usage: .macro record <name> | .macro stop | .macro run <name>"),
+                },
+                ".file" => match args.map(|v| match v.split_once(' ') {
+                    Some((subcmd, args)) => (subcmd, args.trim()),
+                    None => (v, ""),
+                }) {
+                    Some(("add", files)) => {
+                        if files.is_empty() {
+                            println!("Usage: .file add <files>...");
+                        } else {
+                            let files =
+                                shell_words::split(files).with_context(|| "Invalid args")?;
+                            self.config.write().stage_files(files)?;
+                        }
+                    }
+                    Some(("clear", "")) => {
+                        self.config.write().clear_staged_files();
+                    }
+                    Some(_) => {
+                        let args = args.unwrap_or_default();
                         let (files, text) = match args.split_once(" -- ") {
                             Some((files, text)) => (files.trim(), text.trim()),
                             None => (args, ""),
                         };
                         let files = shell_words::split(files).with_context(|| "Invalid args")?;
-                        let input = Input::new(text, files, self.config.read().input_context())?;
+                        let input = self.config.read().new_input(text, files)?;
                         self.ask(input).await?;
                     }
-                    None => println!("Usage: .file <files>... [-- <text>...]"),
+                    None => {
+                        let staged_files = self.config.read().staged_files.clone();
+                        if !staged_files.is_empty() {
+                            println!("Staged files: {}", staged_files.join(" "));
+                        }
+                        println!(
+                            "Usage: .file <files>... [-- <text>...] | .file add <files>... | .file clear"
+                        )
+                    }
                 },
                 ".exit" => match args {
                     Some("role") => {
                         self.config.write().clear_role()?;
                     }
                     Some("session") => {
+                        self.cancel_compression().await;
                         self.config.write().end_session()?;
                     }
                     Some(_) => unknown_command()?,
@@ -236,36 +708,229 @@ impl Repl {
                 },
                 ".clear" => match args {
                     Some("messages") => {
-                        self.config.write().clear_session_messages()?;
+                        self.config.write().clear_session_messages(false)?;
+                    }
+                    Some("messages --all") => {
+                        self.config.write().clear_session_messages(true)?;
                     }
                     _ => unknown_command()?,
                 },
+                ".send" => match args {
+                    Some(text) => self.send_message(text).await?,
+                    None => println!("Usage: .send <text>..."),
+                },
+                ".system" => match args {
+                    Some(text) => self.config.write().add_system_message(text)?,
+                    None => println!("Usage: .system <text>..."),
+                },
+                ".note" => match args {
+                    Some(text) => self.config.write().add_session_note(text)?,
+                    None => println!("Usage: .note <text>..."),
+                },
+                ".export" => match args.and_then(|v| v.split_once(' ')) {
+                    Some(("ansi", path)) => {
+                        let path = path.trim();
+                        self.config.read().export_session_ansi(path)?;
+                        println!("✓ Exported the session to '{path}', it contains ANSI escape sequences, view it with `cat`");
+                    }
+                    _ => println!("Usage: .export ansi <path>"),
+                },
+                ".ping" => self.ping().await?,
+                ".tokenize" => match args {
+                    Some(args) => self.tokenize(args)?,
+                    None => println!("Usage: .tokenize <text|file>"),
+                },
                 _ => unknown_command()?,
             },
+            None if line.trim().is_empty() => {
+                let action = self.config.read().empty_input;
+                match action {
+                    EmptyInputAction::Ignore => {}
+                    EmptyInputAction::Regenerate => {
+                        let input = self
+                            .config
+                            .read()
+                            .last_message
+                            .as_ref()
+                            .map(|(input, _)| input.clone());
+                        match input {
+                            Some(input) => self.ask(input).await?,
+                            None => println!("No previous message to regenerate"),
+                        }
+                    }
+                    EmptyInputAction::Hint => {
+                        println!(r#"Type a message to chat, or ".help" for more information."#)
+                    }
+                }
+            }
             None => {
-                let input = Input::from_str(line, self.config.read().input_context());
-                self.ask(input).await?;
+                let line = unescape_dot(line);
+                let delimiter = self.config.read().batch_delimiter.clone();
+                match split_batch(line, delimiter.as_deref()) {
+                    Some(prompts) => self.send_batch(&prompts).await?,
+                    None => self.send_message(line).await?,
+                }
             }
         }
 
-        println!();
+        if !self.config.read().quiet {
+            println!();
+        }
 
         Ok(false)
     }
 
+    async fn send_message(&self, text: &str) -> Result<()> {
+        let text = {
+            let config = self.config.read();
+            let last_reply = config.last_message.as_ref().map(|(_, v)| v.as_str());
+            expand_last_reply(text, last_reply)?
+        };
+        if !self.confirm_input_length(&text)? {
+            return Ok(());
+        }
+        let input = self.config.read().new_input(&text, vec![])?;
+        self.ask(input).await
+    }
+
+    /// Send the prompts one by one, a Ctrl+C stops the rest of the batch
+    async fn send_batch(&self, prompts: &[&str]) -> Result<()> {
+        let color = if self.config.read().light_theme {
+            Color::LightGray
+        } else {
+            Color::DarkGray
+        };
+        for (i, prompt) in prompts.iter().enumerate() {
+            if i > 0 {
+                println!();
+            }
+            println!(
+                "{}",
+                color.paint(format!("── {}/{} ──", i + 1, prompts.len()))
+            );
+            self.send_message(prompt)
+                .await
+                .with_context(|| format!("Batch stopped at prompt {}", i + 1))?;
+            if self.abort.aborted() && i + 1 < prompts.len() {
+                println!("Batch aborted after prompt {} of {}", i + 1, prompts.len());
+                break;
+            }
+        }
+        Ok(())
+    }
+
     async fn ask(&self, input: Input) -> Result<()> {
         if input.is_empty() {
             return Ok(());
         }
-        while self.config.read().is_compressing_session() {
-            std::thread::sleep(std::time::Duration::from_millis(100));
+        if !self.wait_compression().await? {
+            println!("Cancelled while waiting for the session compression");
+            return Ok(());
         }
+        self.report_compression().await;
         self.config.read().maybe_print_send_tokens(&input);
+        // A Ctrl+C that stopped an earlier request of the same command must not stop this one
+        self.abort.reset();
         let mut client = init_client(&self.config)?;
         ensure_model_capabilities(client.as_mut(), input.required_capabilities())?;
-        let output = send_stream(&input, client.as_ref(), &self.config, self.abort.clone()).await?;
+        self.print_turn_separator();
+        let output = send_stream(&input, client.as_ref(), &self.config, self.abort.clone())
+            .await
+            .with_context(|| "Failed to get answer")?;
         self.config.write().save_message(input, &output)?;
         self.config.read().maybe_copy(&output);
+        self.maybe_compress_session();
+        Ok(())
+    }
+
+    fn print_turn_separator(&self) {
+        let config = self.config.read();
+        match config.turn_separator {
+            TurnSeparator::None => {}
+            TurnSeparator::Blank => println!(),
+            TurnSeparator::Rule => {
+                let columns = terminal::size().map(|v| v.0).unwrap_or(80);
+                let width = match config.wrap.as_deref().map(|v| v.parse::<u16>()) {
+                    Some(Ok(wrap)) => columns.min(wrap),
+                    _ => columns,
+                };
+                let rule = "─".repeat(width as usize);
+                match (config.highlight, config.light_theme) {
+                    (false, _) => println!("{rule}"),
+                    (true, true) => println!("{}", Color::LightGray.paint(rule)),
+                    (true, false) => println!("{}", Color::DarkGray.paint(rule)),
+                }
+            }
+        }
+    }
+
+    /// Send a tiny request to the current model and tell auth failures apart from network ones
+    async fn ping(&self) -> Result<()> {
+        if self.config.read().dry_run {
+            println!("Nothing was sent because dry_run is on");
+            return Ok(());
+        }
+        self.config.read().guard_online()?;
+        let model = self.config.read().model.id();
+        match ping(&self.config).await {
+            Ok(latency) => println!("✓ {model} replied in {} ms", latency.as_millis()),
+            Err(ClientError::Auth(message)) => {
+                bail!("Authentication failed for {model}, check the api_key: {message}")
+            }
+            Err(ClientError::Network(err)) => {
+                bail!("Cannot reach {model}, check the api_base and proxy: {err}")
+            }
+            Err(ClientError::RateLimited(message)) => {
+                println!("✓ {model} is reachable and the API key is accepted, but rate limited: {message}")
+            }
+            Err(ClientError::Api {
+                status, message, ..
+            }) => bail!("{model} returned an error (status {status}): {message}"),
+            Err(ClientError::Other(err)) => {
+                return Err(err.context(format!("Failed to ping {model}")))
+            }
+        }
+        Ok(())
+    }
+
+    /// Print the token count of the text, or of the file it names, and where the tokens split
+    fn tokenize(&self, args: &str) -> Result<()> {
+        let path = Path::new(args);
+        let text = if path.is_file() {
+            std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read '{}'", path.display()))?
+        } else {
+            args.to_string()
+        };
+        let colors = if self.config.read().light_theme {
+            [Color::LightCyan, Color::LightYellow]
+        } else {
+            [Color::Blue, Color::Magenta]
+        };
+        let output: String = tokenize(&text)
+            .iter()
+            .enumerate()
+            .map(|(i, token)| {
+                Style::new()
+                    .on(colors[i % 2])
+                    .paint(token.replace('\n', "\\n\n"))
+                    .to_string()
+            })
+            .collect();
+        println!("{output}");
+        println!("{} tokens", count_tokens(&text));
+        Ok(())
+    }
+
+    /// Start compressing the session in the background if it exceeds the compression threshold
+    ///
+    /// With `compress_idle` set, the compression waits until the REPL has been idle at the prompt for that long
+    fn maybe_compress_session(&self) {
+        let compress_idle = self.config.read().compress_idle;
+        if let Some(idle) = compress_idle.filter(|v| *v > 0) {
+            self.defer_compress_session(Duration::from_secs(idle));
+            return;
+        }
         if self.config.write().should_compress_session() {
             let config = self.config.clone();
             let color = if config.read().light_theme {
@@ -281,15 +946,164 @@ impl Repl {
                 color.italic().paint("compress_threshold"),
                 color.normal().paint("`."),
             );
-            tokio::spawn(async move {
-                let _ = compress_session(&config).await;
+            let abort = create_abort_signal();
+            let task_abort = abort.clone();
+            let handle = tokio::spawn(async move {
+                let ret = tokio::select! {
+                    ret = compress_session_with_retry(&config) => ret,
+                    _ = watch_abort(task_abort) => Ok(()),
+                };
                 config.write().end_compressing_session();
+                ret
             });
+            *self.compression.lock() = Some((abort, handle));
         }
-        Ok(())
+    }
+
+    fn defer_compress_session(&self, idle: Duration) {
+        if self.compression.lock().is_some() || !self.config.read().need_compress_session() {
+            return;
+        }
+        let config = self.config.clone();
+        let color = if config.read().light_theme {
+            Color::LightGray
+        } else {
+            Color::DarkGray
+        };
+        print!(
+            "\n📢 {}{}{}\n",
+            color.normal().paint(
+                "Session compression will start once the REPL is idle, as the current tokens exceed `"
+            ),
+            color.italic().paint("compress_threshold"),
+            color.normal().paint("`."),
+        );
+        let idle_since = self.idle_since.clone();
+        let abort = create_abort_signal();
+        let task_abort = abort.clone();
+        let handle = tokio::spawn(async move {
+            let ret = tokio::select! {
+                ret = async {
+                    wait_idle(&idle_since, idle).await;
+                    if !config.write().should_compress_session() {
+                        return Ok(());
+                    }
+                    compress_session_with_retry(&config).await
+                } => ret,
+                _ = watch_abort(task_abort) => Ok(()),
+            };
+            config.write().end_compressing_session();
+            ret
+        });
+        *self.compression.lock() = Some((abort, handle));
+    }
+
+    /// Wait for the background compression to finish, returns false if Ctrl+C or Ctrl+D cancelled the wait
+    async fn wait_compression(&self) -> Result<bool> {
+        if !self.config.read().is_compressing_session() {
+            return Ok(true);
+        }
+        let (spinner_tx, spinner_rx) = oneshot::channel();
+        if !self.config.read().quiet {
+            tokio::spawn(run_spinner(
+                " Waiting for the session compression",
+                spinner_rx,
+            ));
+        }
+        enable_raw_mode()?;
+        let ret = self.wait_compression_inner();
+        disable_raw_mode()?;
+        let _ = spinner_tx.send(());
+        ret
+    }
+
+    fn wait_compression_inner(&self) -> Result<bool> {
+        while self.config.read().is_compressing_session() {
+            if !event::poll(Duration::from_millis(100))? {
+                continue;
+            }
+            if let Event::Key(key) = event::read()? {
+                match key.code {
+                    event::KeyCode::Char('c') if key.modifiers == event::KeyModifiers::CONTROL => {
+                        self.abort.set_ctrlc();
+                        return Ok(false);
+                    }
+                    event::KeyCode::Char('d') if key.modifiers == event::KeyModifiers::CONTROL => {
+                        self.abort.set_ctrld();
+                        return Ok(false);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Tell the user if the background compression has failed, the session then stays uncompressed
+    async fn report_compression(&self) {
+        let handle = {
+            let mut compression = self.compression.lock();
+            match compression.take() {
+                Some((_, handle)) if handle.is_finished() => handle,
+                other => {
+                    *compression = other;
+                    return;
+                }
+            }
+        };
+        if let Ok(Err(err)) = handle.await {
+            let err = err.context("Session compression failed, the session is left uncompressed");
+            render_error(err, self.config.read().error_color());
+        }
+    }
+
+    /// Stop the background compression and wait briefly for it, so the session isn't written afterwards
+    async fn cancel_compression(&self) {
+        let Some((abort, handle)) = self.compression.lock().take() else {
+            return;
+        };
+        if handle.is_finished() {
+            return;
+        }
+        abort.set_ctrlc();
+        let abort_handle = handle.abort_handle();
+        if tokio::time::timeout(Duration::from_secs(2), handle)
+            .await
+            .is_err()
+        {
+            abort_handle.abort();
+        }
+        self.config.write().end_compressing_session();
+    }
+
+    fn confirm_input_length(&self, line: &str) -> Result<bool> {
+        let max_input_chars = match self.config.read().max_input_chars {
+            Some(v) if v > 0 => v,
+            _ => return Ok(true),
+        };
+        let chars = line.chars().count();
+        if chars <= max_input_chars {
+            return Ok(true);
+        }
+        let ans = Confirm::new(&format!(
+            "The input has {chars} characters, exceeding `max_input_chars` ({max_input_chars}). Send it anyway?"
+        ))
+        .with_default(false)
+        .prompt()?;
+        Ok(ans)
     }
 
     fn banner(&self) {
+        let config = self.config.read();
+        if config.quiet {
+            return;
+        }
+        if let Some(text) = &config.banner_text {
+            if !text.is_empty() {
+                println!("{}", text.trim_end());
+            }
+            return;
+        }
         let version = env!("CARGO_PKG_VERSION");
         print!(
             r#"Welcome to aichat {version}
@@ -314,17 +1128,36 @@ Type ".help" for more information.
             .with_validator(Box::new(ReplValidator))
             .with_ansi_colors(true);
 
-        if let Some(cmd) = config.read().buffer_editor() {
+        let swap_models_key = config.read().swap_models_key.clone();
+        if !config.read().swap_models.is_empty() && parse_key(&swap_models_key).is_none() {
+            eprintln!("Warning: invalid swap_models_key '{swap_models_key}', expected a key such as alt-m, ctrl-t or f2");
+        }
+
+        let (buffer_editor, warning) = config.read().buffer_editor();
+        if let Some(warning) = warning {
+            eprintln!("Warning: {warning}");
+        }
+        if let Some(args) = buffer_editor {
             let temp_file =
                 env::temp_dir().join(format!("aichat-{}.txt", chrono::Utc::now().timestamp()));
-            let command = process::Command::new(cmd);
+            let mut command = process::Command::new(&args[0]);
+            command.args(&args[1..]);
             editor = editor.with_buffer_editor(command, temp_file);
         }
 
         Ok(editor)
     }
 
-    fn extra_keybindings(keybindings: &mut Keybindings) {
+    fn extra_keybindings(config: &GlobalConfig, keybindings: &mut Keybindings) {
+        if !config.read().swap_models.is_empty() {
+            if let Some((modifiers, code)) = parse_key(&config.read().swap_models_key) {
+                keybindings.add_binding(
+                    modifiers,
+                    code,
+                    ReedlineEvent::ExecuteHostCommand(SWAP_MODEL.into()),
+                );
+            }
+        }
         keybindings.add_binding(
             KeyModifiers::NONE,
             KeyCode::Tab,
@@ -338,23 +1171,61 @@ Type ".help" for more information.
             KeyCode::BackTab,
             ReedlineEvent::MenuPrevious,
         );
+        let newline = ReedlineEvent::Edit(vec![EditCommand::InsertNewline]);
+        match config.read().submit_key {
+            SubmitKey::Enter => {
+                keybindings.add_binding(KeyModifiers::CONTROL, KeyCode::Enter, newline);
+            }
+            SubmitKey::AltEnter => {
+                keybindings.add_binding(KeyModifiers::NONE, KeyCode::Enter, newline);
+                keybindings.add_binding(KeyModifiers::ALT, KeyCode::Enter, ReedlineEvent::Submit);
+            }
+            SubmitKey::CtrlEnter => {
+                keybindings.add_binding(KeyModifiers::NONE, KeyCode::Enter, newline);
+                keybindings.add_binding(
+                    KeyModifiers::CONTROL,
+                    KeyCode::Enter,
+                    ReedlineEvent::Submit,
+                );
+            }
+        }
         keybindings.add_binding(
-            KeyModifiers::CONTROL,
-            KeyCode::Enter,
-            ReedlineEvent::Edit(vec![EditCommand::InsertNewline]),
+            KeyModifiers::ALT,
+            KeyCode::Char('y'),
+            ReedlineEvent::ExecuteHostCommand(INSERT_CODE_BLOCK.into()),
         );
+        // `ReedlineEvent::CtrlD` exits on an empty line and deletes a char otherwise
+        let ctrl_d = match config.read().ctrl_d {
+            CtrlDAction::Delete => None,
+            CtrlDAction::Submit => Some(vec![
+                ReedlineEvent::Edit(vec![EditCommand::MoveToEnd { select: false }]),
+                ReedlineEvent::CtrlD,
+                ReedlineEvent::Submit,
+            ]),
+            CtrlDAction::Exit => Some(vec![
+                ReedlineEvent::Edit(vec![EditCommand::Clear]),
+                ReedlineEvent::CtrlD,
+            ]),
+        };
+        if let Some(events) = ctrl_d {
+            keybindings.add_binding(
+                KeyModifiers::CONTROL,
+                KeyCode::Char('d'),
+                ReedlineEvent::Multiple(events),
+            );
+        }
     }
 
     fn create_edit_mode(config: &GlobalConfig) -> Box<dyn EditMode> {
         let edit_mode: Box<dyn EditMode> = if config.read().keybindings.is_vi() {
             let mut normal_keybindings = default_vi_normal_keybindings();
             let mut insert_keybindings = default_vi_insert_keybindings();
-            Self::extra_keybindings(&mut normal_keybindings);
-            Self::extra_keybindings(&mut insert_keybindings);
+            Self::extra_keybindings(config, &mut normal_keybindings);
+            Self::extra_keybindings(config, &mut insert_keybindings);
             Box::new(Vi::new(insert_keybindings, normal_keybindings))
         } else {
             let mut keybindings = default_emacs_keybindings();
-            Self::extra_keybindings(&mut keybindings);
+            Self::extra_keybindings(config, &mut keybindings);
             Box::new(Emacs::new(keybindings))
         };
         edit_mode
@@ -424,6 +1295,7 @@ fn dump_repl_help() {
 
 Type ::: to start multi-line editing, type ::: to finish it.
 Press Ctrl+O to open an editor to edit line input.
+Press Alt+Y to insert a code block of the last response, press it again for the next one.
 Press Ctrl+C to cancel the response, Ctrl+D to exit the REPL"###,
     );
 }
@@ -440,22 +1312,221 @@ fn parse_command(line: &str) -> Option<(&str, Option<&str>)> {
     }
 }
 
+/// Drop the backslash of a line starting with `\.`, which escapes a message that isn't a command
+fn unescape_dot(line: &str) -> &str {
+    match line.trim_start().strip_prefix('\\') {
+        Some(text) if text.starts_with('.') => text,
+        _ => line,
+    }
+}
+
+/// Replace each `!!` that stands alone as a word with the last reply, `\!!` is kept as a literal `!!`
+fn expand_last_reply(text: &str, last_reply: Option<&str>) -> Result<String> {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    let mut prev: Option<char> = None;
+    while !rest.is_empty() {
+        let word_start = prev.is_none_or(char::is_whitespace);
+        let word_end = |v: &str| v.chars().next().is_none_or(char::is_whitespace);
+        if word_start && rest.starts_with("\\!!") && word_end(&rest[3..]) {
+            output.push_str("!!");
+            rest = &rest[3..];
+        } else if word_start && rest.starts_with("!!") && word_end(&rest[2..]) {
+            match last_reply {
+                Some(reply) => output.push_str(reply),
+                None => {
+                    bail!("No previous reply to insert for `!!`, write `\\!!` for a literal `!!`")
+                }
+            }
+            rest = &rest[2..];
+        } else {
+            let ch = rest.chars().next().unwrap();
+            output.push(ch);
+            rest = &rest[ch.len_utf8()..];
+            prev = Some(ch);
+            continue;
+        }
+        prev = Some('!');
+    }
+    Ok(output)
+}
+
+/// Split the `-f <files>... [-- <text>...]` that may follow `.role <name>`, None without `-f`
+fn split_role_files(args: &str) -> Result<Option<(Vec<String>, &str)>> {
+    let Some(args) = args
+        .strip_prefix("-f")
+        .filter(|v| v.starts_with(char::is_whitespace))
+    else {
+        return Ok(None);
+    };
+    // The text may start on the next line of a multi-line input
+    let separator = args.match_indices(" --").map(|(i, _)| i).find(|i| {
+        args[i + 3..]
+            .chars()
+            .next()
+            .is_none_or(|c| c.is_whitespace())
+    });
+    let (files, text) = match separator {
+        Some(i) => (&args[..i], args[i + 3..].trim()),
+        None => (args, ""),
+    };
+    let files = shell_words::split(files).with_context(|| "Invalid args")?;
+    if files.is_empty() {
+        bail!("No files after -f");
+    }
+    Ok(Some((files, text)))
+}
+
+/// Split the input into the prompts of a batch, None if it holds a single prompt
+fn split_batch<'a>(line: &'a str, delimiter: Option<&str>) -> Option<Vec<&'a str>> {
+    let delimiter = delimiter.filter(|v| !v.is_empty())?;
+    let prompts: Vec<&str> = line
+        .split(delimiter)
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .collect();
+    if prompts.len() > 1 {
+        Some(prompts)
+    } else {
+        None
+    }
+}
+
+/// Wait until the REPL has been waiting at the prompt for `idle`
+async fn wait_idle(idle_since: &Mutex<Option<Instant>>, idle: Duration) {
+    loop {
+        let elapsed = idle_since.lock().map(|v| v.elapsed());
+        match elapsed {
+            Some(elapsed) if elapsed >= idle => return,
+            Some(elapsed) => tokio::time::sleep(idle - elapsed).await,
+            None => tokio::time::sleep(idle).await,
+        }
+    }
+}
+
+/// Compress the session, giving each attempt `compress_timeout` seconds and retrying `compress_retries` times
+async fn compress_session_with_retry(config: &GlobalConfig) -> Result<()> {
+    let (timeout, retries) = {
+        let config = config.read();
+        (config.compress_timeout, config.compress_retries)
+    };
+    let mut attempt = 0;
+    loop {
+        let ret = match tokio::time::timeout(Duration::from_secs(timeout), compress_session(config))
+            .await
+        {
+            Ok(ret) => ret,
+            Err(_) => Err(anyhow!("Timed out after {timeout}s")),
+        };
+        match ret {
+            Err(err) if attempt < retries => {
+                debug!(
+                    "Session compression attempt {} failed: {err:?}",
+                    attempt + 1
+                );
+                attempt += 1;
+            }
+            ret => return ret,
+        }
+    }
+}
+
 async fn compress_session(config: &GlobalConfig) -> Result<()> {
     let input = Input::from_str(
         config.read().summarize_prompt(),
         config.read().input_context(),
-    );
-    let mut client = init_client(config)?;
-    ensure_model_capabilities(client.as_mut(), input.required_capabilities())?;
-    let summary = client.send_message(input).await?;
+    )
+    .summarize_session();
+    let summary = ask_once(config, input)
+        .await
+        .with_context(|| "Failed to get answer")?;
     config.write().compress_session(&summary);
     Ok(())
 }
 
+/// Parse a key such as `alt-m`, `ctrl-shift-t` or `f2`
+fn parse_key(value: &str) -> Option<(KeyModifiers, KeyCode)> {
+    let value = value.trim().to_lowercase();
+    let (modifiers, key) = match value.rsplit_once('-') {
+        Some((modifiers, key)) if !key.is_empty() => (modifiers, key),
+        _ => ("", value.as_str()),
+    };
+    let mut key_modifiers = KeyModifiers::NONE;
+    for modifier in modifiers.split('-').filter(|v| !v.is_empty()) {
+        key_modifiers |= match modifier {
+            "ctrl" | "control" => KeyModifiers::CONTROL,
+            "alt" => KeyModifiers::ALT,
+            "shift" => KeyModifiers::SHIFT,
+            _ => return None,
+        };
+    }
+    let mut chars = key.chars();
+    let code = match (chars.next(), chars.next()) {
+        (Some(ch), None) => KeyCode::Char(ch),
+        _ => match key.strip_prefix('f').and_then(|v| v.parse::<u8>().ok()) {
+            Some(n @ 1..=12) => KeyCode::F(n),
+            _ => return None,
+        },
+    };
+    Some((key_modifiers, code))
+}
+
+/// Print a line where the prompt starts and move the prompt down a row.
+/// The editor repaints the prompt in place after a host command, so anything printed below it would be cleared.
+fn print_above_prompt(text: &str, rows_above_cursor: u16) -> Result<()> {
+    let (_, row) = cursor::position()?;
+    let prompt_row = row.saturating_sub(rows_above_cursor);
+    let mut stdout = stdout();
+    stdout
+        .queue(terminal::ScrollUp(1))?
+        .queue(cursor::MoveTo(0, prompt_row.saturating_sub(1)))?
+        .queue(terminal::Clear(terminal::ClearType::CurrentLine))?
+        .queue(Print(text))?;
+    stdout.flush()?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(
+            parse_key("alt-m"),
+            Some((KeyModifiers::ALT, KeyCode::Char('m')))
+        );
+        assert_eq!(
+            parse_key("Ctrl-Shift-T"),
+            Some((
+                KeyModifiers::CONTROL | KeyModifiers::SHIFT,
+                KeyCode::Char('t')
+            ))
+        );
+        assert_eq!(parse_key("f2"), Some((KeyModifiers::NONE, KeyCode::F(2))));
+        assert_eq!(parse_key("hyper-m"), None);
+        assert_eq!(parse_key("alt-f13"), None);
+    }
+
+    #[test]
+    fn test_split_role_files() {
+        assert_eq!(split_role_files("fix this").unwrap(), None);
+        assert_eq!(split_role_files("-fix this").unwrap(), None);
+        assert_eq!(
+            split_role_files("-f main.rs 'a b.rs' -- fix --all of it").unwrap(),
+            Some((vec!["main.rs".into(), "a b.rs".into()], "fix --all of it"))
+        );
+        assert_eq!(
+            split_role_files("-f main.rs --\nfix this").unwrap(),
+            Some((vec!["main.rs".into()], "fix this"))
+        );
+        assert_eq!(
+            split_role_files("-f main.rs").unwrap(),
+            Some((vec!["main.rs".into()], ""))
+        );
+        assert!(split_role_files("-f -- fix").is_err());
+    }
+
     #[test]
     fn test_process_command_line() {
         assert_eq!(parse_command(" ."), Some((".", None)));
@@ -473,5 +1544,37 @@ mod tests {
             parse_command(".prompt \nabc\n"),
             Some((".prompt", Some("abc")))
         );
+        assert_eq!(parse_command(".send .foo"), Some((".send", Some(".foo"))));
+        assert_eq!(parse_command("\\.foo"), None);
+        assert_eq!(unescape_dot("\\.foo"), ".foo");
+        assert_eq!(unescape_dot("\\foo"), "\\foo");
+    }
+
+    #[test]
+    fn test_expand_last_reply() {
+        let reply = Some("error: boom");
+        assert_eq!(
+            expand_last_reply("why !!\nfix it", reply).unwrap(),
+            "why error: boom\nfix it"
+        );
+        assert_eq!(expand_last_reply("!!", reply).unwrap(), "error: boom");
+        assert_eq!(
+            expand_last_reply("wow!! a!!b", reply).unwrap(),
+            "wow!! a!!b"
+        );
+        assert_eq!(expand_last_reply("say \\!!", reply).unwrap(), "say !!");
+        assert!(expand_last_reply("why !!", None).is_err());
+        assert_eq!(expand_last_reply("no bang", None).unwrap(), "no bang");
+    }
+
+    #[test]
+    fn test_split_batch() {
+        assert_eq!(
+            split_batch("a ;; b;;;; c ", Some(";;")),
+            Some(vec!["a", "b", "c"])
+        );
+        assert_eq!(split_batch("a ;; ", Some(";;")), None);
+        assert_eq!(split_batch("a ;; b", None), None);
+        assert_eq!(split_batch("a ;; b", Some("")), None);
     }
 }
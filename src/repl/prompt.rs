@@ -1,8 +1,15 @@
 use crate::config::GlobalConfig;
+use crate::utils::truncate_prompt;
 
+use crossterm::terminal;
 use reedline::{Prompt, PromptHistorySearch, PromptHistorySearchStatus};
 use std::borrow::Cow;
 
+/// Columns kept free for typing when fitting the prompts to the terminal
+const MIN_INPUT_WIDTH: usize = 20;
+/// The left prompt is never cut below this width
+const MIN_PROMPT_WIDTH: usize = 8;
+
 #[derive(Clone)]
 pub struct ReplPrompt {
     config: GlobalConfig,
@@ -17,26 +24,35 @@ impl ReplPrompt {
 }
 
 impl Prompt for ReplPrompt {
-    fn render_prompt_left(&self) -> Cow<str> {
-        Cow::Owned(self.config.read().render_prompt_left())
+    fn render_prompt_left(&self) -> Cow<'_, str> {
+        let max_width = max_prompt_width();
+        let (left_prompt, _) = self.config.read().render_prompts(max_width);
+        match max_width {
+            Some(max_width) => Cow::Owned(truncate_prompt(
+                &left_prompt,
+                max_width.max(MIN_PROMPT_WIDTH),
+            )),
+            None => Cow::Owned(left_prompt),
+        }
     }
 
-    fn render_prompt_right(&self) -> Cow<str> {
-        Cow::Owned(self.config.read().render_prompt_right())
+    fn render_prompt_right(&self) -> Cow<'_, str> {
+        let (_, right_prompt) = self.config.read().render_prompts(max_prompt_width());
+        Cow::Owned(right_prompt)
     }
 
-    fn render_prompt_indicator(&self, _prompt_mode: reedline::PromptEditMode) -> Cow<str> {
+    fn render_prompt_indicator(&self, _prompt_mode: reedline::PromptEditMode) -> Cow<'_, str> {
         Cow::Borrowed("")
     }
 
-    fn render_prompt_multiline_indicator(&self) -> Cow<str> {
+    fn render_prompt_multiline_indicator(&self) -> Cow<'_, str> {
         Cow::Borrowed("")
     }
 
     fn render_prompt_history_search_indicator(
         &self,
         history_search: PromptHistorySearch,
-    ) -> Cow<str> {
+    ) -> Cow<'_, str> {
         let prefix = match history_search.status {
             PromptHistorySearchStatus::Passing => "",
             PromptHistorySearchStatus::Failing => "failing ",
@@ -49,3 +65,9 @@ impl Prompt for ReplPrompt {
         ))
     }
 }
+
+/// The columns left for the prompts in the terminal
+fn max_prompt_width() -> Option<usize> {
+    let (columns, _) = terminal::size().ok()?;
+    Some((columns as usize).saturating_sub(MIN_INPUT_WIDTH))
+}
@@ -1,5 +1,8 @@
 use crate::{
-    client::{init_client, ClientConfig, Message, Model, ReplyEvent, ReplyHandler, SendData},
+    client::{
+        init_client, reply_channel, ClientConfig, Message, Model, ModelRegistry, ReplyEvent,
+        ReplyStream, SendData,
+    },
     config::{Config, GlobalConfig},
     utils::create_abort_signal,
 };
@@ -22,7 +25,7 @@ use std::{convert::Infallible, sync::Arc};
 use tokio::{
     net::TcpListener,
     sync::{
-        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        mpsc::{unbounded_channel, UnboundedSender},
         oneshot,
     },
 };
@@ -46,8 +49,13 @@ pub async fn run(config: GlobalConfig, addr: Option<String>) -> Result<()> {
     };
     let clients = config.read().clients.clone();
     let model = config.read().model.clone();
+    let model_registry = config.read().model_registry.clone();
     let listener = TcpListener::bind(&addr).await?;
-    let server = Arc::new(Server { clients, model });
+    let server = Arc::new(Server {
+        clients,
+        model,
+        model_registry,
+    });
     let stop_server = server.run(listener).await?;
     println!("Access the chat completion API at: http://{addr}/v1/chat/completions");
     shutdown_signal().await;
@@ -58,6 +66,7 @@ pub async fn run(config: GlobalConfig, addr: Option<String>) -> Result<()> {
 struct Server {
     clients: Vec<ClientConfig>,
     model: Model,
+    model_registry: Vec<ModelRegistry>,
 }
 
 impl Server {
@@ -135,6 +144,8 @@ impl Server {
             messages,
             temperature,
             top_p,
+            seed,
+            user,
             max_tokens,
             stream,
         } = req_body;
@@ -142,6 +153,7 @@ impl Server {
         let config = Config {
             clients: self.clients.to_vec(),
             model: self.model.clone(),
+            model_registry: self.model_registry.clone(),
             ..Default::default()
         };
         let config = Arc::new(RwLock::new(config));
@@ -163,21 +175,24 @@ impl Server {
             messages,
             temperature,
             top_p,
+            seed,
+            user,
             stream,
+            cache_prompt: false,
+            thread: None,
         };
 
         if stream {
             let (tx, mut rx) = unbounded_channel();
             tokio::spawn(async move {
                 let mut is_first = true;
-                let (tx2, rx2) = unbounded_channel();
-                let mut handler = ReplyHandler::new(tx2, abort);
+                let (mut handler, reply_stream) = reply_channel(abort);
                 async fn map_event(
-                    mut rx: UnboundedReceiver<ReplyEvent>,
+                    mut reply_stream: ReplyStream,
                     tx: &UnboundedSender<ResEvent>,
                     is_first: &mut bool,
                 ) {
-                    while let Some(reply_event) = rx.recv().await {
+                    while let Some(reply_event) = reply_stream.next().await {
                         if *is_first {
                             let _ = tx.send(ResEvent::First(None));
                             *is_first = false;
@@ -193,7 +208,7 @@ impl Server {
                     }
                 }
                 tokio::select! {
-                    _ = map_event(rx2, &tx, &mut is_first) => {}
+                    _ = map_event(reply_stream, &tx, &mut is_first) => {}
                     ret = client.send_message_streaming_inner(&http_client, &mut handler, send_data) => {
                         if let Err(err) = ret {
                             send_first_event(&tx, Some(format!("{err:?}")), &mut is_first)
@@ -245,6 +260,8 @@ struct ChatCompletionReqBody {
     messages: Vec<Message>,
     temperature: Option<f64>,
     top_p: Option<f64>,
+    seed: Option<u64>,
+    user: Option<String>,
     max_tokens: Option<isize>,
     #[serde(default)]
     stream: bool,
@@ -2,23 +2,41 @@ use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc,
 };
+use tokio::sync::Notify;
 
 pub type AbortSignal = Arc<AbortSignalInner>;
 
 pub struct AbortSignalInner {
     ctrlc: AtomicBool,
     ctrld: AtomicBool,
+    notify: Notify,
 }
 
 pub fn create_abort_signal() -> AbortSignal {
     AbortSignalInner::new()
 }
 
+/// Resolve as soon as the signal is aborted
+///
+/// Racing a request against it in `tokio::select!` drops the request when aborted,
+/// which closes its connection so the server stops generating the reply.
+pub async fn watch_abort(abort: AbortSignal) {
+    loop {
+        // Created before the check, so an abort in between still wakes it
+        let notified = abort.notify.notified();
+        if abort.aborted() {
+            break;
+        }
+        notified.await;
+    }
+}
+
 impl AbortSignalInner {
     pub fn new() -> AbortSignal {
         Arc::new(Self {
             ctrlc: AtomicBool::new(false),
             ctrld: AtomicBool::new(false),
+            notify: Notify::new(),
         })
     }
 
@@ -40,6 +58,16 @@ impl AbortSignalInner {
         self.ctrld.load(Ordering::SeqCst)
     }
 
+    /// Which abort flags are set, shown in the verbose info
+    pub fn state(&self) -> &'static str {
+        match (self.aborted_ctrlc(), self.aborted_ctrld()) {
+            (false, false) => "none",
+            (true, false) => "ctrlc",
+            (false, true) => "ctrld",
+            (true, true) => "ctrlc, ctrld",
+        }
+    }
+
     pub fn reset(&self) {
         self.ctrlc.store(false, Ordering::SeqCst);
         self.ctrld.store(false, Ordering::SeqCst);
@@ -47,9 +75,29 @@ impl AbortSignalInner {
 
     pub fn set_ctrlc(&self) {
         self.ctrlc.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
     }
 
     pub fn set_ctrld(&self) {
         self.ctrld.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_watch_abort() {
+        let abort = create_abort_signal();
+        let task = tokio::spawn(watch_abort(abort.clone()));
+        tokio::task::yield_now().await;
+        assert!(!task.is_finished());
+        abort.set_ctrlc();
+        tokio::time::timeout(std::time::Duration::from_millis(50), task)
+            .await
+            .unwrap()
+            .unwrap();
     }
 }
@@ -5,10 +5,10 @@ mod render_prompt;
 mod spinner;
 mod tiktoken;
 
-pub use self::abort_signal::{create_abort_signal, AbortSignal};
+pub use self::abort_signal::{create_abort_signal, watch_abort, AbortSignal};
 pub use self::clipboard::set_text;
 pub use self::prompt_input::*;
-pub use self::render_prompt::render_prompt;
+pub use self::render_prompt::{prompt_width, render_prompt, truncate_prompt};
 pub use self::spinner::run_spinner;
 pub use self::tiktoken::cl100k_base_singleton;
 
@@ -16,7 +16,9 @@ use fancy_regex::Regex;
 use lazy_static::lazy_static;
 use sha2::{Digest, Sha256};
 use std::env;
-use std::process::Command;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 
 lazy_static! {
     pub static ref CODE_BLOCK_RE: Regex = Regex::new(r"(?ms)```\w*(.*)```").unwrap();
@@ -64,6 +66,24 @@ pub fn count_tokens(text: &str) -> usize {
         .len()
 }
 
+/// The number of single-character edits turning one string into the other
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let value = (prev + usize::from(ca != *cb))
+                .min(row[j] + 1)
+                .min(row[j + 1] + 1);
+            prev = row[j + 1];
+            row[j + 1] = value;
+        }
+    }
+    row[b.len()]
+}
+
 pub fn light_theme_from_colorfgbg(colorfgbg: &str) -> Option<bool> {
     let parts: Vec<_> = colorfgbg.split(';').collect();
     let bg = match parts.len() {
@@ -89,6 +109,22 @@ pub fn sha256sum(input: &str) -> String {
     format!("{:x}", result)
 }
 
+/// A stable id of this machine, read from the system machine-id or else derived from the host and home dir
+pub fn machine_id() -> String {
+    ["/etc/machine-id", "/var/lib/dbus/machine-id"]
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .map(|v| v.trim().to_string())
+        .find(|v| !v.is_empty())
+        .unwrap_or_else(|| {
+            let host = env::var("HOSTNAME")
+                .or_else(|_| env::var("COMPUTERNAME"))
+                .unwrap_or_default();
+            let home = dirs::home_dir().unwrap_or_default();
+            format!("{host}:{}", home.display())
+        })
+}
+
 pub fn detect_os() -> String {
     let os = env::consts::OS;
     if os == "linux" {
@@ -141,15 +177,58 @@ pub fn detect_shell() -> (String, String, &'static str) {
     }
 }
 
+/// Show text in `$PAGER`, or `less -R`, returns false if neither is installed
+pub fn page_text(text: &str) -> anyhow::Result<bool> {
+    let pager = env::var("PAGER")
+        .ok()
+        .and_then(|v| shell_words::split(&v).ok())
+        .filter(|v| v.first().is_some_and(|name| find_program(name).is_some()))
+        .or_else(|| find_program("less").map(|_| vec!["less".into(), "-R".into()]));
+    let Some(pager) = pager else {
+        return Ok(false);
+    };
+    let mut child = Command::new(&pager[0])
+        .args(&pager[1..])
+        .stdin(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // The pager may quit before reading everything
+        let _ = stdin.write_all(text.as_bytes());
+    }
+    child.wait()?;
+    Ok(true)
+}
+
 pub fn run_command(eval_str: &str) -> anyhow::Result<i32> {
+    run_command_with_envs(eval_str, &[])
+}
+
+/// Run a shell command with extra environment variables
+pub fn run_command_with_envs(eval_str: &str, envs: &[(String, String)]) -> anyhow::Result<i32> {
     let (_shell_name, shell_cmd, shell_arg) = detect_shell();
     let status = Command::new(shell_cmd)
         .arg(shell_arg)
         .arg(eval_str)
+        .envs(envs.iter().cloned())
         .status()?;
     Ok(status.code().unwrap_or_default())
 }
 
+/// Run a shell command and capture its stdout, stdin and stderr stay attached to the terminal
+pub fn run_command_output(eval_str: &str) -> anyhow::Result<String> {
+    let (_shell_name, shell_cmd, shell_arg) = detect_shell();
+    let output = Command::new(shell_cmd)
+        .arg(shell_arg)
+        .arg(eval_str)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        anyhow::bail!("Command exited with {}", output.status);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
 pub fn extract_block(input: &str) -> String {
     let output: String = CODE_BLOCK_RE
         .captures_iter(input)
@@ -166,16 +245,91 @@ pub fn extract_block(input: &str) -> String {
     }
 }
 
+/// Extract the contents of the fenced code blocks in a markdown text
+pub fn extract_code_blocks(input: &str) -> Vec<String> {
+    let mut blocks = vec![];
+    let mut current: Option<(&str, Vec<&str>)> = None;
+    for line in input.lines() {
+        let trimmed = line.trim_start();
+        match current.as_mut() {
+            Some((fence, lines)) => {
+                if trimmed.trim_end() == *fence {
+                    blocks.push(lines.join("\n"));
+                    current = None;
+                } else {
+                    lines.push(line);
+                }
+            }
+            None => {
+                if let Some(fence) = ["```", "~~~"].into_iter().find(|v| trimmed.starts_with(v)) {
+                    let len = trimmed.len()
+                        - trimmed
+                            .trim_start_matches(fence.chars().next().unwrap())
+                            .len();
+                    current = Some((&trimmed[..len], vec![]));
+                }
+            }
+        }
+    }
+    blocks
+}
+
+/// Look up a program like the shell would, a name with a path separator is taken as a path
+pub fn find_program(name: &str) -> Option<PathBuf> {
+    let path = Path::new(name);
+    if path.components().count() > 1 {
+        return path.is_file().then(|| path.to_path_buf());
+    }
+    let exts: Vec<String> = if cfg!(windows) {
+        let pathext = env::var("PATHEXT").unwrap_or_else(|_| ".EXE;.CMD;.BAT".into());
+        pathext.split(';').map(|v| v.to_string()).collect()
+    } else {
+        vec![]
+    };
+    env::split_paths(&env::var_os("PATH")?).find_map(|dir| {
+        let path = dir.join(name);
+        if path.is_file() {
+            return Some(path);
+        }
+        exts.iter()
+            .map(|ext| dir.join(format!("{name}{ext}")))
+            .find(|v| v.is_file())
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_find_program() {
+        let exe = env::current_exe().unwrap();
+        assert_eq!(find_program(&exe.display().to_string()), Some(exe));
+        assert!(find_program("aichat-no-such-editor").is_none());
+        assert!(find_program("./aichat-no-such-editor").is_none());
+    }
+
+    #[test]
+    fn test_extract_code_blocks() {
+        let input = "text\n```rust\nfn a() {}\n\n```\nmore\n~~~~\nb\n```\n~~~~\n```sh\nunclosed";
+        assert_eq!(extract_code_blocks(input), ["fn a() {}\n", "b\n```"]);
+        assert!(extract_code_blocks("no code").is_empty());
+    }
+
     #[test]
     fn test_tokenize() {
         assert_eq!(tokenize("😊 hello world"), ["😊", " hello", " world"]);
         assert_eq!(tokenize("世界"), ["世", "界"]);
     }
 
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("temprature", "temperature"), 1);
+        assert_eq!(edit_distance("top-p", "top_p"), 1);
+        assert_eq!(edit_distance("", "seed"), 4);
+        assert_eq!(edit_distance("save", "save"), 0);
+    }
+
     #[test]
     fn test_count_tokens() {
         assert_eq!(count_tokens("😊 hello world"), 4);
@@ -1,4 +1,7 @@
+use crate::render::{strip_ansi, AnsiFilter};
+
 use std::collections::HashMap;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 /// Render REPL prompt
 ///
@@ -13,6 +16,40 @@ pub fn render_prompt(template: &str, variables: &HashMap<&str, String>) -> Strin
     eval_exprs(&exprs, variables)
 }
 
+/// The display width of a rendered prompt, ignoring ANSI escape sequences
+pub fn prompt_width(prompt: &str) -> usize {
+    strip_ansi(prompt).width()
+}
+
+/// Cut a rendered prompt down to `max_width` columns, ending it with `…`, escape sequences are kept
+pub fn truncate_prompt(prompt: &str, max_width: usize) -> String {
+    if prompt_width(prompt) <= max_width {
+        return prompt.to_string();
+    }
+    let mut output = String::new();
+    let mut width = 0;
+    let mut filter = AnsiFilter::default();
+    for ch in prompt.chars() {
+        if !filter.is_visible(ch) {
+            output.push(ch);
+            continue;
+        }
+        // Leave room for the `…` and the space after it
+        let ch_width = ch.width().unwrap_or_default();
+        if width + ch_width + 2 > max_width {
+            break;
+        }
+        width += ch_width;
+        output.push(ch);
+    }
+    // Close a hyperlink the cut may have left open
+    if output.contains("\x1b]8;") {
+        output.push_str("\x1b]8;;\x1b\\");
+    }
+    output.push_str("…\x1b[0m ");
+    output
+}
+
 fn parse_template(template: &str) -> Vec<Expr> {
     let chars: Vec<char> = template.chars().collect();
     let mut exprs = vec![];
@@ -152,4 +189,19 @@ mod tests {
             "temp/coder)"
         );
     }
+
+    #[test]
+    fn test_truncate_prompt() {
+        let prompt = "\x1b[32mtemp/coder\x1b[36m)\x1b[0m ";
+        assert_eq!(prompt_width(prompt), 12);
+        assert_eq!(truncate_prompt(prompt, 12), prompt);
+        assert_eq!(truncate_prompt(prompt, 6), "\x1b[32mtemp…\x1b[0m ");
+
+        let prompt = "\x1b]8;;https://x.io\x1b\\coder\x1b]8;;\x1b\\> ";
+        assert_eq!(prompt_width(prompt), 7);
+        assert_eq!(
+            truncate_prompt(prompt, 5),
+            "\x1b]8;;https://x.io\x1b\\cod\x1b]8;;\x1b\\…\x1b[0m "
+        );
+    }
 }